@@ -4,6 +4,7 @@ mod intr;
 mod kernel;
 mod log;
 mod mpstart;
+mod multiboot1;
 mod panic;
 mod symbols;
 mod timer;
@@ -33,9 +34,14 @@ struct MpInfo {
 }
 
 #[no_mangle]
-pub extern "C" fn boot_main(mbp: usize) -> ! {
+pub extern "C" fn boot_main(mbp: usize, magic: u32) -> ! {
     let mut logdev = log::Device::Serial;
 
+    let mbp = match magic {
+        multiboot1::BOOTLOADER_MAGIC => unsafe { multiboot1::convert_to_multiboot2(mbp) },
+        _ => mbp,
+    };
+
     let mbh = unsafe { mb::load(mbp).expect("Could not load multiboot structure") };
 
     let command_line: Option<&'static str> = mbh
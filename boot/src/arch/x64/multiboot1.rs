@@ -0,0 +1,201 @@
+//! Multiboot 1 support, used when booting with 'qemu -kernel'.
+//!
+//! The rest of the boot code (and the kernel) only understands Multiboot 2, so the Multiboot 1
+//! information structure is converted to an equivalent Multiboot 2 structure.
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::slice;
+
+pub const BOOTLOADER_MAGIC: u32 = 0x2badb002;
+
+const INFO_MEMORY: u32 = 1 << 0;
+const INFO_CMDLINE: u32 = 1 << 2;
+const INFO_MODS: u32 = 1 << 3;
+const INFO_MMAP: u32 = 1 << 6;
+
+const MB2_TAG_END: u32 = 0;
+const MB2_TAG_CMDLINE: u32 = 1;
+const MB2_TAG_MODULE: u32 = 3;
+const MB2_TAG_BASIC_MEMINFO: u32 = 4;
+const MB2_TAG_MMAP: u32 = 6;
+const MB2_TAG_ACPI_OLD: u32 = 14;
+
+const MB2_MMAP_ENTRY_SIZE: u32 = 24;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const EBDA_SEGMENT_PTR: usize = 0x40e;
+const BIOS_AREA: (usize, usize) = (0xe0000, 0x100000);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Info {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32; 4],
+    mmap_length: u32,
+    mmap_addr: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Module {
+    start: u32,
+    end: u32,
+    string: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MmapEntry {
+    size: u32,
+    base: u64,
+    length: u64,
+    typ: u32,
+}
+
+#[repr(C, align(8))]
+struct Mb2Buffer([u8; 16 * 1024]);
+
+static mut MB2_INFO: Mb2Buffer = Mb2Buffer([0; 16 * 1024]);
+
+struct Mb2Writer {
+    buf: &'static mut [u8],
+    len: usize,
+}
+
+impl Mb2Writer {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn put_u64(&mut self, v: u64) {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn put_str(&mut self, s: &[u8]) {
+        self.put(s);
+        self.put(&[0]);
+    }
+
+    fn tag(&mut self, typ: u32, f: impl FnOnce(&mut Self)) {
+        let start = self.len;
+        self.put_u32(typ);
+        self.put_u32(0);
+        f(self);
+        let size = (self.len - start) as u32;
+        self.buf[start + 4..start + 8].copy_from_slice(&size.to_le_bytes());
+        self.len = (self.len + 7) & !7;
+    }
+}
+
+unsafe fn c_str(addr: u32) -> &'static [u8] {
+    let ptr = addr as usize as *const u8;
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    slice::from_raw_parts(ptr, len)
+}
+
+// Multiboot 1 strings start with the file name, Multiboot 2 strings don't
+fn skip_file_name(s: &[u8]) -> &[u8] {
+    match s.iter().position(|&c| c == b' ') {
+        Some(idx) => &s[idx + 1..],
+        None => &[],
+    }
+}
+
+unsafe fn find_rsdp_in(start: usize, end: usize) -> Option<&'static [u8]> {
+    (start..end).step_by(16).find_map(|addr| {
+        let rsdp = slice::from_raw_parts(addr as *const u8, RSDP_V1_SIZE);
+        (&rsdp[..8] == RSDP_SIGNATURE && rsdp.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0)
+            .then(|| rsdp)
+    })
+}
+
+// Multiboot 1 doesn't give us the RSDP, look for it where the BIOS puts it
+unsafe fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = (read_unaligned(EBDA_SEGMENT_PTR as *const u16) as usize) << 4;
+    (ebda != 0)
+        .then(|| find_rsdp_in(ebda, ebda + 1024))
+        .flatten()
+        .or_else(|| find_rsdp_in(BIOS_AREA.0, BIOS_AREA.1))
+}
+
+/// Converts the Multiboot 1 information structure at `mbp` and returns the address of the
+/// Multiboot 2 structure
+pub unsafe fn convert_to_multiboot2(mbp: usize) -> usize {
+    let info: Info = read_unaligned(mbp as *const Info);
+    let mut w = Mb2Writer {
+        buf: &mut MB2_INFO.0,
+        len: 0,
+    };
+    // Total size & reserved
+    w.put_u32(0);
+    w.put_u32(0);
+
+    if info.flags & INFO_CMDLINE != 0 {
+        w.tag(MB2_TAG_CMDLINE, |w| {
+            w.put_str(skip_file_name(c_str(info.cmdline)))
+        });
+    }
+
+    if info.flags & INFO_MEMORY != 0 {
+        w.tag(MB2_TAG_BASIC_MEMINFO, |w| {
+            w.put_u32(info.mem_lower);
+            w.put_u32(info.mem_upper);
+        });
+    }
+
+    if info.flags & INFO_MODS != 0 {
+        for i in 0..info.mods_count as usize {
+            let module: Module =
+                read_unaligned((info.mods_addr as usize + i * size_of::<Module>()) as *const _);
+            w.tag(MB2_TAG_MODULE, |w| {
+                w.put_u32(module.start);
+                w.put_u32(module.end);
+                w.put_str(skip_file_name(c_str(module.string)));
+            });
+        }
+    }
+
+    if info.flags & INFO_MMAP != 0 {
+        w.tag(MB2_TAG_MMAP, |w| {
+            w.put_u32(MB2_MMAP_ENTRY_SIZE);
+            w.put_u32(0);
+            let mut addr = info.mmap_addr as usize;
+            let end = addr + info.mmap_length as usize;
+            while addr < end {
+                let entry: MmapEntry = read_unaligned(addr as *const _);
+                w.put_u64(entry.base);
+                w.put_u64(entry.length);
+                w.put_u32(entry.typ);
+                w.put_u32(0);
+                addr += entry.size as usize + size_of::<u32>();
+            }
+        });
+    }
+
+    if let Some(rsdp) = find_rsdp() {
+        w.tag(MB2_TAG_ACPI_OLD, |w| w.put(rsdp));
+    }
+
+    w.tag(MB2_TAG_END, |_| ());
+
+    let total_size = w.len as u32;
+    w.buf[..4].copy_from_slice(&total_size.to_le_bytes());
+    w.buf.as_ptr() as usize
+}
@@ -25,7 +25,7 @@ fn cargo_build(project: &str, cargo_args: Vec<String>, rustc_args: Vec<String>)
         .unwrap();
 }
 
-fn find_all_drivers() -> impl Iterator<Item = PathBuf> {
+pub(crate) fn find_all_drivers() -> impl Iterator<Item = PathBuf> {
     fs::read_dir(DRIVERS_ROOT)
         .expect("Could not open drivers dir")
         .filter_map(|dir| {
@@ -39,7 +39,7 @@ fn find_all_drivers() -> impl Iterator<Item = PathBuf> {
         .flatten()
 }
 
-fn get_args_for_project(
+pub(crate) fn get_args_for_project(
     opts: &BuildOpts,
     _workspace: &WorkspaceConfig,
     proj: &Project,
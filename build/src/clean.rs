@@ -1,4 +1,3 @@
-
 use duct::cmd;
 
 pub fn clean_main() {
@@ -74,6 +74,7 @@ pub struct ProjectFlags {
 pub struct TargetMatch<'a> {
     pub arch: &'a str,
     pub profile: &'a str,
+    pub flavour: Option<&'a str>,
 }
 
 impl Flags {
@@ -111,9 +112,12 @@ impl ProjectFlags {
     fn populate_flags(&self, target: TargetMatch, flags: &mut Flags) {
         flags.merge_with(&self.flags);
         for (name, config) in &self.configs {
-            if name == target.arch || name == target.profile {
+            if name == target.arch
+                || name == target.profile
+                || Some(name.as_str()) == target.flavour
+            {
                 config.populate_flags(target, flags);
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,3 @@
-
 pub const ROOT_CONFIG_PATH: &'static str = "./chos.toml";
 pub const PROJECT_CONFIG_NAME: &'static str = "project.toml";
 
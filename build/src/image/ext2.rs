@@ -0,0 +1,555 @@
+use std::fs::File;
+
+use super::{write_at, Dir, Entry};
+use crate::ErrorMessage;
+
+const BLOCK_SIZE: usize = 4096;
+const LOG_BLOCK_SIZE: u32 = 2; // 1024 << 2
+const BLOCKS_PER_GROUP: u32 = 8 * BLOCK_SIZE as u32;
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BYTES_PER_INODE: u64 = 16 * 1024;
+const GROUP_DESC_SIZE: usize = 32;
+const PTRS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+const DIRECT_BLOCKS: usize = 12;
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const EXT2_VALID_FS: u16 = 1;
+const EXT2_ERRORS_CONTINUE: u16 = 1;
+const EXT2_DYNAMIC_REV: u32 = 1;
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+
+const ROOT_INO: u32 = 2;
+const LOST_FOUND_INO: u32 = 11;
+const FIRST_INO: u32 = 11;
+
+const S_IFREG: u16 = 0o100000;
+const S_IFDIR: u16 = 0o040000;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+
+fn put_u16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn set_bits(bitmap: &mut [u8], range: std::ops::Range<u32>) {
+    for bit in range {
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+}
+
+fn ceil_div(a: u64, b: u64) -> u64 {
+    match a % b {
+        0 => a / b,
+        _ => a / b + 1,
+    }
+}
+
+fn too_small(size: u64) -> Box<dyn std::error::Error> {
+    ErrorMessage::from(format!("Partition too small for ext2 ({})", size)).into()
+}
+
+struct Layout {
+    blocks_count: u32,
+    groups: u32,
+    inodes_per_group: u32,
+    gdt_blocks: u32,
+    inode_table_blocks: u32,
+}
+
+impl Layout {
+    fn new(size: u64) -> crate::Result<Self> {
+        let mut blocks_count = (size / BLOCK_SIZE as u64) as u32;
+        let mut groups = ceil_div(blocks_count as u64, BLOCKS_PER_GROUP as u64) as u32;
+        if groups == 0 {
+            return Err(too_small(size));
+        }
+        let gdt_blocks = ceil_div(groups as u64 * GROUP_DESC_SIZE as u64, BLOCK_SIZE as u64) as u32;
+        let inodes_count = (size / BYTES_PER_INODE) as u32;
+        let inodes_per_group = (ceil_div(inodes_count as u64, groups as u64) as u32)
+            .max(INODES_PER_BLOCK)
+            .min(8 * BLOCK_SIZE as u32);
+        let inodes_per_group =
+            ceil_div(inodes_per_group as u64, INODES_PER_BLOCK as u64) as u32 * INODES_PER_BLOCK;
+        let inode_table_blocks = inodes_per_group / INODES_PER_BLOCK;
+
+        let mut layout = Self {
+            blocks_count,
+            groups,
+            inodes_per_group,
+            gdt_blocks,
+            inode_table_blocks,
+        };
+        // Drop the last group if it can't hold its own metadata
+        if layout.group_blocks(groups - 1) <= layout.overhead() {
+            groups -= 1;
+            blocks_count = groups * BLOCKS_PER_GROUP;
+            layout.groups = groups;
+            layout.blocks_count = blocks_count;
+        }
+        if groups == 0 {
+            return Err(too_small(size));
+        }
+        Ok(layout)
+    }
+
+    fn inodes_count(&self) -> u32 {
+        self.groups * self.inodes_per_group
+    }
+
+    fn group_start(&self, group: u32) -> u32 {
+        group * BLOCKS_PER_GROUP
+    }
+
+    fn group_blocks(&self, group: u32) -> u32 {
+        (self.blocks_count - self.group_start(group)).min(BLOCKS_PER_GROUP)
+    }
+
+    // Every group has a copy of the superblock and of the descriptor table (no sparse_super)
+    fn overhead(&self) -> u32 {
+        1 + self.gdt_blocks + 2 + self.inode_table_blocks
+    }
+
+    fn block_bitmap(&self, group: u32) -> u32 {
+        self.group_start(group) + 1 + self.gdt_blocks
+    }
+
+    fn inode_bitmap(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u32) -> u32 {
+        self.inode_bitmap(group) + 1
+    }
+
+    fn first_data_block(&self, group: u32) -> u32 {
+        self.group_start(group) + self.overhead()
+    }
+}
+
+struct Ext2<'a> {
+    file: &'a File,
+    offset: u64,
+    layout: Layout,
+    mtime: u32,
+    inodes: Vec<[u8; INODE_SIZE]>,
+    used_dirs: Vec<u16>,
+    next_ino: u32,
+    next_block: u32,
+}
+
+impl<'a> Ext2<'a> {
+    fn write_block(&self, block: u32, data: &[u8]) -> crate::Result<()> {
+        debug_assert!(data.len() <= BLOCK_SIZE);
+        write_at(
+            self.file,
+            data,
+            self.offset + block as u64 * BLOCK_SIZE as u64,
+        )
+    }
+
+    fn alloc_block(&mut self) -> crate::Result<u32> {
+        loop {
+            let group = self.next_block / BLOCKS_PER_GROUP;
+            if group >= self.layout.groups {
+                return Err(ErrorMessage::from("ext2: Filesystem is full").into());
+            }
+            self.next_block = self.next_block.max(self.layout.first_data_block(group));
+            if self.next_block >= self.layout.group_start(group) + self.layout.group_blocks(group) {
+                self.next_block = self.layout.group_start(group + 1);
+                continue;
+            }
+            self.next_block += 1;
+            return Ok(self.next_block - 1);
+        }
+    }
+
+    fn alloc_inode(&mut self) -> crate::Result<u32> {
+        if self.next_ino > self.layout.inodes_count() {
+            return Err(ErrorMessage::from("ext2: No more inodes").into());
+        }
+        self.next_ino += 1;
+        Ok(self.next_ino - 1)
+    }
+
+    fn used_blocks_in_group(&self, group: u32) -> u32 {
+        let start = self.layout.first_data_block(group);
+        let end = self.layout.group_start(group) + self.layout.group_blocks(group);
+        self.layout.overhead() + self.next_block.clamp(start, end) - start
+    }
+
+    fn used_inodes_in_group(&self, group: u32) -> u32 {
+        let start = group * self.layout.inodes_per_group + 1;
+        let end = start + self.layout.inodes_per_group;
+        self.next_ino.clamp(start, end) - start
+    }
+
+    /// Writes a tree of indirect blocks pointing to `ptrs`, returns the root block
+    fn write_indirect(&mut self, ptrs: &[u32], level: u32, used: &mut u32) -> crate::Result<u32> {
+        let children: Vec<u32> = if level == 1 {
+            ptrs.to_vec()
+        } else {
+            ptrs.chunks(PTRS_PER_BLOCK.pow(level - 1))
+                .map(|chunk| self.write_indirect(chunk, level - 1, used))
+                .collect::<crate::Result<_>>()?
+        };
+        let block = self.alloc_block()?;
+        *used += 1;
+        let mut data = [0u8; BLOCK_SIZE];
+        for (i, &ptr) in children.iter().enumerate() {
+            put_u32(&mut data, i * 4, ptr);
+        }
+        self.write_block(block, &data)?;
+        Ok(block)
+    }
+
+    /// Writes `data` to newly allocated blocks, returns the inode block pointers and the number of
+    /// blocks used (including indirect blocks)
+    fn write_data(&mut self, data: &[u8]) -> crate::Result<([u32; 15], u32)> {
+        let mut blocks =
+            Vec::with_capacity(ceil_div(data.len() as u64, BLOCK_SIZE as u64) as usize);
+        for chunk in data.chunks(BLOCK_SIZE) {
+            let block = self.alloc_block()?;
+            self.write_block(block, chunk)?;
+            blocks.push(block);
+        }
+        let mut used = blocks.len() as u32;
+        let mut ptrs = [0u32; 15];
+
+        let direct = blocks.len().min(DIRECT_BLOCKS);
+        ptrs[..direct].copy_from_slice(&blocks[..direct]);
+        let mut rest = &blocks[direct..];
+        for level in 1..=3 {
+            if rest.is_empty() {
+                break;
+            }
+            let count = rest.len().min(PTRS_PER_BLOCK.pow(level));
+            ptrs[DIRECT_BLOCKS + level as usize - 1] =
+                self.write_indirect(&rest[..count], level, &mut used)?;
+            rest = &rest[count..];
+        }
+        if !rest.is_empty() {
+            return Err(ErrorMessage::from("ext2: File too big").into());
+        }
+        Ok((ptrs, used))
+    }
+
+    fn set_inode(&mut self, ino: u32, mode: u16, size: u64, links: u16, data: ([u32; 15], u32)) {
+        let (ptrs, blocks) = data;
+        let inode = &mut self.inodes[(ino - 1) as usize];
+        put_u16(inode, 0, mode);
+        put_u32(inode, 4, size as u32);
+        put_u32(inode, 8, self.mtime);
+        put_u32(inode, 12, self.mtime);
+        put_u32(inode, 16, self.mtime);
+        put_u16(inode, 26, links);
+        put_u32(inode, 28, blocks * (BLOCK_SIZE / 512) as u32);
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            put_u32(inode, 40 + i * 4, ptr);
+        }
+        put_u32(inode, 108, (size >> 32) as u32);
+    }
+
+    fn dir_contents(entries: &[(&str, u32, u8)]) -> Vec<u8> {
+        fn rec_len(name: &str) -> usize {
+            (8 + name.len() + 3) & !3
+        }
+        let mut data = Vec::new();
+        let mut block_start = 0;
+        let mut last = 0;
+        for &(name, ino, typ) in entries {
+            let len = rec_len(name);
+            if data.len() + len > block_start + BLOCK_SIZE {
+                // Extend the last entry to the end of the block
+                put_u16(
+                    &mut data,
+                    last + 4,
+                    (block_start + BLOCK_SIZE - last) as u16,
+                );
+                block_start += BLOCK_SIZE;
+                data.resize(block_start, 0);
+            }
+            last = data.len();
+            data.resize(last + len, 0);
+            put_u32(&mut data, last, ino);
+            put_u16(&mut data, last + 4, len as u16);
+            data[last + 6] = name.len() as u8;
+            data[last + 7] = typ;
+            data[last + 8..last + 8 + name.len()].copy_from_slice(name.as_bytes());
+        }
+        put_u16(
+            &mut data,
+            last + 4,
+            (block_start + BLOCK_SIZE - last) as u16,
+        );
+        data.resize(block_start + BLOCK_SIZE, 0);
+        data
+    }
+
+    fn build_dir(&mut self, ino: u32, parent: u32, dir: &Dir) -> crate::Result<()> {
+        let mut entries = vec![(".", ino, FT_DIR), ("..", parent, FT_DIR)];
+        if ino == ROOT_INO {
+            entries.push(("lost+found", LOST_FOUND_INO, FT_DIR));
+        }
+        let mut children = Vec::new();
+        for (name, entry) in dir.entries() {
+            if name.len() > 255 {
+                return Err(ErrorMessage::from(format!("ext2: Name too long: {}", name)).into());
+            }
+            let child = self.alloc_inode()?;
+            let typ = match entry {
+                Entry::Dir(_) => FT_DIR,
+                Entry::File(_) => FT_REG_FILE,
+            };
+            entries.push((name, child, typ));
+            children.push((child, entry));
+        }
+        let subdirs = entries.iter().skip(2).filter(|e| e.2 == FT_DIR).count();
+
+        let contents = Self::dir_contents(&entries);
+        let data = self.write_data(&contents)?;
+        self.set_inode(
+            ino,
+            S_IFDIR | 0o755,
+            contents.len() as u64,
+            2 + subdirs as u16,
+            data,
+        );
+        self.used_dirs[((ino - 1) / self.layout.inodes_per_group) as usize] += 1;
+
+        for (child, entry) in children {
+            match entry {
+                Entry::Dir(dir) => self.build_dir(child, ino, dir)?,
+                Entry::File(contents) => {
+                    let data = self.write_data(contents)?;
+                    self.set_inode(child, S_IFREG | 0o644, contents.len() as u64, 1, data);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn superblock(&self, group: u32) -> [u8; SUPERBLOCK_SIZE] {
+        let l = &self.layout;
+        let used_blocks: u32 = (0..l.groups).map(|g| self.used_blocks_in_group(g)).sum();
+        let used_inodes = self.next_ino - 1;
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        put_u32(&mut sb, 0, l.inodes_count());
+        put_u32(&mut sb, 4, l.blocks_count);
+        put_u32(&mut sb, 12, l.blocks_count - used_blocks);
+        put_u32(&mut sb, 16, l.inodes_count() - used_inodes);
+        put_u32(&mut sb, 20, 0); // First data block
+        put_u32(&mut sb, 24, LOG_BLOCK_SIZE);
+        put_u32(&mut sb, 28, LOG_BLOCK_SIZE);
+        put_u32(&mut sb, 32, BLOCKS_PER_GROUP);
+        put_u32(&mut sb, 36, BLOCKS_PER_GROUP);
+        put_u32(&mut sb, 40, l.inodes_per_group);
+        put_u32(&mut sb, 48, self.mtime);
+        put_u16(&mut sb, 54, u16::MAX); // No max mount count
+        put_u16(&mut sb, 56, EXT2_MAGIC);
+        put_u16(&mut sb, 58, EXT2_VALID_FS);
+        put_u16(&mut sb, 60, EXT2_ERRORS_CONTINUE);
+        put_u32(&mut sb, 64, self.mtime);
+        put_u32(&mut sb, 76, EXT2_DYNAMIC_REV);
+        put_u32(&mut sb, 84, FIRST_INO);
+        put_u16(&mut sb, 88, INODE_SIZE as u16);
+        put_u16(&mut sb, 90, group as u16);
+        put_u32(&mut sb, 96, FEATURE_INCOMPAT_FILETYPE);
+        // UUID, only needs to be unique enough to tell images apart
+        sb[104..112].copy_from_slice(&(self.mtime as u64).to_le_bytes());
+        sb[112..120].copy_from_slice(&(l.blocks_count as u64 ^ 0x43484f53).to_le_bytes());
+        sb[120..124].copy_from_slice(b"chos");
+        sb
+    }
+
+    fn group_descriptors(&self) -> Vec<u8> {
+        let l = &self.layout;
+        let mut gdt = vec![0u8; l.gdt_blocks as usize * BLOCK_SIZE];
+        for g in 0..l.groups {
+            let desc = &mut gdt[g as usize * GROUP_DESC_SIZE..(g as usize + 1) * GROUP_DESC_SIZE];
+            put_u32(desc, 0, l.block_bitmap(g));
+            put_u32(desc, 4, l.inode_bitmap(g));
+            put_u32(desc, 8, l.inode_table(g));
+            put_u16(
+                desc,
+                12,
+                (l.group_blocks(g) - self.used_blocks_in_group(g)) as u16,
+            );
+            put_u16(
+                desc,
+                14,
+                (l.inodes_per_group - self.used_inodes_in_group(g)) as u16,
+            );
+            put_u16(desc, 16, self.used_dirs[g as usize]);
+        }
+        gdt
+    }
+
+    fn write_metadata(&self) -> crate::Result<()> {
+        let l = &self.layout;
+        let gdt = self.group_descriptors();
+        for g in 0..l.groups {
+            let start = l.group_start(g);
+            let sb = self.superblock(g);
+            if g == 0 {
+                write_at(self.file, &sb, self.offset + SUPERBLOCK_OFFSET as u64)?;
+            } else {
+                self.write_block(start, &sb)?;
+            }
+            for (i, block) in gdt.chunks(BLOCK_SIZE).enumerate() {
+                self.write_block(start + 1 + i as u32, block)?;
+            }
+
+            let mut block_bitmap = [0u8; BLOCK_SIZE];
+            set_bits(&mut block_bitmap, 0..self.used_blocks_in_group(g));
+            set_bits(&mut block_bitmap, l.group_blocks(g)..BLOCKS_PER_GROUP);
+            self.write_block(l.block_bitmap(g), &block_bitmap)?;
+
+            let mut inode_bitmap = [0u8; BLOCK_SIZE];
+            set_bits(&mut inode_bitmap, 0..self.used_inodes_in_group(g));
+            set_bits(&mut inode_bitmap, l.inodes_per_group..8 * BLOCK_SIZE as u32);
+            self.write_block(l.inode_bitmap(g), &inode_bitmap)?;
+
+            let first = (g * l.inodes_per_group) as usize;
+            let table = &self.inodes[first..first + l.inodes_per_group as usize];
+            for (i, chunk) in table.chunks(INODES_PER_BLOCK as usize).enumerate() {
+                self.write_block(l.inode_table(g) + i as u32, &chunk.concat())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `size` bytes of `file` at `offset` as ext2 and fills it with `root`
+pub fn format(file: &File, offset: u64, size: u64, root: &Dir, mtime: u32) -> crate::Result<()> {
+    let layout = Layout::new(size)?;
+    let mut fs = Ext2 {
+        file,
+        offset,
+        mtime,
+        inodes: vec![[0u8; INODE_SIZE]; layout.inodes_count() as usize],
+        used_dirs: vec![0; layout.groups as usize],
+        next_ino: FIRST_INO + 1,
+        next_block: 0,
+        layout,
+    };
+    fs.build_dir(LOST_FOUND_INO, ROOT_INO, &Dir::new())?;
+    fs.build_dir(ROOT_INO, ROOT_INO, root)?;
+    fs.write_metadata()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::os::unix::fs::FileExt;
+
+    use super::*;
+
+    fn get_u16(buf: &[u8], off: usize) -> u16 {
+        u16::from_le_bytes(buf[off..off + 2].try_into().unwrap())
+    }
+
+    fn get_u32(buf: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+    }
+
+    fn read_block(file: &File, block: u32) -> Vec<u8> {
+        let mut data = vec![0u8; BLOCK_SIZE];
+        file.read_exact_at(&mut data, block as u64 * BLOCK_SIZE as u64)
+            .unwrap();
+        data
+    }
+
+    fn read_inode(file: &File, gd: &[u8], ino: u32) -> Vec<u8> {
+        let mut inode = vec![0u8; INODE_SIZE];
+        let offset =
+            get_u32(gd, 8) as u64 * BLOCK_SIZE as u64 + (ino - 1) as u64 * INODE_SIZE as u64;
+        file.read_exact_at(&mut inode, offset).unwrap();
+        inode
+    }
+
+    fn read_dir(file: &File, inode: &[u8]) -> Vec<(String, u32, u8)> {
+        let data = read_block(file, get_u32(inode, 40));
+        let mut entries = Vec::new();
+        let mut off = 0;
+        while off < BLOCK_SIZE {
+            let name_len = data[off + 6] as usize;
+            entries.push((
+                String::from_utf8(data[off + 8..off + 8 + name_len].to_vec()).unwrap(),
+                get_u32(&data, off),
+                data[off + 7],
+            ));
+            off += get_u16(&data, off + 4) as usize;
+        }
+        entries
+    }
+
+    #[test]
+    fn format_small_image() {
+        const SIZE: u64 = 8 * 1024 * 1024;
+        let mut root = Dir::new();
+        root.add_file("a.txt", b"hello".to_vec()).unwrap();
+        root.add_file("dir/b.bin", vec![0xaa; 3 * BLOCK_SIZE])
+            .unwrap();
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(SIZE).unwrap();
+        format(&file, 0, SIZE, &root, 1234).unwrap();
+
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut sb, SUPERBLOCK_OFFSET as u64)
+            .unwrap();
+        assert_eq!(get_u16(&sb, 56), EXT2_MAGIC);
+        assert_eq!(get_u32(&sb, 4), (SIZE / BLOCK_SIZE as u64) as u32);
+        assert_eq!(get_u32(&sb, 24), LOG_BLOCK_SIZE);
+        assert_eq!(get_u32(&sb, 84), FIRST_INO);
+        assert_eq!(get_u16(&sb, 88), INODE_SIZE as u16);
+        let inodes_count = get_u32(&sb, 0);
+        // lost+found, root, a.txt, dir, b.bin
+        assert_eq!(get_u32(&sb, 16), inodes_count - (FIRST_INO + 3));
+
+        let gdt = read_block(&file, 1);
+        let gd = &gdt[..GROUP_DESC_SIZE];
+        assert_eq!(get_u32(gd, 0), 2);
+        assert_eq!(get_u32(gd, 4), 3);
+        assert_eq!(get_u32(gd, 8), 4);
+        assert_eq!(get_u16(gd, 16), 3);
+        assert_eq!(get_u16(gd, 12) as u32, get_u32(&sb, 12));
+
+        let root_inode = read_inode(&file, gd, ROOT_INO);
+        assert_eq!(get_u16(&root_inode, 0), S_IFDIR | 0o755);
+        assert_eq!(get_u16(&root_inode, 26), 4);
+        let entries = read_dir(&file, &root_inode);
+        let names: Vec<_> = entries.iter().map(|e| e.0.as_str()).collect();
+        assert_eq!(names, [".", "..", "lost+found", "a.txt", "dir"]);
+        assert_eq!(entries[2].1, LOST_FOUND_INO);
+
+        let (_, a_ino, a_typ) = entries[3];
+        assert_eq!(a_typ, FT_REG_FILE);
+        let a_inode = read_inode(&file, gd, a_ino);
+        assert_eq!(get_u16(&a_inode, 0), S_IFREG | 0o644);
+        assert_eq!(get_u32(&a_inode, 4), 5);
+        assert_eq!(&read_block(&file, get_u32(&a_inode, 40))[..5], b"hello");
+
+        let (_, dir_ino, dir_typ) = entries[4];
+        assert_eq!(dir_typ, FT_DIR);
+        let dir_entries = read_dir(&file, &read_inode(&file, gd, dir_ino));
+        assert_eq!(dir_entries[1], ("..".into(), ROOT_INO, FT_DIR));
+        let b_inode = read_inode(&file, gd, dir_entries[2].1);
+        assert_eq!(dir_entries[2].0, "b.bin");
+        assert_eq!(get_u32(&b_inode, 4), 3 * BLOCK_SIZE as u32);
+        assert_eq!(get_u32(&b_inode, 28), 3 * (BLOCK_SIZE / 512) as u32);
+    }
+
+    #[test]
+    fn too_small_for_ext2() {
+        assert!(Layout::new(0).is_err());
+        assert!(Layout::new(BLOCK_SIZE as u64 - 1).is_err());
+        assert!(Layout::new(4 * BLOCK_SIZE as u64).is_err());
+    }
+}
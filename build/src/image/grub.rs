@@ -0,0 +1,98 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use duct::cmd;
+use tempfile::Builder;
+
+use crate::util::display_cmd_hook;
+use crate::ErrorMessage;
+
+const GRUB_DIRS: &[&str] = &[
+    "/usr/lib/grub/i386-pc",
+    "/usr/lib/grub2/i386-pc",
+    "/usr/share/grub2/i386-pc",
+];
+const GRUB_MKIMAGES: &[&str] = &["grub-mkimage", "grub2-mkimage"];
+const GRUB_DIR_ENV: &str = "CHOS_GRUB_DIR";
+
+const GRUB_MODULES: &[&str] = &[
+    "biosdisk",
+    "part_msdos",
+    "ext2",
+    "normal",
+    "multiboot2",
+    "boot",
+];
+const GRUB_PREFIX: &str = "(hd0,msdos1)/boot/grub";
+const GRUB_EMBEDDED_CONFIG: &str = "set root=(hd0,msdos1)\nset prefix=($root)/boot/grub\n";
+
+// See include/grub/i386/pc/boot.h in GRUB
+const BOOT_KERNEL_SECTOR_OFFSET: usize = 0x5c;
+const BOOT_DRIVE_CHECK_OFFSET: usize = 0x66;
+const CORE_IMG_SECTOR: u64 = 1;
+
+fn find_grub_dir() -> crate::Result<PathBuf> {
+    if let Some(dir) = std::env::var_os(GRUB_DIR_ENV) {
+        return Ok(dir.into());
+    }
+    GRUB_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|dir| dir.join("boot.img").exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            ErrorMessage::from(format!(
+                "Could not find GRUB i386-pc files, set {}",
+                GRUB_DIR_ENV
+            ))
+            .into()
+        })
+}
+
+fn find_grub_mkimage() -> crate::Result<&'static str> {
+    GRUB_MKIMAGES
+        .iter()
+        .copied()
+        .find(|mkimage| {
+            cmd!(*mkimage, "--version")
+                .stdout_null()
+                .stderr_null()
+                .run()
+                .is_ok()
+        })
+        .ok_or_else(|| ErrorMessage::from("Could not find grub-mkimage").into())
+}
+
+/// Returns GRUB's boot.img (MBR boot code) and core.img (embedded right after the MBR)
+pub fn make_grub_images() -> crate::Result<(Vec<u8>, Vec<u8>)> {
+    let grub_dir = find_grub_dir()?;
+    let mkimage = find_grub_mkimage()?;
+
+    let tmp = Builder::new().prefix("chos-grub").tempdir()?;
+    let config = tmp.path().join("embedded.cfg");
+    let core_img = tmp.path().join("core.img");
+    fs::write(&config, GRUB_EMBEDDED_CONFIG)?;
+
+    let mut args = vec![
+        "-d".into(),
+        grub_dir.to_string_lossy().into_owned(),
+        "-O".into(),
+        "i386-pc".into(),
+        "-o".into(),
+        core_img.to_string_lossy().into_owned(),
+        "-c".into(),
+        config.to_string_lossy().into_owned(),
+        "-p".into(),
+        GRUB_PREFIX.into(),
+    ];
+    args.extend(GRUB_MODULES.iter().map(|&m| m.to_string()));
+    cmd(mkimage, args).before_spawn(display_cmd_hook).run()?;
+
+    let mut boot_img = fs::read(grub_dir.join("boot.img"))?;
+    boot_img[BOOT_KERNEL_SECTOR_OFFSET..BOOT_KERNEL_SECTOR_OFFSET + 8]
+        .copy_from_slice(&CORE_IMG_SECTOR.to_le_bytes());
+    // We always boot from a hard disk, skip the floppy check like grub-bios-setup does
+    boot_img[BOOT_DRIVE_CHECK_OFFSET..BOOT_DRIVE_CHECK_OFFSET + 2].copy_from_slice(&[0x90, 0x90]);
+
+    Ok((boot_img, fs::read(core_img)?))
+}
@@ -0,0 +1,86 @@
+use std::fs::File;
+
+use super::write_at;
+
+const BOOT_CODE_SIZE: usize = 440;
+const DISK_SIGNATURE_OFFSET: usize = 440;
+const PARTITION_TABLE_OFFSET: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+
+const DISK_SIGNATURE: u32 = 0x43484f53; // CHOS
+const PARTITION_BOOTABLE: u8 = 0x80;
+const PARTITION_TYPE_LINUX: u8 = 0x83;
+
+const HEADS: u64 = 255;
+const SECTORS_PER_TRACK: u64 = 63;
+
+fn lba_to_chs(lba: u64) -> [u8; 3] {
+    let cylinder = lba / (HEADS * SECTORS_PER_TRACK);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS_PER_TRACK) % HEADS;
+    let sector = lba % SECTORS_PER_TRACK + 1;
+    [
+        head as u8,
+        (sector as u8) | (((cylinder >> 8) as u8) << 6),
+        cylinder as u8,
+    ]
+}
+
+/// Writes the MBR with `boot_img` as boot code and a single bootable Linux partition
+pub fn write_mbr(file: &File, boot_img: &[u8], start_lba: u64, sectors: u64) -> crate::Result<()> {
+    let mut mbr = [0u8; 512];
+    mbr[..BOOT_CODE_SIZE].copy_from_slice(&boot_img[..BOOT_CODE_SIZE]);
+    mbr[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4]
+        .copy_from_slice(&DISK_SIGNATURE.to_le_bytes());
+
+    let entry = &mut mbr[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + PARTITION_ENTRY_SIZE];
+    entry[0] = PARTITION_BOOTABLE;
+    entry[1..4].copy_from_slice(&lba_to_chs(start_lba));
+    entry[4] = PARTITION_TYPE_LINUX;
+    entry[5..8].copy_from_slice(&lba_to_chs(start_lba + sectors - 1));
+    entry[8..12].copy_from_slice(&(start_lba as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+
+    mbr[BOOT_SIGNATURE_OFFSET] = 0x55;
+    mbr[BOOT_SIGNATURE_OFFSET + 1] = 0xaa;
+
+    write_at(file, &mbr, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::os::unix::fs::FileExt;
+
+    use super::*;
+
+    #[test]
+    fn single_partition_entry() {
+        let file = tempfile::tempfile().unwrap();
+        let boot_img = [0x90u8; 512];
+        write_mbr(&file, &boot_img, 2048, 100_000).unwrap();
+
+        let mut mbr = [0u8; 512];
+        file.read_exact_at(&mut mbr, 0).unwrap();
+        assert_eq!(&mbr[BOOT_SIGNATURE_OFFSET..], &[0x55, 0xaa]);
+        assert!(mbr[..BOOT_CODE_SIZE].iter().all(|&b| b == 0x90));
+        let entry = &mbr[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + PARTITION_ENTRY_SIZE];
+        assert_eq!(entry[0], PARTITION_BOOTABLE);
+        assert_eq!(entry[4], PARTITION_TYPE_LINUX);
+        assert_eq!(&entry[1..4], &lba_to_chs(2048));
+        assert_eq!(u32::from_le_bytes(entry[8..12].try_into().unwrap()), 2048);
+        assert_eq!(
+            u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+            100_000
+        );
+        // The other entries are empty
+        assert!(
+            mbr[PARTITION_TABLE_OFFSET + PARTITION_ENTRY_SIZE..BOOT_SIGNATURE_OFFSET]
+                .iter()
+                .all(|&b| b == 0)
+        );
+    }
+}
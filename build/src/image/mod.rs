@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ErrorMessage;
+
+mod ext2;
+mod grub;
+mod mbr;
+mod tar;
+
+pub use tar::build_tar;
+
+/// First sector of the partition, leaves room for GRUB's core.img after the MBR
+const PARTITION_START_LBA: u64 = 2048;
+
+#[derive(Debug, Default)]
+pub struct Dir {
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug)]
+pub enum Entry {
+    Dir(Dir),
+    File(Vec<u8>),
+}
+
+impl Dir {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Entry)> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry))
+    }
+
+    pub fn add_file(&mut self, path: impl AsRef<Path>, contents: Vec<u8>) -> crate::Result<()> {
+        let path = path.as_ref();
+        let mut names = Vec::new();
+        for c in path.components() {
+            match c {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => names.push(
+                    name.to_str()
+                        .ok_or_else(|| ErrorMessage::from(format!("Invalid path {:?}", path)))?,
+                ),
+                _ => return Err(ErrorMessage::from(format!("Invalid path {:?}", path)).into()),
+            }
+        }
+        let (filename, dirs) = names
+            .split_last()
+            .ok_or_else(|| ErrorMessage::from(format!("Invalid path {:?}", path)))?;
+        let mut dir = self;
+        for &name in dirs {
+            let entry = dir
+                .entries
+                .entry(name.into())
+                .or_insert_with(|| Entry::Dir(Dir::new()));
+            dir = match entry {
+                Entry::Dir(dir) => dir,
+                Entry::File(_) => {
+                    return Err(ErrorMessage::from(format!("{:?} is not a directory", name)).into())
+                }
+            };
+        }
+        dir.entries
+            .insert((*filename).into(), Entry::File(contents));
+        Ok(())
+    }
+}
+
+fn write_at(file: &File, data: &[u8], offset: u64) -> crate::Result<()> {
+    file.write_all_at(data, offset)?;
+    Ok(())
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Writes a bootable disk image with a single ext2 partition containing `root`
+pub fn write_image(path: impl AsRef<Path>, image_size: usize, root: &Dir) -> crate::Result<()> {
+    let sector_size = crate::DEPLOY_BLOCK_SIZE as u64;
+    let total_sectors = image_size as u64 / sector_size;
+    if total_sectors <= PARTITION_START_LBA {
+        return Err(ErrorMessage::from(format!("Image size {} is too small", image_size)).into());
+    }
+    let part_sectors = total_sectors - PARTITION_START_LBA;
+
+    let file = File::create(path)?;
+    file.set_len(total_sectors * sector_size)?;
+
+    let (boot_img, core_img) = grub::make_grub_images()?;
+    if core_img.len() as u64 > (PARTITION_START_LBA - 1) * sector_size {
+        return Err(ErrorMessage::from("GRUB core.img does not fit before the partition").into());
+    }
+    mbr::write_mbr(&file, &boot_img, PARTITION_START_LBA, part_sectors)?;
+    write_at(&file, &core_img, sector_size)?;
+
+    ext2::format(
+        &file,
+        PARTITION_START_LBA * sector_size,
+        part_sectors * sector_size,
+        root,
+        now(),
+    )?;
+
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use super::{now, Dir, Entry};
+use crate::ErrorMessage;
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const UID: (usize, usize) = (108, 8);
+const GID: (usize, usize) = (116, 8);
+const SIZE: (usize, usize) = (124, 12);
+const MTIME: (usize, usize) = (136, 12);
+const CHKSUM: (usize, usize) = (148, 8);
+const TYPEFLAG: usize = 156;
+const MAGIC: (usize, usize) = (257, 8);
+const UNAME: (usize, usize) = (265, 32);
+const GNAME: (usize, usize) = (297, 32);
+const PREFIX: (usize, usize) = (345, 155);
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_DIR: u8 = b'5';
+
+const OWNER: &str = "root";
+
+fn field(header: &mut [u8; BLOCK_SIZE], (off, len): (usize, usize)) -> &mut [u8] {
+    &mut header[off..off + len]
+}
+
+fn write_str(header: &mut [u8; BLOCK_SIZE], f: (usize, usize), s: &[u8]) {
+    field(header, f)[..s.len()].copy_from_slice(s);
+}
+
+fn write_octal(header: &mut [u8; BLOCK_SIZE], f: (usize, usize), value: u64) {
+    let s = format!("{:01$o}", value, f.1 - 1);
+    write_str(header, f, s.as_bytes());
+}
+
+fn split_name(name: &str) -> crate::Result<(&str, &str)> {
+    if name.len() <= NAME.1 {
+        return Ok(("", name));
+    }
+    name.char_indices()
+        .filter(|&(_, c)| c == '/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= PREFIX.1 && name.len() <= NAME.1)
+        .ok_or_else(|| ErrorMessage::from(format!("Path too long for tar: {}", name)).into())
+}
+
+fn write_header(
+    out: &mut Vec<u8>,
+    name: &str,
+    typ: u8,
+    size: u64,
+    mtime: u32,
+) -> crate::Result<()> {
+    let (prefix, name) = split_name(name)?;
+    let mut header = [0u8; BLOCK_SIZE];
+    write_str(&mut header, NAME, name.as_bytes());
+    write_str(&mut header, PREFIX, prefix.as_bytes());
+    write_octal(
+        &mut header,
+        MODE,
+        if typ == TYPE_DIR { 0o755 } else { 0o644 },
+    );
+    write_octal(&mut header, UID, 0);
+    write_octal(&mut header, GID, 0);
+    write_octal(&mut header, SIZE, size);
+    write_octal(&mut header, MTIME, mtime as u64);
+    header[TYPEFLAG] = typ;
+    write_str(&mut header, MAGIC, b"ustar\x0000");
+    write_str(&mut header, UNAME, OWNER.as_bytes());
+    write_str(&mut header, GNAME, OWNER.as_bytes());
+
+    field(&mut header, CHKSUM).fill(b' ');
+    let chksum: u32 = header.iter().map(|&b| b as u32).sum();
+    write_str(&mut header, CHKSUM, format!("{:06o}\0", chksum).as_bytes());
+
+    out.extend_from_slice(&header);
+    Ok(())
+}
+
+fn build_dir(out: &mut Vec<u8>, path: &str, dir: &Dir, mtime: u32) -> crate::Result<()> {
+    write_header(out, path, TYPE_DIR, 0, mtime)?;
+    for (name, entry) in dir.entries() {
+        match entry {
+            Entry::Dir(dir) => build_dir(out, &format!("{}{}/", path, name), dir, mtime)?,
+            Entry::File(contents) => {
+                write_header(
+                    out,
+                    &format!("{}{}", path, name),
+                    TYPE_FILE,
+                    contents.len() as u64,
+                    mtime,
+                )?;
+                out.extend_from_slice(contents);
+                out.resize(
+                    out.len() + (BLOCK_SIZE - contents.len() % BLOCK_SIZE) % BLOCK_SIZE,
+                    0,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates an ustar archive of `root`, equivalent to `tar --owner=root --group=root -cf - .`
+pub fn build_tar(root: &Dir) -> crate::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    build_dir(&mut out, "./", root, now())?;
+    // End of archive
+    out.resize(out.len() + 2 * BLOCK_SIZE, 0);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_str(header: &[u8], (off, len): (usize, usize)) -> &str {
+        let field = &header[off..off + len];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+        std::str::from_utf8(&field[..end]).unwrap()
+    }
+
+    fn read_octal(header: &[u8], f: (usize, usize)) -> u64 {
+        u64::from_str_radix(read_str(header, f).trim(), 8).unwrap()
+    }
+
+    // Returns (path, type, size) and checks the checksum
+    fn read_header(header: &[u8]) -> (String, u8, u64) {
+        let mut sum: u64 = header.iter().map(|&b| b as u64).sum();
+        sum -= header[CHKSUM.0..CHKSUM.0 + CHKSUM.1]
+            .iter()
+            .map(|&b| b as u64)
+            .sum::<u64>();
+        sum += CHKSUM.1 as u64 * b' ' as u64;
+        assert_eq!(read_octal(header, CHKSUM), sum);
+        assert_eq!(&header[MAGIC.0..MAGIC.0 + MAGIC.1], b"ustar\x0000");
+        let prefix = read_str(header, PREFIX);
+        let name = read_str(header, NAME);
+        let path = match prefix {
+            "" => name.to_string(),
+            prefix => format!("{}/{}", prefix, name),
+        };
+        (path, header[TYPEFLAG], read_octal(header, SIZE))
+    }
+
+    #[test]
+    fn archive_layout() {
+        let mut root = Dir::new();
+        root.add_file("a.txt", b"hello".to_vec()).unwrap();
+        root.add_file("dir/b.bin", vec![0xaa; BLOCK_SIZE + 88])
+            .unwrap();
+        let tar = build_tar(&root).unwrap();
+        let blocks: Vec<_> = tar.chunks(BLOCK_SIZE).collect();
+        assert_eq!(tar.len() % BLOCK_SIZE, 0);
+        assert_eq!(blocks.len(), 9);
+
+        assert_eq!(read_header(blocks[0]), ("./".into(), TYPE_DIR, 0));
+        assert_eq!(read_header(blocks[1]), ("./a.txt".into(), TYPE_FILE, 5));
+        assert_eq!(&blocks[2][..5], b"hello");
+        assert!(blocks[2][5..].iter().all(|&b| b == 0));
+        assert_eq!(read_header(blocks[3]), ("./dir/".into(), TYPE_DIR, 0));
+        assert_eq!(
+            read_header(blocks[4]),
+            ("./dir/b.bin".into(), TYPE_FILE, BLOCK_SIZE as u64 + 88)
+        );
+        assert!(blocks[5].iter().all(|&b| b == 0xaa));
+        assert!(blocks[6][..88].iter().all(|&b| b == 0xaa));
+        assert!(blocks[6][88..].iter().all(|&b| b == 0));
+        assert!(blocks[7..]
+            .iter()
+            .all(|block| block.iter().all(|&b| b == 0)));
+    }
+
+    #[test]
+    fn long_paths_use_prefix() {
+        let dir = "d".repeat(80);
+        let file = "f".repeat(90);
+        let mut root = Dir::new();
+        root.add_file(format!("{}/{}", dir, file), Vec::new())
+            .unwrap();
+        let tar = build_tar(&root).unwrap();
+        let file_header = &tar[2 * BLOCK_SIZE..3 * BLOCK_SIZE];
+        assert_eq!(read_str(file_header, NAME), file);
+        assert_eq!(read_str(file_header, PREFIX), format!("./{}", dir));
+        assert_eq!(
+            read_header(file_header),
+            (format!("./{}/{}", dir, file), TYPE_FILE, 0)
+        );
+
+        let mut root = Dir::new();
+        root.add_file("f".repeat(NAME.1 + 1), Vec::new()).unwrap();
+        assert!(build_tar(&root).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use std::env::current_dir;
+
+use duct::cmd;
+
+use crate::build::{find_all_drivers, get_args_for_project};
+use crate::config::{ProjectType, WorkspaceConfig};
+use crate::util::display_cmd_hook;
+use crate::{LintOpts, Project};
+
+fn cargo_clippy(project: &str, cargo_args: Vec<String>, clippy_args: &[String]) -> bool {
+    println!("==> Linting {}", project);
+
+    let mut args = vec!["clippy".into(), "-p".into(), project.to_string()];
+    args.extend(cargo_args);
+    args.push("--".into());
+    args.extend(clippy_args.iter().cloned());
+
+    cmd("cargo", args)
+        .env("CLIPPY_CONF_DIR", current_dir().unwrap())
+        .before_spawn(display_cmd_hook)
+        .unchecked()
+        .run()
+        .unwrap()
+        .status
+        .success()
+}
+
+pub fn lint_main(opts: &LintOpts, workspace: &WorkspaceConfig, projects: &[Project]) {
+    let clippy_args = opts.clippy_args.as_deref().unwrap_or_default();
+    let mut failed = Vec::new();
+    for proj in projects {
+        let (cargo_args, _) = get_args_for_project(&opts.build, workspace, proj);
+        if proj.typ == ProjectType::Kernel {
+            for driver_path in find_all_drivers() {
+                let config = cargo_toml::Manifest::from_path(driver_path.join("Cargo.toml"))
+                    .unwrap_or_else(|_| {
+                        panic!(
+                            "Could not open {}/Cargo.toml",
+                            driver_path.to_string_lossy()
+                        )
+                    });
+                let name = config.package.expect("Should be a normal project").name;
+                if !cargo_clippy(&name, cargo_args.clone(), clippy_args) {
+                    failed.push(name);
+                }
+            }
+        }
+        if !cargo_clippy(&proj.name, cargo_args, clippy_args) {
+            failed.push(proj.name.clone());
+        }
+    }
+    if !failed.is_empty() {
+        eprintln!("Lint failed for {}", failed.join(", "));
+        std::process::exit(1);
+    }
+}
@@ -23,17 +23,25 @@ use config::*;
 mod driver;
 use driver::*;
 
+mod image;
+
+mod lint;
+use lint::*;
+
 mod opts;
 use opts::*;
 
 mod run;
 use run::*;
 
+mod test;
+use test::*;
+
 mod util;
 use std::fmt;
-use std::sync::LazyLock;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::LazyLock;
 
 use cargo_toml::Manifest;
 use structopt::StructOpt;
@@ -44,6 +52,7 @@ static ROOT_CONFIG: LazyLock<PathBuf> =
 
 const DEBUG_STR: &'static str = "debug";
 const RELEASE_STR: &'static str = "release";
+const TEST_STR: &str = "test";
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -111,6 +120,7 @@ fn target_from_opts(opts: &opts::BuildOpts) -> config::TargetMatch<'_> {
     config::TargetMatch {
         arch: &opts.arch,
         profile: if opts.release { RELEASE_STR } else { DEBUG_STR },
+        flavour: None,
     }
 }
 
@@ -148,6 +158,22 @@ fn main() {
             &workspace.chos,
             &find_all_projects(&workspace, target_from_opts(&opts.build)),
         ),
+        opts::Opts::Test(opts) => test_main(
+            &opts,
+            &workspace.chos,
+            &find_all_projects(
+                &workspace,
+                config::TargetMatch {
+                    flavour: Some(TEST_STR),
+                    ..target_from_opts(&opts.build)
+                },
+            ),
+        ),
+        opts::Opts::Lint(opts) => lint_main(
+            &opts,
+            &workspace.chos,
+            &find_all_projects(&workspace, target_from_opts(&opts.build)),
+        ),
         opts::Opts::Driver(opts) => driver_main(&opts),
         opts::Opts::Clean => clean_main(),
     }
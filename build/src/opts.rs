@@ -1,4 +1,3 @@
-
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -25,34 +24,53 @@ pub struct DeployOpts {
     pub output: String,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct QemuOpts {
+    #[structopt(long, short, default_value = "4G")]
+    pub mem: String,
+    #[structopt(long, short, default_value = "2")]
+    pub smp: usize,
+    /// Don't use KVM acceleration
+    #[structopt(long)]
+    pub no_kvm: bool,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct RunOpts {
     #[structopt(flatten)]
     pub build: BuildOpts,
+    #[structopt(flatten)]
+    pub qemu: QemuOpts,
     /// Start qemu in debug mode
     #[structopt(long, short = "d")]
     pub debug: bool,
-    #[structopt(long, short, default_value = "4G")]
-    pub mem: String,
-    #[structopt(long, short, default_value = "2")]
-    pub smp: usize,
     /// Don't rebuild before deploying
     #[structopt(long)]
     pub no_build: bool,
-    /// Don't use KVM acceleration
-    #[structopt(long)]
-    pub no_kvm: bool,
     /// Start QEMU with monitor set to 'curses' and serial to 'none'
     #[structopt(long)]
     pub curses: bool,
+    /// Boot with 'qemu -kernel' instead of creating a disk image
+    #[structopt(long)]
+    pub direct: bool,
+    /// Arguments passed on the kernel command line (requires --direct)
+    #[structopt(last = true)]
+    pub kernel_args: Vec<String>,
 }
 
 #[derive(StructOpt, Debug)]
 pub struct TestOpts {
     #[structopt(flatten)]
     pub build: BuildOpts,
-    #[structopt(long, short = "p")]
+    #[structopt(flatten)]
+    pub qemu: QemuOpts,
+    /// Only run the tests of these packages
+    #[structopt(long, short = "p", number_of_values = 1)]
     pub packages: Option<Vec<String>>,
+    /// Kill QEMU after this many seconds
+    #[structopt(long, default_value = "300")]
+    pub timeout: u64,
+    /// Only run the tests whose name contains one of these filters
     pub filters: Vec<String>,
 }
 
@@ -60,7 +78,8 @@ pub struct TestOpts {
 pub struct LintOpts {
     #[structopt(flatten)]
     pub build: BuildOpts,
-    #[structopt(long)]
+    /// Extra arguments for clippy (e.g. '-D warnings')
+    #[structopt(long, allow_hyphen_values = true)]
     pub clippy_args: Option<Vec<String>>,
 }
 
@@ -94,6 +113,12 @@ pub enum Opts {
     /// Build and run project
     #[structopt(visible_alias = "r")]
     Run(RunOpts),
+    /// Build and run the kernel tests
+    #[structopt(visible_alias = "t")]
+    Test(TestOpts),
+    /// Run clippy on all projects and drivers
+    #[structopt(visible_alias = "l")]
+    Lint(LintOpts),
     /// Driver projects management
     Driver(DriverOpts),
     /// Clean project
@@ -1,17 +1,28 @@
+use std::io::Write;
+
 use duct::cmd;
-use tempfile::Builder;
+use tempfile::{Builder, NamedTempFile};
 
 use crate::build::build_main;
-use crate::config::WorkspaceConfig;
-use crate::{Project, RunOpts};
+use crate::config::{ProjectType, WorkspaceConfig};
+use crate::image::build_tar;
+use crate::{binary_path, collect_files, BuildOpts, DeployFiles, Project, QemuOpts, RunOpts};
 
-const KERNEL_EXIT_SUCCESS: i32 = 33;
+pub const KERNEL_EXIT_SUCCESS: i32 = 33;
 
-pub fn run_main(opts: &RunOpts, workspace: &WorkspaceConfig, config: &[Project]) {
-    if opts.build.arch != "x86_64" {
-        panic!("Run not supported for {}", opts.build.arch);
+fn check_arch(opts: &BuildOpts) {
+    if opts.arch != "x86_64" {
+        panic!("Run not supported for {}", opts.arch);
     }
-    let initrd_drivers = build_main(&opts.build, workspace, config);
+}
+
+pub fn deploy_temp_image(
+    opts: &BuildOpts,
+    workspace: &WorkspaceConfig,
+    config: &[Project],
+) -> NamedTempFile {
+    check_arch(opts);
+    let initrd_drivers = build_main(opts, workspace, config);
     let imgfile = Builder::new()
         .prefix("chos")
         .suffix(".img")
@@ -20,52 +31,110 @@ pub fn run_main(opts: &RunOpts, workspace: &WorkspaceConfig, config: &[Project])
     crate::deploy(
         imgfile.path(),
         config,
-        opts.build.release,
+        opts.release,
         crate::DEPLOY_DEFAULT_SIZE,
         initrd_drivers,
     )
     .unwrap();
+    imgfile
+}
+
+fn find_project(config: &[Project], typ: ProjectType) -> &Project {
+    config
+        .iter()
+        .find(|proj| proj.typ == typ)
+        .unwrap_or_else(|| panic!("No {:?} project", typ))
+}
+
+// Multiboot modules are passed as '<file> <cmdline>,<file> <cmdline>' with 'qemu -kernel'
+pub fn direct_boot_args(
+    opts: &BuildOpts,
+    workspace: &WorkspaceConfig,
+    config: &[Project],
+    kernel_args: &[String],
+) -> (Vec<String>, NamedTempFile) {
+    check_arch(opts);
+    let initrd_drivers = build_main(opts, workspace, config);
+    let DeployFiles { initrd, .. } = collect_files(config, opts.release, &initrd_drivers).unwrap();
+
+    let mut initrd_file = Builder::new()
+        .prefix("chos")
+        .suffix(".tar")
+        .tempfile()
+        .unwrap();
+    initrd_file.write_all(&build_tar(&initrd).unwrap()).unwrap();
 
-    let smp = format!("{}", opts.smp);
-    let imgfile_str = imgfile.path().to_string_lossy();
-    let qemu = format!("qemu-system-{}", opts.build.arch);
+    let boot = binary_path(find_project(config, ProjectType::Boot), opts.release);
+    let kernel = binary_path(find_project(config, ProjectType::Kernel), opts.release);
+    let args = vec![
+        "-kernel".into(),
+        boot.to_string_lossy().into(),
+        "-initrd".into(),
+        format!(
+            "{} kernel,{} initrd",
+            kernel.to_string_lossy(),
+            initrd_file.path().to_string_lossy()
+        ),
+        "-append".into(),
+        kernel_args.join(" "),
+    ];
+    (args, initrd_file)
+}
+
+pub fn qemu_args(arch: &str, opts: &QemuOpts) -> Vec<String> {
     let (machine, cpu) = match !opts.no_kvm {
         true => ("q35,accel=kvm", "host"),
         false => ("q35", "Skylake-Client"),
     };
+    vec![
+        format!("qemu-system-{}", arch),
+        "-m".into(),
+        opts.mem.clone(),
+        "-smp".into(),
+        format!("{}", opts.smp),
+        "-machine".into(),
+        machine.into(),
+        "-cpu".into(),
+        cpu.into(),
+        "-device".into(),
+        "isa-debug-exit,iobase=0xf4,iosize=0x4".into(),
+        "-D".into(),
+        "target/qemu.log".into(),
+        "-d".into(),
+        "guest_errors".into(),
+    ]
+}
+
+pub fn run_main(opts: &RunOpts, workspace: &WorkspaceConfig, config: &[Project]) {
+    if !opts.kernel_args.is_empty() && !opts.direct {
+        panic!("Kernel arguments can only be passed with --direct");
+    }
+
+    let mut args = qemu_args(&opts.build.arch, &opts.qemu);
+    // Keeps the temporary files alive until qemu exits
+    let _tempfile = if opts.direct {
+        let (boot_args, initrd) =
+            direct_boot_args(&opts.build, workspace, config, &opts.kernel_args);
+        args.extend(boot_args);
+        initrd
+    } else {
+        let imgfile = deploy_temp_image(&opts.build, workspace, config);
+        args.push(imgfile.path().to_string_lossy().into());
+        imgfile
+    };
+
     let (display, serial) = match opts.curses {
         true => ("curses", "none"),
         false => ("none", "stdio"),
     };
-    let mut args = vec![
-        &*qemu,
-        "-m",
-        &opts.mem,
-        "-smp",
-        &smp,
-        "-machine",
-        machine,
-        "-cpu",
-        cpu,
-        "-device",
-        "isa-debug-exit,iobase=0xf4,iosize=0x4",
-        "-display",
-        display,
-        "-serial",
-        serial,
-        &*imgfile_str,
-        "-D",
-        "target/qemu.log",
-        "-d",
-        "guest_errors",
-    ];
+    args.extend(["-display", display, "-serial", serial].map(String::from));
 
     if opts.debug {
-        args.push("-s");
-        args.push("-S");
+        args.push("-s".into());
+        args.push("-S".into());
     }
 
-    let qemu = cmd("sudo", args)
+    let qemu = cmd(&args[0], &args[1..])
         .before_spawn(crate::display_cmd_hook)
         .unchecked()
         // .stderr_null()
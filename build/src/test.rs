@@ -0,0 +1,124 @@
+use std::io::{BufRead, BufReader};
+
+use duct::cmd;
+
+use crate::config::WorkspaceConfig;
+use crate::{deploy_temp_image, qemu_args, Project, TestOpts, KERNEL_EXIT_SUCCESS};
+
+const TEST_LINE_PREFIX: &str = "chos-test: ";
+const TEST_RESULT_SEP: &str = " ... ";
+const TIMEOUT_EXIT: i32 = 124;
+
+const FILTERS_ENV: &str = "CHOS_TEST_FILTERS";
+const PACKAGES_ENV: &str = "CHOS_TEST_PACKAGES";
+
+// The kernel tests check the system time against this date
+const TEST_RTC_BASE: &str = "2021-03-04T05:06:07";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TestStatus {
+    Ok,
+    Failed,
+    Timeout,
+}
+
+impl TestStatus {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "ok" => Some(Self::Ok),
+            "FAILED" => Some(Self::Failed),
+            "TIMEOUT" => Some(Self::Timeout),
+            _ => None,
+        }
+    }
+}
+
+fn strip_ansi(line: &str) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip until the final byte of the escape sequence
+            for c in &mut chars {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn parse_test_line(line: &str) -> Option<(String, TestStatus)> {
+    let line = strip_ansi(line);
+    let start = line.find(TEST_LINE_PREFIX)? + TEST_LINE_PREFIX.len();
+    let (name, status) = line[start..].trim_end().rsplit_once(TEST_RESULT_SEP)?;
+    Some((name.into(), TestStatus::from_str(status)?))
+}
+
+pub fn test_main(opts: &TestOpts, workspace: &WorkspaceConfig, config: &[Project]) {
+    // The test runner reads these at compile time
+    std::env::set_var(FILTERS_ENV, opts.filters.join(","));
+    match &opts.packages {
+        Some(packages) => std::env::set_var(
+            PACKAGES_ENV,
+            packages
+                .iter()
+                .map(|p| p.replace("-", "_"))
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        None => std::env::remove_var(PACKAGES_ENV),
+    }
+
+    let imgfile = deploy_temp_image(&opts.build, workspace, config);
+
+    let mut args = vec![format!("{}", opts.timeout)];
+    args.extend(qemu_args(&opts.build.arch, &opts.qemu));
+    args.push(imgfile.path().to_string_lossy().into());
+    args.extend(["-display", "none", "-serial", "stdio"].map(String::from));
+    args.extend(["-rtc".into(), format!("base={}", TEST_RTC_BASE)]);
+
+    let qemu = cmd("timeout", args)
+        .before_spawn(crate::display_cmd_hook)
+        .unchecked()
+        .reader()
+        .unwrap();
+
+    let mut results = Vec::new();
+    for line in BufReader::new(&qemu).lines() {
+        let line = line.unwrap();
+        println!("{}", line);
+        if let Some(result) = parse_test_line(&line) {
+            results.push(result);
+        }
+    }
+    let code = qemu
+        .try_wait()
+        .unwrap()
+        .expect("Qemu should have exited")
+        .status
+        .code()
+        .unwrap();
+
+    println!("==> Test results");
+    for (name, status) in &results {
+        if *status != TestStatus::Ok {
+            println!("    {:?}: {}", status, name);
+        }
+    }
+    let passed = results.iter().filter(|(_, s)| *s == TestStatus::Ok).count();
+    let failed = results.len() - passed;
+    println!("    {} passed; {} failed", passed, failed);
+
+    if code == TIMEOUT_EXIT {
+        eprintln!("Qemu timed out after {}s", opts.timeout);
+        std::process::exit(1);
+    }
+    if code != KERNEL_EXIT_SUCCESS || failed != 0 {
+        eprintln!("Tests failed, qemu exited with code {}", code);
+        std::process::exit(1);
+    }
+}
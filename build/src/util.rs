@@ -1,5 +1,5 @@
-
-use std::{process::{Command}, path::{Path, PathBuf}};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 pub struct ErrorGuard<F: FnOnce()> {
     f: Option<F>,
@@ -7,9 +7,7 @@ pub struct ErrorGuard<F: FnOnce()> {
 
 impl<F: FnOnce()> ErrorGuard<F> {
     pub fn new(on_err: F) -> Self {
-        Self {
-            f: Some(on_err),
-        }
+        Self { f: Some(on_err) }
     }
 
     pub fn defuse(mut self) {
@@ -25,7 +23,6 @@ impl<F: FnOnce()> Drop for ErrorGuard<F> {
     }
 }
 
-
 pub fn display_cmd(cmd: &Command) {
     print!("> {} ", cmd.get_program().to_string_lossy());
     for s in cmd.get_args() {
@@ -76,4 +73,4 @@ impl Target {
             Self::File(path) => path.to_str().expect("Invalid target name"),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,8 @@
+use proc_macro::TokenStream;
+
+mod test;
+
+#[proc_macro_attribute]
+pub fn kernel_test(attr: TokenStream, items: TokenStream) -> TokenStream {
+    test::parse_kernel_test(attr, items)
+}
@@ -0,0 +1,59 @@
+use proc_macro::TokenStream;
+use quote::quote;
+
+fn error(msg: &str) -> TokenStream {
+    quote! {
+        compile_error!(#msg);
+    }
+    .into()
+}
+
+pub fn parse_kernel_test(attr: TokenStream, items: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return error("Attributes must be empty");
+    }
+    let item = syn::parse_macro_input!(items as syn::ItemFn);
+    let sig = &item.sig;
+    if !sig.generics.params.is_empty() {
+        return error("Kernel tests cannot have generics");
+    }
+    if !sig.inputs.is_empty() {
+        return error("Kernel tests cannot take arguments");
+    }
+    if sig.unsafety.is_some() || sig.abi.is_some() {
+        return error("Kernel tests must be safe Rust functions");
+    }
+    match &sig.output {
+        syn::ReturnType::Default => (),
+        syn::ReturnType::Type(_, typ) => match &**typ {
+            syn::Type::Tuple(tuple) if tuple.elems.is_empty() => (),
+            _ => return error("Kernel tests must return ()"),
+        },
+    }
+    let name = &sig.ident;
+    let fun = if sig.asyncness.is_some() {
+        quote! {
+            ::chos::test::KernelTestFn::Async(|| ::chos::test::__box_test(#name()))
+        }
+    } else {
+        quote! {
+            ::chos::test::KernelTestFn::Sync(#name)
+        }
+    };
+    quote! {
+        ::chos::test::__kernel_test! {
+            #item
+
+            const _: () = {
+                #[used]
+                #[link_section = ::chos::test::__test_section!()]
+                static __CHOS_KERNEL_TEST: ::chos::test::KernelTestDecl =
+                    ::chos::test::KernelTestDecl::new(
+                        ::core::concat!(::core::module_path!(), "::", ::core::stringify!(#name)),
+                        #fun,
+                    );
+            };
+        }
+    }
+    .into()
+}
@@ -3,10 +3,11 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use chos_config::arch::mm::{stack, virt};
 use chos_lib::arch::acpi::madt;
 use chos_lib::arch::apic::{self, Apic};
-use chos_lib::arch::intr::{enable_interrupts, IoPl};
+use chos_lib::arch::intr::{enable_interrupts, without_interrupts, IoPl};
 use chos_lib::arch::ioapic::{self, IOApic};
-use chos_lib::arch::regs::{Cr2, Rsp};
+use chos_lib::arch::regs::{AllRegs, Cr2, Rsp};
 use chos_lib::arch::tables::{interrupt, Descriptor, Gdt, Idt, PageFaultError, StackFrame, Tss};
+use chos_lib::cpumask::Cpumask;
 use chos_lib::log::debug;
 use chos_lib::mm::VAddr;
 use chos_lib::sync::{SpinLazy, SpinOnceCell, Spinlock};
@@ -71,6 +72,7 @@ macro_rules! ioapic_intr {
                         handler(frame);
                     }
                     unsafe { LAPIC.as_mut_unchecked().eoi() };
+                    crate::sched::schedule_irq_exit();
                 }
             )*
         }
@@ -79,6 +81,46 @@ macro_rules! ioapic_intr {
 // Update if changing IOAPIC_MAX_INTR
 ioapic_intr!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23);
 
+pub const LAPIC_TIMER_VECTOR: u8 = IOAPIC_IDT_BASE + IOAPIC_MAX_INTR;
+static LAPIC_TIMER_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+#[interrupt]
+extern "x86-interrupt" fn lapic_timer_intr(frame: StackFrame) {
+    let handler = LAPIC_TIMER_HANDLER.load(Ordering::Relaxed);
+    if handler != 0 {
+        let handler: fn(StackFrame) = unsafe { core::mem::transmute(handler) };
+        handler(frame);
+    }
+    unsafe { LAPIC.as_mut_unchecked().eoi() };
+    crate::sched::schedule_irq_exit();
+}
+
+// Only used to get a CPU out of hlt, it will check for work by itself
+pub const WAKEUP_VECTOR: u8 = LAPIC_TIMER_VECTOR + 1;
+
+#[interrupt]
+extern "x86-interrupt" fn wakeup_intr(_: StackFrame) {
+    unsafe { LAPIC.as_mut_unchecked().eoi() };
+    crate::sched::schedule_irq_exit();
+}
+
+pub const TLB_SHOOTDOWN_VECTOR: u8 = WAKEUP_VECTOR + 1;
+
+#[interrupt]
+extern "x86-interrupt" fn tlb_shootdown_intr(_: StackFrame) {
+    crate::arch::mm::virt::handle_tlb_shootdown();
+    unsafe { LAPIC.as_mut_unchecked().eoi() };
+}
+
+pub const CALL_FUNCTION_VECTOR: u8 = TLB_SHOOTDOWN_VECTOR + 1;
+
+#[interrupt]
+extern "x86-interrupt" fn call_function_intr(_: StackFrame) {
+    crate::smp::handle_call_function();
+    unsafe { LAPIC.as_mut_unchecked().eoi() };
+    crate::sched::schedule_irq_exit();
+}
+
 fn is_addr_in_kernel(addr: VAddr) -> bool {
     addr >= virt::KERNEL_BASE.addr()
 }
@@ -127,6 +169,14 @@ extern "x86-interrupt" fn intr_breakpoint(frame: StackFrame) {
     );
 }
 
+#[interrupt]
+extern "x86-interrupt" fn intr_nmi(frame: StackFrame<AllRegs>) {
+    if crate::panic::in_panic() {
+        crate::panic::stop_this_cpu(&frame);
+    }
+    crate::sched::watchdog::on_nmi(&frame);
+}
+
 #[interrupt]
 extern "x86-interrupt" fn intr_page_fault(frame: StackFrame, error: PageFaultError) {
     let vaddr = Cr2::read();
@@ -168,6 +218,9 @@ static IDT: SpinLazy<Idt> = SpinLazy::new(|| {
     // Breakpoint
     idt.breakpoint.set_handler(intr_breakpoint);
 
+    // NMI, also used by a panicking CPU to stop the others
+    idt.non_maskable_interrupt.set_handler(intr_nmi);
+
     // Page Fault
     idt.page_fault
         .set_handler(intr_page_fault)
@@ -204,6 +257,11 @@ static IDT: SpinLazy<Idt> = SpinLazy::new(|| {
     idt[(IOAPIC_IDT_BASE + 22) as usize].set_handler(ioapic_intr_22);
     idt[(IOAPIC_IDT_BASE + 23) as usize].set_handler(ioapic_intr_23);
 
+    idt[LAPIC_TIMER_VECTOR as usize].set_handler(lapic_timer_intr);
+    idt[WAKEUP_VECTOR as usize].set_handler(wakeup_intr);
+    idt[TLB_SHOOTDOWN_VECTOR as usize].set_handler(tlb_shootdown_intr);
+    idt[CALL_FUNCTION_VECTOR as usize].set_handler(call_function_intr);
+
     idt
 });
 
@@ -284,6 +342,57 @@ pub unsafe fn arch_init_interrupts(args: &KernelArgs) {
         .expect("LAPIC already init");
 }
 
+/// The LAPIC registers are different on each CPU.
+///
+/// # Safety
+/// The caller must not be moved to another CPU while using the LAPIC.
+pub unsafe fn this_lapic() -> &'static mut Apic<'static> {
+    LAPIC.as_mut_unchecked()
+}
+
+pub fn set_lapic_timer_handler(intr_fn: fn(StackFrame)) {
+    LAPIC_TIMER_HANDLER.store(intr_fn as usize, Ordering::Relaxed);
+}
+
+pub fn arch_send_wakeup(cpu: usize) {
+    without_interrupts(|| unsafe {
+        this_lapic().commands().send(
+            apic::Destination::logical(Cpumask::for_cpu(cpu as u8)),
+            apic::Command::fixed(WAKEUP_VECTOR),
+        )
+    })
+}
+
+pub fn arch_send_tlb_shootdown(cpus: Cpumask) {
+    without_interrupts(|| unsafe {
+        this_lapic().commands().send(
+            apic::Destination::logical(cpus),
+            apic::Command::fixed(TLB_SHOOTDOWN_VECTOR),
+        )
+    })
+}
+
+pub fn arch_send_call_function(cpus: Cpumask) {
+    without_interrupts(|| unsafe {
+        this_lapic().commands().send(
+            apic::Destination::logical(cpus),
+            apic::Command::fixed(CALL_FUNCTION_VECTOR),
+        )
+    })
+}
+
+pub fn arch_send_nmi(cpus: Cpumask) {
+    // Can be called by a panic before the interrupts are initialized
+    if unsafe { LAPIC.try_get() }.is_none() {
+        return;
+    }
+    without_interrupts(|| unsafe {
+        this_lapic()
+            .commands()
+            .send(apic::Destination::logical(cpus), apic::Command::nmi())
+    })
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct IoApicAllocateError;
 
@@ -317,6 +426,28 @@ pub fn allocate_ioapic_interrupt(
     Err(IoApicAllocateError)
 }
 
+/// Routes the first free IOAPIC input in `mask` to an NMI on `cpus`, NMIs don't need a handler
+pub unsafe fn allocate_ioapic_nmi(mask: u64, cpus: Cpumask) -> Result<u8, IoApicAllocateError> {
+    let mut ioapic = IOAPIC.try_get().expect("IOApic not initialized").lock();
+    let mask = mask & ((1 << u8::min(ioapic.max_red_entries(), IOAPIC_MAX_INTR)) - 1);
+    for n in (0..IOAPIC_MAX_INTR).filter(|&n| mask & (1 << n) != 0) {
+        if ioapic.update_redirection(n, |red| {
+            if red.enabled() {
+                false
+            } else {
+                red.set_defaults();
+                red.set_delivery_mode(ioapic::DeliveryMode::NMI);
+                red.set_destination(ioapic::Destination::Logical(cpus.raw() as u8));
+                red.enable();
+                true
+            }
+        }) {
+            return Ok(n);
+        }
+    }
+    Err(IoApicAllocateError)
+}
+
 pub unsafe fn free_ioapic_interrupt(n: u8) {
     let mut ioapic = IOAPIC.try_get().expect("IOApic not initialized").lock();
     ioapic.update_redirection(n, |red| {
@@ -1,15 +1,22 @@
 use core::alloc::AllocError;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use chos_config::arch::mm::virt;
-use chos_lib::arch::mm::{FrameSize4K, OffsetMapper, PageTable};
+use chos_lib::arch::intr::without_interrupts;
+use chos_lib::arch::mm::{Flush, FrameSize4K, OffsetMapper, PageTable};
+use chos_lib::cpumask::Cpumask;
 use chos_lib::mm::{
-    FrameAllocator, FrameSize, LoggingMapper, MapFlags, MapperFlush, PAddr, PFrame, PFrameRange,
-    RangeMapper, VAddr, VFrame,
+    FrameAllocator, FrameSize, LoggingMapper, MapFlags, Mapper, MapperFlush, PAddr, PFrame,
+    PFrameRange, RangeMapper, UnmapError, VAddr, VFrame, VFrameRange,
 };
+use chos_lib::sync::Spinlock;
 
 use crate::arch::early::{copy_early_kernel_table_to, early_paddr_of};
+use crate::arch::intr::arch_send_tlb_shootdown;
+use crate::cpumask;
 use crate::mm::phys::{raw_alloc, AllocFlags, Page};
-use crate::mm::{per_cpu, per_cpu_lazy, PerCpu};
+use crate::mm::{per_cpu, this_cpu_info, PerCpu};
+use crate::smp::spin_until_handling_ipis;
 
 pub struct MMFrameAllocator;
 
@@ -29,16 +36,58 @@ unsafe impl FrameAllocator<FrameSize4K> for MMFrameAllocator {
     }
 }
 
+// Keeps the page tables freed by an unmap until the TLBs are flushed, the other CPUs might still
+// have them cached. They are chained through their first entry.
+struct DeferredFree {
+    head: Option<VFrame<FrameSize4K>>,
+}
+
+unsafe impl FrameAllocator<FrameSize4K> for DeferredFree {
+    type Error = AllocError;
+    unsafe fn alloc_frame(&mut self) -> Result<VFrame<FrameSize4K>, Self::Error> {
+        Err(AllocError)
+    }
+    unsafe fn dealloc_frame(&mut self, frame: VFrame<FrameSize4K>) -> Result<(), Self::Error> {
+        *frame.addr().as_mut_ptr::<Option<VFrame<FrameSize4K>>>() = self.head.replace(frame);
+        Ok(())
+    }
+}
+
+impl DeferredFree {
+    // The TLBs that could hold the page tables must have been flushed
+    unsafe fn free(self) {
+        let mut head = self.head;
+        while let Some(frame) = head {
+            head = *frame.addr().as_ptr::<Option<VFrame<FrameSize4K>>>();
+            MMFrameAllocator.dealloc_frame(frame).unwrap();
+        }
+    }
+}
+
 per_cpu! {
     static mut ref PAGE_TABLE: PageTable = PageTable::empty();
+    // The other CPUs also change this page table when they unmap vmalloc pages
+    static mut ref PAGE_TABLE_LOCK: Spinlock<()> = Spinlock::new(());
 }
 
-per_cpu_lazy! {
-    static mut ref MAPPER: LoggingMapper<OffsetMapper<'static>> = unsafe { LoggingMapper::new(OffsetMapper::new(PAGE_TABLE.get_mut(), virt::PHYSICAL_MAP_BASE.addr())) };
+// Only changes the page table of `cpu`, its TLB is not flushed
+fn with_mapper<R>(cpu: usize, f: impl FnOnce(&mut LoggingMapper<OffsetMapper<'static>>) -> R) -> R {
+    let _guard = unsafe { &*PAGE_TABLE_LOCK.get_for(cpu) }.lock_noirq();
+    let mut mapper = unsafe {
+        LoggingMapper::new(OffsetMapper::new(
+            &mut *PAGE_TABLE.get_for(cpu),
+            virt::PHYSICAL_MAP_BASE.addr(),
+        ))
+    };
+    f(&mut mapper)
+}
+
+fn with_this_mapper<R>(f: impl FnOnce(&mut LoggingMapper<OffsetMapper<'static>>) -> R) -> R {
+    without_interrupts(|| with_mapper(this_cpu_info().id, f))
 }
 
 pub unsafe fn init_kernel_virt() {
-    MAPPER.with(|mapper| {
+    with_this_mapper(|mapper| {
         copy_early_kernel_table_to(mapper);
         let vaddr = VAddr::from(&*mapper.p4);
         let paddr = early_paddr_of(vaddr).expect("PerCpu should be mapped");
@@ -57,7 +106,7 @@ where
     if flags.contains(MapFlags::IMM_ALL_CPUS) {
         todo!("Notify all cpus to map the range");
     }
-    MAPPER.with(|mapper| unsafe {
+    with_this_mapper(|mapper| unsafe {
         mapper
             .map_range(range, vbase, flags, &mut MMFrameAllocator)
             .map_err(|err| {
@@ -79,3 +128,132 @@ where
 pub fn map_page(page: &Page, vbase: VFrame, flags: MapFlags) -> Result<(), AllocError> {
     map_frames(page.frame_range(), vbase, flags)
 }
+
+/// Unmaps the range on this CPU and on the CPUs in `cpus`. Every CPU has its own page table, the
+/// entries are removed from here and the other CPUs only flush their TLB.
+/// Waits for the other CPUs, see [`shootdown`].
+pub fn unmap_frames_on(range: VFrameRange, cpus: Cpumask) {
+    let mut freed = DeferredFree { head: None };
+    let flush = (cpus | cpumask::this_cpu())
+        .into_iter()
+        .map(|cpu| {
+            let flush = with_mapper(cpu as usize, |mapper| {
+                range.into_iter().fold(Flush::None, |flush, vframe| unsafe {
+                    match Mapper::<FrameSize4K>::unmap(mapper, vframe, &mut freed) {
+                        Ok(unmapped) => flush.combine(unmapped),
+                        Err(UnmapError::NotMapped) => flush,
+                        Err(err) => panic!("Unmap error {:?}, tried to unmap {:#x}", err, vframe),
+                    }
+                })
+            });
+            TlbShootdown::new(flush, Cpumask::for_cpu(cpu))
+        })
+        .fold(TlbShootdown::NONE, TlbShootdown::combine);
+    flush.flush();
+    unsafe { freed.free() };
+}
+
+/// Flush of the TLB of this CPU and of the CPUs in `cpus`. The other CPUs are only interrupted
+/// once [`MapperFlush::flush`] is called, combine the flushes to send a single shootdown.
+#[must_use = "Must flush or ignore"]
+pub struct TlbShootdown {
+    flush: Flush<FrameSize4K>,
+    cpus: Cpumask,
+}
+
+impl TlbShootdown {
+    pub fn new(flush: Flush<FrameSize4K>, cpus: Cpumask) -> Self {
+        Self { flush, cpus }
+    }
+}
+
+impl MapperFlush for TlbShootdown {
+    const NONE: Self = Self {
+        flush: Flush::None,
+        cpus: Cpumask::empty(),
+    };
+
+    fn flush(self) {
+        let op = match &self.flush {
+            Flush::All => ShootdownOp::FlushAll,
+            Flush::Range(range) => ShootdownOp::Flush(*range),
+            Flush::None => return,
+        };
+        self.flush.flush();
+        shootdown(self.cpus, op);
+    }
+
+    fn combine(self, rhs: Self) -> Self {
+        Self {
+            flush: self.flush.combine(rhs.flush),
+            cpus: self.cpus | rhs.cpus,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ShootdownOp {
+    FlushAll,
+    Flush(VFrameRange),
+}
+
+// Only one shootdown at a time, SHOOTDOWN_OP is written while holding it
+static SHOOTDOWN_LOCK: Spinlock<()> = Spinlock::new(());
+static SHOOTDOWN_OP: Spinlock<ShootdownOp> = Spinlock::new(ShootdownOp::FlushAll);
+// CPUs that did not acknowledge the current shootdown yet
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Sends the operation to the CPUs in `cpus` (except this one) and waits until they are all done.
+/// The caller must not hold a lock that the other CPUs could be waiting for with interrupts
+/// disabled.
+fn shootdown(cpus: Cpumask, op: ShootdownOp) {
+    without_interrupts(|| {
+        let cpus = cpus & cpumask::all_but_this_cpu();
+        if cpus == Cpumask::empty() {
+            return;
+        }
+        let mut guard = None;
+        spin_until_handling_ipis(|| {
+            guard = SHOOTDOWN_LOCK.try_lock_nodisable();
+            guard.is_some()
+        });
+        *SHOOTDOWN_OP.lock_nodisable() = op;
+        SHOOTDOWN_PENDING.store(cpus.raw(), Ordering::Release);
+        arch_send_tlb_shootdown(cpus);
+        spin_until_handling_ipis(|| SHOOTDOWN_PENDING.load(Ordering::Acquire) == 0);
+        drop(guard);
+    })
+}
+
+/// Called from the shootdown IPI, does nothing if this CPU has nothing to do. Only flushes the TLB,
+/// the page tables were already changed by the CPU that sent it.
+pub fn handle_tlb_shootdown() {
+    let this_cpu = cpumask::this_cpu().raw();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & this_cpu == 0 {
+        return;
+    }
+    let op = *SHOOTDOWN_OP.lock_nodisable();
+    match op {
+        ShootdownOp::FlushAll => Flush::<FrameSize4K>::All.flush(),
+        ShootdownOp::Flush(range) => Flush::Range(range).flush(),
+    }
+    SHOOTDOWN_PENDING.fetch_and(!this_cpu, Ordering::Release);
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    fn tlb_shootdown_is_acknowledged() {
+        let vframe = virt::HEAP_BASE;
+        TlbShootdown::new(
+            Flush::Range(VFrameRange::new(vframe, vframe.add(1))),
+            cpumask::all(),
+        )
+        .combine(TlbShootdown::new(Flush::All, cpumask::all()))
+        .flush();
+        assert_eq!(SHOOTDOWN_PENDING.load(Ordering::Relaxed), 0);
+    }
+}
@@ -36,6 +36,13 @@ unsafe extern "C" fn switch_task(new_stack: VAddr, old_state: *mut ArchTaskState
     // RDI = new_stack
     // RSI = old_stack
     asm!(
+        // Callee-saved registers, the task can be switched from anywhere (e.g. preempted)
+        "push %rbx",
+        "push %rbp",
+        "push %r12",
+        "push %r13",
+        "push %r14",
+        "push %r15",
         "mov %rsp, %rax",
         "pushq $0",  // SS
         "push %rax", // RSP
@@ -68,6 +75,12 @@ unsafe extern "C" fn switch_task(new_stack: VAddr, old_state: *mut ArchTaskState
         "add $8, %rsp", // Error
         "iretq",
         "0:",
+        "pop %r15",
+        "pop %r14",
+        "pop %r13",
+        "pop %r12",
+        "pop %rbp",
+        "pop %rbx",
         "ret",
         KERNEL_CS = const KERNEL_CS as u64,
         options(att_syntax, noreturn),
@@ -1,68 +1,263 @@
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use chos_config::arch::mm::virt;
 use chos_config::timer::TICKS_HZ;
 use chos_lib::arch::acpi::Rsdt;
-use chos_lib::arch::hpet::{Hpet, TimerType};
-use chos_lib::arch::ioapic;
+use chos_lib::arch::apic::{InterruptMask, TimerDivide, TimerInterrupt, TimerMode};
+use chos_lib::arch::hpet::Hpet;
+use chos_lib::arch::rtc::Rtc;
 use chos_lib::arch::tables::StackFrame;
-use chos_lib::int::CeilDiv;
-use chos_lib::log::debug;
+use chos_lib::arch::tsc::{has_invariant_tsc, has_tsc_deadline, rdtsc, set_tsc_deadline};
+use chos_lib::cpumask::Cpumask;
+use chos_lib::log::{debug, warn};
+use chos_lib::sync::SpinOnceCell;
+use chos_lib::time::DateTime;
 
-use super::intr::allocate_ioapic_interrupt;
+use super::intr::{allocate_ioapic_nmi, set_lapic_timer_handler, this_lapic, LAPIC_TIMER_VECTOR};
 use crate::kmain::KernelArgs;
-use crate::mm::this_cpu_info;
-use crate::timer::{on_tick, on_tick_main_cpu, NS_PER_TICKS};
+use crate::timer::{on_timer_interrupt, ClockSource, Instant};
 
-const PIT_TIMER_IOAPIC_INTR: u8 = 2;
-const APIC_INTR_MASK: u32 = !(1 << PIT_TIMER_IOAPIC_INTR);
+const NS_PER_SEC: u64 = 1_000_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+const CALIBRATION_NS: u64 = 10_000_000;
+const LAPIC_TIMER_DIVIDE: TimerDivide = TimerDivide::By16;
+const TSC_SHIFT: u32 = 32;
+// How far the TSC of a CPU can be from the HPET, covers the calibration error
+const TSC_MAX_SKEW_NS: u64 = 100_000;
 
 static mut HPET: MaybeUninit<Hpet> = MaybeUninit::uninit();
 
+// Calibrated against the HPET by CPU 0, the LAPIC timers of all CPUs run at the same rate
+static LAPIC_HZ: AtomicU64 = AtomicU64::new(0);
+// 0 if TSC-deadline mode is not supported
+static TSC_DEADLINE_HZ: AtomicU64 = AtomicU64::new(0);
+
+fn hpet() -> &'static Hpet {
+    unsafe { HPET.assume_init_ref() }
+}
+
+struct HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read_ns(&self) -> u64 {
+        let hpet = hpet();
+        (hpet.count() as u128 * hpet.period() as u128 / FS_PER_NS as u128) as u64
+    }
+}
+
+static HPET_CLOCK: HpetClock = HpetClock;
+
+// Only used if the TSC is invariant, every CPU checks that its TSC is synchronized with the others
+struct TscClock {
+    base_tsc: u64,
+    base_ns: u64,
+    // Nanoseconds per cycle, shifted by TSC_SHIFT
+    mult: u64,
+    // Set when a CPU's TSC is too far from the others, the HPET is used instead
+    unstable: AtomicBool,
+}
+
+impl TscClock {
+    fn tsc_ns(&self) -> u64 {
+        let cycles = rdtsc().saturating_sub(self.base_tsc);
+        self.base_ns + ((cycles as u128 * self.mult as u128) >> TSC_SHIFT) as u64
+    }
+}
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read_ns(&self) -> u64 {
+        if self.unstable.load(Ordering::Relaxed) {
+            return HPET_CLOCK.read_ns();
+        }
+        self.tsc_ns()
+    }
+}
+
+static TSC_CLOCK: SpinOnceCell<TscClock> = SpinOnceCell::new();
+
+fn hpet_wait(hpet: &Hpet, ns: u64) {
+    let count = ns * FS_PER_NS / hpet.period() as u64;
+    let start = hpet.count();
+    while hpet.count().wrapping_sub(start) < count {
+        core::hint::spin_loop();
+    }
+}
+
+const fn per_sec(count: u64, ns: u64) -> u64 {
+    (count as u128 * NS_PER_SEC as u128 / ns as u128) as u64
+}
+
+const fn count_for(ns: u64, hz: u64) -> u64 {
+    (ns as u128 * hz as u128 / NS_PER_SEC as u128) as u64
+}
+
+// Runs the LAPIC timer and the TSC against the HPET, returns their frequencies
+unsafe fn calibrate(hpet: &Hpet) -> (u64, u64) {
+    let lapic = this_lapic();
+    lapic.timer_mut().write(
+        TimerInterrupt::new()
+            .with_vector(LAPIC_TIMER_VECTOR)
+            .with_mode(TimerMode::OneShot)
+            .with_mask(InterruptMask::Disabled),
+    );
+    lapic.set_timer_divide(LAPIC_TIMER_DIVIDE);
+
+    let start = HPET_CLOCK.read_ns();
+    lapic.set_timer_initial_count(u32::MAX);
+    let tsc_start = rdtsc();
+    hpet_wait(hpet, CALIBRATION_NS);
+    let lapic_count = u32::MAX - lapic.timer_current_count();
+    let tsc_count = rdtsc() - tsc_start;
+    let elapsed = HPET_CLOCK.read_ns() - start;
+    lapic.set_timer_initial_count(0);
+
+    (
+        per_sec(lapic_count as u64, elapsed),
+        per_sec(tsc_count, elapsed),
+    )
+}
+
 fn timer_intr_handler(_: StackFrame) {
-    let id = this_cpu_info().id;
-    if id == 0 {
-        on_tick_main_cpu();
-    } else {
-        on_tick()
+    on_timer_interrupt()
+}
+
+/// Programs the local timer to interrupt at `deadline`, or not at all.
+/// Must be called with interrupts disabled.
+pub fn arch_set_next_event(deadline: Option<Instant>) {
+    let tsc_hz = TSC_DEADLINE_HZ.load(Ordering::Relaxed);
+    let delta = deadline.map(|deadline| deadline.duration_since(Instant::now()).as_nanos() as u64);
+    unsafe {
+        match (delta, tsc_hz) {
+            (None, 0) => this_lapic().set_timer_initial_count(0),
+            (None, _) => set_tsc_deadline(0),
+            (Some(delta), 0) => {
+                let count = count_for(delta, LAPIC_HZ.load(Ordering::Relaxed));
+                // Wakes up early if it doesn't fit, the next event is programmed again then
+                this_lapic().set_timer_initial_count(count.clamp(1, u32::MAX as u64) as u32);
+            }
+            (Some(delta), _) => set_tsc_deadline(rdtsc() + count_for(delta, tsc_hz).max(1)),
+        }
     }
 }
 
-pub fn arch_init_timer(args: &KernelArgs) {
+pub fn arch_init_timer(args: &KernelArgs) -> &'static dyn ClockSource {
     let rsdt = unsafe { Rsdt::new_offset(args.arch.rsdt, virt::PHYSICAL_MAP_BASE.addr()) };
     let hpet_tbl = rsdt.hpet().expect("Need HPET table");
 
     let mut hpet = unsafe { Hpet::new(virt::DEVICE_BASE.addr() + hpet_tbl.address as u64) };
 
+    // The ticks come from the LAPIC timers, the HPET is used as a clock and for the watchdog NMI
     unsafe {
-        let period = hpet.period();
-        let comparator = (NS_PER_TICKS * 1_000_000).ceil_div(period as u64);
-
-        debug!(
-            "HPET Period is {}fs, setting comparator to {} ({}Hz)",
-            period, comparator, TICKS_HZ
-        );
-
         hpet.disable();
+        hpet.set_count(0);
+        hpet.enable();
+        HPET = MaybeUninit::new(hpet);
+    }
 
-        let mut timer = hpet.get_timer_mut(0);
+    let (lapic_hz, tsc_hz) = unsafe { calibrate(hpet()) };
+    let tsc_deadline = has_tsc_deadline();
+    let invariant_tsc = has_invariant_tsc();
+    debug!(
+        "Timer at {}Hz, LAPIC timer at {}Hz, TSC at {}Hz (TSC-deadline: {}, invariant: {}, HPET period: {}fs)",
+        TICKS_HZ,
+        lapic_hz,
+        tsc_hz,
+        tsc_deadline,
+        invariant_tsc,
+        hpet().period(),
+    );
+    LAPIC_HZ.store(lapic_hz, Ordering::Relaxed);
+    if tsc_deadline {
+        TSC_DEADLINE_HZ.store(tsc_hz, Ordering::Relaxed);
+    }
+    set_lapic_timer_handler(timer_intr_handler);
 
-        let mask = timer.int_route_mask() & APIC_INTR_MASK;
-        let intr = allocate_ioapic_interrupt(
-            mask as u64,
-            timer_intr_handler,
-            ioapic::Destination::Logical((1 << args.core_count) - 1),
-        )
-        .expect("No interrupt free for timer");
+    if invariant_tsc {
+        // Starts where the HPET clock is, so that time doesn't go backward
+        let base_ns = HPET_CLOCK.read_ns();
+        let base_tsc = rdtsc();
+        TSC_CLOCK.get_or_set(TscClock {
+            base_tsc,
+            base_ns,
+            mult: (((NS_PER_SEC as u128) << TSC_SHIFT) / tsc_hz as u128) as u64,
+            unstable: AtomicBool::new(false),
+        })
+    } else {
+        &HPET_CLOCK
+    }
+}
 
-        timer.set_int_route(intr);
-        timer.set_type(TimerType::Periodic);
-        timer.enable();
-        timer.set_comparator(comparator);
+/// Only called once at boot, the RTC is not used afterwards
+pub fn arch_read_rtc() -> DateTime {
+    unsafe { Rtc::new() }.read()
+}
 
-        hpet.set_count(0);
-        hpet.enable();
+// An invariant TSC runs at a constant rate, but the firmware might not have started it at the same
+// time on every CPU. The switch to the HPET only happens while the CPUs are started.
+fn check_tsc_sync() {
+    let tsc = match TSC_CLOCK.try_get() {
+        Some(tsc) if !tsc.unstable.load(Ordering::Relaxed) => tsc,
+        _ => return,
+    };
+    let before = HPET_CLOCK.read_ns();
+    let ns = tsc.tsc_ns();
+    let after = HPET_CLOCK.read_ns();
+    if ns + TSC_MAX_SKEW_NS < before || ns > after + TSC_MAX_SKEW_NS {
+        tsc.unstable.store(true, Ordering::Relaxed);
+        warn!("TSC is not synchronized between CPUs, using the HPET as the clock");
     }
+}
 
-    unsafe { HPET = MaybeUninit::new(hpet) };
+/// Sets up the local timer on this CPU, after [`arch_init_timer`] ran on CPU 0
+pub fn arch_init_timer_cpu() {
+    check_tsc_sync();
+    let mode = match TSC_DEADLINE_HZ.load(Ordering::Relaxed) {
+        0 => TimerMode::OneShot,
+        _ => TimerMode::TscDeadline,
+    };
+    unsafe {
+        let lapic = this_lapic();
+        lapic.set_timer_divide(LAPIC_TIMER_DIVIDE);
+        lapic.timer_mut().write(
+            TimerInterrupt::new()
+                .with_vector(LAPIC_TIMER_VECTOR)
+                .with_mode(mode)
+                .with_mask(InterruptMask::Enabled),
+        );
+    }
+}
+
+/// Makes the HPET send an NMI to `cpus` every `ns`, it doesn't depend on any CPU's tick. Returns
+/// false if no HPET timer can be routed as a periodic NMI.
+pub fn arch_start_periodic_nmi(ns: u64, cpus: Cpumask) -> bool {
+    let hpet = unsafe { HPET.assume_init_mut() };
+    let period = (ns * FS_PER_NS / hpet.period() as u64).max(1);
+    for n in 0..hpet.timer_count() {
+        let start = hpet.count() + period;
+        let mut timer = hpet.get_timer_mut(n);
+        if !timer.supports_periodic() {
+            continue;
+        }
+        let route = match unsafe { allocate_ioapic_nmi(timer.int_route_mask() as u64, cpus) } {
+            Ok(route) => route,
+            Err(_) => continue,
+        };
+        unsafe {
+            timer.set_int_route(route);
+            timer.set_periodic(start, period);
+            timer.enable();
+        }
+        debug!("HPET timer {} sends the NMI on IOAPIC input {}", n, route);
+        return true;
+    }
+    false
 }
@@ -0,0 +1,147 @@
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use chos_lib::sync::Spinlock;
+
+use super::sem::{Waiter, WaiterList};
+
+struct BarrierState {
+    count: usize,
+    // Incremented every time the barrier is released
+    generation: usize,
+    waiters: WaiterList,
+}
+
+/// Waits until `n` tasks have reached the barrier. The barrier can be reused once released.
+pub struct AsyncBarrier {
+    n: usize,
+    state: Spinlock<BarrierState>,
+}
+
+impl AsyncBarrier {
+    pub const fn new(n: usize) -> Self {
+        Self {
+            n,
+            state: Spinlock::new(BarrierState {
+                count: 0,
+                generation: 0,
+                waiters: WaiterList::new(),
+            }),
+        }
+    }
+
+    pub fn wait(&self) -> AsyncBarrierWaitFut<'_> {
+        AsyncBarrierWaitFut {
+            barrier: self,
+            waiter: Waiter::one(),
+            generation: None,
+            pinned: PhantomPinned,
+        }
+    }
+}
+
+pub struct BarrierWaitResult {
+    leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// True for exactly one of the tasks released by the barrier
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct AsyncBarrierWaitFut<'a> {
+    barrier: &'a AsyncBarrier,
+    waiter: Waiter,
+    // Generation we arrived in, None until the first poll
+    generation: Option<usize>,
+    pinned: PhantomPinned,
+}
+
+impl Future for AsyncBarrierWaitFut<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut state = this.barrier.state.lock();
+        let waiter = unsafe { Pin::new_unchecked(&mut this.waiter) };
+        match this.generation {
+            None => {
+                state.count += 1;
+                if state.count >= this.barrier.n {
+                    state.count = 0;
+                    state.generation = state.generation.wrapping_add(1);
+                    state.waiters.wake_all();
+                    return Poll::Ready(BarrierWaitResult { leader: true });
+                }
+                this.generation = Some(state.generation);
+            }
+            Some(generation) if generation != state.generation => {
+                this.generation = None;
+                return Poll::Ready(BarrierWaitResult { leader: false });
+            }
+            Some(_) => (),
+        }
+        state.waiters.add_to_waitlist(waiter, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for AsyncBarrierWaitFut<'_> {
+    fn drop(&mut self) {
+        if let Some(generation) = self.generation {
+            let mut state = self.barrier.state.lock();
+            state
+                .waiters
+                .remove(unsafe { Pin::new_unchecked(&mut self.waiter) });
+            // Not released yet, we don't count anymore
+            if generation == state.generation {
+                state.count -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::async_::join_all;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn barrier_releases_all() {
+        const N: usize = 4;
+        let state = Arc::new((AsyncBarrier::new(N), AtomicUsize::new(0)));
+        let tasks: Vec<_> = (0..N)
+            .map(|_| {
+                let state = state.clone();
+                spawn_future(
+                    async move {
+                        let (barrier, arrived) = &*state;
+                        let mut leaders = 0;
+                        for round in 1..=2 {
+                            arrived.fetch_add(1, Ordering::Relaxed);
+                            if barrier.wait().await.is_leader() {
+                                leaders += 1;
+                            }
+                            assert!(arrived.load(Ordering::Relaxed) >= round * N);
+                        }
+                        leaders
+                    },
+                    "[barrier]",
+                )
+            })
+            .collect();
+        let leaders: usize = join_all(tasks).await.into_iter().map(Result::unwrap).sum();
+        assert_eq!(leaders, 2);
+    }
+}
@@ -0,0 +1,269 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use chos_lib::sync::Spinlock;
+
+use super::mpsc::SendError;
+use super::sem::{Waiter, WaiterList};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver missed that many values, the next receive returns the oldest one still kept
+    Lagged(u64),
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Lagged(u64),
+    Closed,
+}
+
+struct Shared<T> {
+    // The last `capacity` values, buf[0] has the sequence number `head`
+    buf: VecDeque<T>,
+    capacity: usize,
+    head: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: WaiterList,
+}
+
+impl<T> Shared<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.buf.len() as u64
+    }
+}
+type SharedPtr<T> = Arc<Spinlock<Shared<T>>>;
+
+/// Sending half of a broadcast channel, every receiver gets every value.
+/// Only the last `capacity` values are kept, a receiver that falls behind gets [`RecvError::Lagged`].
+pub struct Sender<T> {
+    shared: SharedPtr<T>,
+}
+
+impl<T> Sender<T> {
+    /// Returns the number of receivers that will see the value
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut shared = self.shared.lock_noirq();
+        if shared.receivers == 0 {
+            return Err(SendError(value));
+        }
+        if shared.buf.len() == shared.capacity {
+            shared.buf.pop_front();
+            shared.head += 1;
+        }
+        shared.buf.push_back(value);
+        shared.waiters.wake_all();
+        Ok(shared.receivers)
+    }
+
+    /// New receiver that gets the values sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut shared = self.shared.lock();
+        shared.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: shared.tail(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            shared.waiters.wake_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: SharedPtr<T>,
+    // Sequence number of the next value to receive
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    fn try_recv_locked(&mut self, shared: &Shared<T>) -> Result<T, TryRecvError> {
+        if self.next < shared.head {
+            let lagged = shared.head - self.next;
+            self.next = shared.head;
+            return Err(TryRecvError::Lagged(lagged));
+        }
+        if self.next < shared.tail() {
+            let value = shared.buf[(self.next - shared.head) as usize].clone();
+            self.next += 1;
+            return Ok(value);
+        }
+        if shared.senders == 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = self.shared.clone();
+        let shared = shared.lock();
+        self.try_recv_locked(&shared)
+    }
+
+    pub fn recv(&mut self) -> RecvFut<'_, T> {
+        RecvFut {
+            receiver: self,
+            waiter: Waiter::one(),
+            queued: false,
+            pinned: PhantomPinned,
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().receivers += 1;
+        Self {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receivers -= 1;
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct RecvFut<'a, T> {
+    receiver: &'a mut Receiver<T>,
+    waiter: Waiter,
+    queued: bool,
+    pinned: PhantomPinned,
+}
+
+impl<T: Clone> Future for RecvFut<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let shared = this.receiver.shared.clone();
+        let mut shared = shared.lock();
+        let res = match this.receiver.try_recv_locked(&shared) {
+            Ok(value) => Ok(value),
+            Err(TryRecvError::Lagged(lagged)) => Err(RecvError::Lagged(lagged)),
+            Err(TryRecvError::Closed) => Err(RecvError::Closed),
+            Err(TryRecvError::Empty) => {
+                this.queued = true;
+                shared.waiters.add_to_waitlist(
+                    unsafe { Pin::new_unchecked(&mut this.waiter) },
+                    cx.waker().clone(),
+                );
+                return Poll::Pending;
+            }
+        };
+        if this.queued {
+            this.queued = false;
+            shared
+                .waiters
+                .remove(unsafe { Pin::new_unchecked(&mut this.waiter) });
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<T> Drop for RecvFut<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            // Everyone is woken on send, there is no wakeup to give back
+            self.receiver
+                .shared
+                .lock()
+                .waiters
+                .remove(unsafe { Pin::new_unchecked(&mut self.waiter) });
+        }
+    }
+}
+
+/// Broadcast channel that keeps the last `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "Broadcast channel needs a capacity");
+    let shared = Arc::new(Spinlock::new(Shared {
+        buf: VecDeque::with_capacity(capacity),
+        capacity,
+        head: 0,
+        senders: 1,
+        receivers: 1,
+        waiters: WaiterList::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn broadcast_to_all() {
+        let (tx, mut rx1) = channel(4);
+        let mut rx2 = tx.subscribe();
+        let consumer = spawn_future(
+            async move {
+                let mut sum = 0;
+                while let Ok(v) = rx2.recv().await {
+                    sum += v;
+                }
+                sum
+            },
+            "[broadcast]",
+        );
+        for i in 1..=3 {
+            assert_eq!(tx.send(i), Ok(2));
+        }
+        drop(tx);
+        assert_eq!(consumer.await, Ok(6));
+        assert_eq!(rx1.recv().await, Ok(1));
+        assert_eq!(rx1.try_recv(), Ok(2));
+    }
+
+    #[kernel_test]
+    async fn broadcast_lagged() {
+        let (tx, mut rx) = channel(2);
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(rx.recv().await, Err(RecvError::Lagged(3)));
+        assert_eq!(rx.recv().await, Ok(3));
+        assert_eq!(rx.recv().await, Ok(4));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        drop(tx);
+        assert_eq!(rx.recv().await, Err(RecvError::Closed));
+    }
+}
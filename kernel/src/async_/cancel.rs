@@ -0,0 +1,225 @@
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::mem::take;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use chos_lib::sync::Spinlock;
+use pin_project::pin_project;
+
+use super::sem::{Waiter, WaiterList};
+
+struct TokenState {
+    waiters: WaiterList,
+    children: Vec<Weak<TokenInner>>,
+}
+
+struct TokenInner {
+    cancelled: AtomicBool,
+    state: Spinlock<TokenState>,
+}
+
+impl TokenInner {
+    fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            state: Spinlock::new(TokenState {
+                waiters: WaiterList::new(),
+                children: Vec::new(),
+            }),
+        }
+    }
+
+    fn cancel(&self) {
+        let children = {
+            let mut state = self.state.lock_noirq();
+            if self.cancelled.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            state.waiters.wake_all();
+            take(&mut state.children)
+        };
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+}
+
+/// Shared cancellation flag for async operations.
+/// Cancelling a token also cancels all the tokens created with [`CancellationToken::child_token`].
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(TokenInner::new()),
+        }
+    }
+
+    /// Creates a token that is cancelled with this one, but can also be cancelled on its own
+    pub fn child_token(&self) -> Self {
+        let child = Self::new();
+        {
+            let mut state = self.inner.state.lock();
+            if !self.is_cancelled() {
+                state.children.retain(|child| child.strong_count() != 0);
+                state.children.push(Arc::downgrade(&child.inner));
+                return child;
+            }
+        }
+        child.cancel();
+        child
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancel()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Completes once the token is cancelled
+    pub fn cancelled(&self) -> CancelledFut<'_> {
+        CancelledFut {
+            token: self,
+            waiter: Waiter::one(),
+            pinned: PhantomPinned,
+        }
+    }
+
+    /// Runs `fut` until it completes or the token is cancelled, returns None if it was cancelled
+    pub fn run_until_cancelled<F: Future>(&self, fut: F) -> RunUntilCancelled<'_, F> {
+        RunUntilCancelled {
+            fut,
+            cancelled: self.cancelled(),
+        }
+    }
+
+    /// Cancels the token when the guard goes out of scope
+    pub fn drop_guard(self) -> CancelGuard {
+        CancelGuard { token: Some(self) }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct CancelledFut<'a> {
+    token: &'a CancellationToken,
+    waiter: Waiter,
+    pinned: PhantomPinned,
+}
+
+impl Future for CancelledFut<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.token.inner.state.lock();
+        if self.token.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            let this = unsafe { self.get_unchecked_mut() };
+            state.waiters.add_to_waitlist(
+                unsafe { Pin::new_unchecked(&mut this.waiter) },
+                cx.waker().clone(),
+            );
+            Poll::Pending
+        }
+    }
+}
+
+#[pin_project]
+#[must_use = "Future do nothing unless awaited"]
+pub struct RunUntilCancelled<'a, F> {
+    #[pin]
+    fut: F,
+    #[pin]
+    cancelled: CancelledFut<'a>,
+}
+
+impl<F: Future> Future for RunUntilCancelled<'_, F> {
+    type Output = Option<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+        if this.cancelled.poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        this.fut.poll(cx).map(Some)
+    }
+}
+
+#[must_use = "The token is cancelled when the guard is dropped"]
+pub struct CancelGuard {
+    token: Option<CancellationToken>,
+}
+
+impl CancelGuard {
+    /// Gives back the token without cancelling it
+    pub fn disarm(mut self) -> CancellationToken {
+        self.token.take().unwrap()
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            token.cancel();
+        }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    fn cancel_propagates_to_children() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+        let other = parent.child_token();
+        other.cancel();
+        assert!(!parent.is_cancelled());
+        drop(parent.clone().drop_guard());
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[kernel_test]
+    async fn cancel_wakes_waiters() {
+        let token = CancellationToken::new();
+        let child = token.child_token();
+        let handle = spawn_future(
+            async move {
+                child
+                    .run_until_cancelled(core::future::pending::<()>())
+                    .await
+            },
+            "[cancel]",
+        );
+        token.cancel();
+        assert_eq!(handle.await, Ok(None));
+        assert_eq!(token.run_until_cancelled(async { 1 }).await, None);
+        assert_eq!(
+            CancellationToken::new()
+                .run_until_cancelled(async { 1 })
+                .await,
+            Some(1)
+        );
+    }
+}
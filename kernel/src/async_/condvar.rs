@@ -0,0 +1,153 @@
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use chos_lib::sync::{NoOpLockPolicy, Spinlock};
+
+use super::lock::{AsyncLock, AsyncLockFut, AsyncLockGuard};
+use super::sem::{Waiter, WaiterList};
+
+/// Condition variable for [`AsyncLock`]
+pub struct AsyncCondvar {
+    waiters: Spinlock<WaiterList>,
+}
+
+impl AsyncCondvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Spinlock::new(WaiterList::new()),
+        }
+    }
+
+    /// Releases the lock until the condvar is notified, and takes it back before returning.
+    /// The condition must be checked again, another task might have changed it in the meantime.
+    pub fn wait<'a, T: ?Sized>(
+        &'a self,
+        guard: AsyncLockGuard<'a, NoOpLockPolicy, T>,
+    ) -> AsyncCondvarWaitFut<'a, T> {
+        AsyncCondvarWaitFut {
+            condvar: self,
+            lock: AsyncLockGuard::lock_of(&guard),
+            guard: Some(guard),
+            waiter: Waiter::one(),
+            relock: None,
+            pinned: PhantomPinned,
+        }
+    }
+
+    /// Waits until `cond` returns false
+    pub async fn wait_while<'a, T: ?Sized>(
+        &'a self,
+        mut guard: AsyncLockGuard<'a, NoOpLockPolicy, T>,
+        mut cond: impl FnMut(&mut T) -> bool,
+    ) -> AsyncLockGuard<'a, NoOpLockPolicy, T> {
+        while cond(&mut guard) {
+            guard = self.wait(guard).await;
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.lock_noirq().wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.lock_noirq().wake_all();
+    }
+}
+
+impl Default for AsyncCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct AsyncCondvarWaitFut<'a, T: ?Sized> {
+    condvar: &'a AsyncCondvar,
+    lock: &'a AsyncLock<T>,
+    // Released on the first poll, once we are in the waiters
+    guard: Option<AsyncLockGuard<'a, NoOpLockPolicy, T>>,
+    waiter: Waiter,
+    // Set once notified
+    relock: Option<AsyncLockFut<'a, T>>,
+    pinned: PhantomPinned,
+}
+
+impl<'a, T: ?Sized> Future for AsyncCondvarWaitFut<'a, T> {
+    type Output = AsyncLockGuard<'a, NoOpLockPolicy, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.relock.is_none() {
+            let mut waiters = this.condvar.waiters.lock();
+            let waiter = unsafe { Pin::new_unchecked(&mut this.waiter) };
+            if let Some(guard) = this.guard.take() {
+                // In the waiters before unlocking, a notify can't be missed
+                waiters.add_to_waitlist(waiter, cx.waker().clone());
+                drop(waiters);
+                drop(guard);
+                return Poll::Pending;
+            }
+            if waiter.is_linked() {
+                waiters.add_to_waitlist(waiter, cx.waker().clone());
+                return Poll::Pending;
+            }
+            drop(waiters);
+            this.relock = Some(this.lock.lock());
+        }
+        unsafe { Pin::new_unchecked(this.relock.as_mut().unwrap()) }.poll(cx)
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncCondvarWaitFut<'_, T> {
+    fn drop(&mut self) {
+        if self.guard.is_some() || self.relock.is_some() {
+            return;
+        }
+        let mut waiters = self.condvar.waiters.lock();
+        // Notified but cancelled, give the notification to someone else
+        if !waiters.remove(unsafe { Pin::new_unchecked(&mut self.waiter) }) {
+            waiters.wake_one();
+        }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::async_::join_all;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn condvar_wakes_waiters() {
+        let state = Arc::new((AsyncLock::new(0usize), AsyncCondvar::new()));
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                spawn_future(
+                    async move {
+                        let (lock, condvar) = &*state;
+                        let mut count = condvar.wait_while(lock.lock().await, |v| *v == 0).await;
+                        *count += 1;
+                    },
+                    "[condvar]",
+                )
+            })
+            .collect();
+        {
+            let (lock, condvar) = &*state;
+            *lock.lock().await = 1;
+            condvar.notify_all();
+        }
+        for res in join_all(waiters).await {
+            assert_eq!(res, Ok(()));
+        }
+        assert_eq!(*state.0.lock().await, 5);
+    }
+}
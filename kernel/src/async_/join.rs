@@ -0,0 +1,176 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem::replace;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+#[doc(hidden)]
+pub use futures::future::poll_fn;
+
+/// Future that keeps its output once it completes, until it is taken
+pub enum MaybeDone<F: Future> {
+    Future(F),
+    Done(F::Output),
+    Gone,
+}
+
+impl<F: Future> MaybeDone<F> {
+    pub fn new(fut: F) -> Self {
+        Self::Future(fut)
+    }
+
+    /// Returns true once the future has completed
+    pub fn poll_done(self: Pin<&mut Self>, cx: &mut Context) -> bool {
+        let this = unsafe { self.get_unchecked_mut() };
+        match this {
+            Self::Future(fut) => match unsafe { Pin::new_unchecked(fut) }.poll(cx) {
+                Poll::Ready(res) => {
+                    *this = Self::Done(res);
+                    true
+                }
+                Poll::Pending => false,
+            },
+            Self::Done(_) => true,
+            Self::Gone => panic!("MaybeDone polled after its output was taken"),
+        }
+    }
+
+    pub fn take_output(self: Pin<&mut Self>) -> Option<F::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        match this {
+            Self::Done(_) => match replace(this, Self::Gone) {
+                Self::Done(res) => Some(res),
+                _ => unreachable!(),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Polls all the futures concurrently in the current task, and returns a tuple of their outputs
+/// once they have all completed. Must be used in an async context.
+pub macro join {
+    (@ {
+        // One `_` per future
+        ( $($count:tt)* )
+        // Each future with one `_` per future before it, to find it in the tuple
+        $( ( $($skip:tt)* ) $fut:expr, )*
+    }) => {{
+        let mut futures = ( $( $crate::async_::join::MaybeDone::new($fut), )* );
+        // Shadowed so that the futures can not be moved anymore
+        let futures = &mut futures;
+        $crate::async_::join::poll_fn(move |cx| {
+            let mut done = true;
+            $(
+                let ( $($skip,)* fut, .. ) = &mut *futures;
+                done &= unsafe { core::pin::Pin::new_unchecked(fut) }.poll_done(cx);
+            )*
+            if !done {
+                return core::task::Poll::Pending;
+            }
+            core::task::Poll::Ready(($({
+                let ( $($skip,)* fut, .. ) = &mut *futures;
+                unsafe { core::pin::Pin::new_unchecked(fut) }
+                    .take_output()
+                    .expect("All the futures are done")
+            },)*))
+        })
+        .await
+    }},
+    (@ { ( $($s:tt)* ) $($t:tt)* } $fut:expr, $($rest:tt)*) => {
+        $crate::async_::join::join!(@ { ($($s)* _) $($t)* ($($s)*) $fut, } $($rest)*)
+    },
+    ($($fut:expr),+ $(,)?) => {
+        $crate::async_::join::join!(@ { () } $($fut,)+)
+    },
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct JoinAll<F: Future> {
+    futures: Pin<Box<[MaybeDone<F>]>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let futures = unsafe { self.futures.as_mut().get_unchecked_mut() };
+        let mut done = true;
+        for fut in futures.iter_mut() {
+            done &= unsafe { Pin::new_unchecked(fut) }.poll_done(cx);
+        }
+        if !done {
+            return Poll::Pending;
+        }
+        Poll::Ready(
+            futures
+                .iter_mut()
+                .map(|fut| {
+                    unsafe { Pin::new_unchecked(fut) }
+                        .take_output()
+                        .expect("All the futures are done")
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Like [`join!`], for any number of futures of the same type. The outputs are in the same order
+/// as the futures.
+pub fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> JoinAll<F> {
+    JoinAll {
+        futures: Box::into_pin(futures.into_iter().map(MaybeDone::new).collect()),
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+    use crate::timer::{delay, Instant};
+
+    #[kernel_test]
+    async fn join_polls_concurrently() {
+        let start = Instant::now();
+        let (a, b, c) = join!(
+            async {
+                delay(Duration::from_millis(20)).await;
+                1
+            },
+            async {
+                delay(Duration::from_millis(20)).await;
+                "b"
+            },
+            async { 3 },
+        );
+        assert_eq!((a, b, c), (1, "b", 3));
+        assert!(Instant::now() < start + Duration::from_millis(40));
+        assert_eq!(join!(async { 1 }), (1,));
+    }
+
+    #[kernel_test]
+    async fn join_all_keeps_order() {
+        let handles = (0..8u64).map(|i| {
+            spawn_future(
+                async move {
+                    delay(Duration::from_millis(5 * (8 - i))).await;
+                    i
+                },
+                "[join-all]",
+            )
+        });
+        let res: Vec<_> = join_all(handles)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(res, (0..8).collect::<Vec<_>>());
+        assert!(join_all(Vec::<core::future::Ready<()>>::new())
+            .await
+            .is_empty());
+    }
+}
@@ -95,7 +95,12 @@ impl<T: ?Sized, P: LockPolicy> Drop for AsyncLockGuard<'_, P, T> {
     }
 }
 
-impl<T: ?Sized, P: LockPolicy> AsyncLockGuard<'_, P, T> {
+impl<'lock, T: ?Sized, P: LockPolicy> AsyncLockGuard<'lock, P, T> {
+    // Not a method, it would hide the methods of T
+    pub(super) fn lock_of(guard: &Self) -> &'lock AsyncLock<T> {
+        guard.lock
+    }
+
     pub fn as_ref(&self) -> &T {
         unsafe { &*self.lock.value.get() }
     }
@@ -1,7 +1,21 @@
+pub mod barrier;
+pub mod broadcast;
+pub mod cancel;
+pub mod condvar;
+pub mod join;
 pub mod lock;
 pub mod mpsc;
 pub mod oneshot;
+pub mod rwlock;
+pub mod select;
 pub mod sem;
+pub mod watch;
 
+pub use barrier::AsyncBarrier;
+pub use cancel::CancellationToken;
+pub use condvar::AsyncCondvar;
+pub use join::{join, join_all};
 pub use lock::AsyncLock;
+pub use rwlock::AsyncRwLock;
+pub use select::select;
 pub use sem::AsyncSem;
@@ -1,15 +1,19 @@
 use alloc::sync::Arc;
 use core::fmt::Debug;
-use core::marker::PhantomData;
+use core::future::Future;
+use core::marker::{PhantomData, PhantomPinned};
 use core::pin::Pin;
 use core::task::{Context, Poll, Waker};
 
 use chos_lib::init::Init;
-use chos_lib::queue::Queue;
+use chos_lib::queue::{HeapRingQueue, Queue, RingQueueFullError};
 use chos_lib::sync::Spinlock;
+use futures::future::poll_fn;
 use futures::Stream;
 use pin_project::pin_project;
 
+use super::sem::{Waiter, WaiterList};
+
 struct Channel<T, Q: Queue<T>> {
     queue: Q,
     waker: Option<Waker>,
@@ -103,3 +107,248 @@ where
 {
     channel_with(Q::new())
 }
+
+/// The receiver was dropped, the value is given back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+struct BoundedChannel<T> {
+    queue: HeapRingQueue<T>,
+    senders: usize,
+    receiver_closed: bool,
+    receiver_waker: Option<Waker>,
+    // Senders waiting for room in the queue
+    send_waiters: WaiterList,
+}
+type BoundedChannelPtr<T> = Arc<Spinlock<BoundedChannel<T>>>;
+
+impl<T> BoundedChannel<T> {
+    fn try_send(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        if self.receiver_closed {
+            return Err(TrySendError::Closed(value));
+        }
+        self.queue
+            .try_enqueue(value)
+            .map_err(|RingQueueFullError(value)| TrySendError::Full(value))?;
+        if let Some(waker) = self.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// Sending half of a bounded channel, [`BoundedSender::send`] waits until there is room in the
+/// queue
+pub struct BoundedSender<T> {
+    channel: BoundedChannelPtr<T>,
+}
+
+impl<T> BoundedSender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.channel.lock_noirq().try_send(value)
+    }
+
+    pub fn send(&self, value: T) -> BoundedSendFut<'_, T> {
+        BoundedSendFut {
+            sender: self,
+            value: Some(value),
+            waiter: Waiter::one(),
+            queued: false,
+            pinned: PhantomPinned,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.channel.lock().receiver_closed
+    }
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        self.channel.lock().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut channel = self.channel.lock();
+        channel.senders -= 1;
+        if channel.senders == 0 {
+            if let Some(waker) = channel.receiver_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct BoundedSendFut<'a, T> {
+    sender: &'a BoundedSender<T>,
+    value: Option<T>,
+    waiter: Waiter,
+    queued: bool,
+    pinned: PhantomPinned,
+}
+
+impl<T> Future for BoundedSendFut<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut channel = this.sender.channel.lock();
+        let value = this.value.take().expect("Future polled after completion");
+        let res = match channel.try_send(value) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(value)) => Err(SendError(value)),
+            Err(TrySendError::Full(value)) => {
+                this.value = Some(value);
+                this.queued = true;
+                channel.send_waiters.add_to_waitlist(
+                    unsafe { Pin::new_unchecked(&mut this.waiter) },
+                    cx.waker().clone(),
+                );
+                return Poll::Pending;
+            }
+        };
+        if this.queued {
+            this.queued = false;
+            channel
+                .send_waiters
+                .remove(unsafe { Pin::new_unchecked(&mut this.waiter) });
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<T> Drop for BoundedSendFut<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            let mut channel = self.sender.channel.lock();
+            // We were woken for a free slot, give it to another sender
+            if !channel
+                .send_waiters
+                .remove(unsafe { Pin::new_unchecked(&mut self.waiter) })
+            {
+                channel.send_waiters.wake_one();
+            }
+        }
+    }
+}
+
+/// Receiving half of a bounded channel
+pub struct BoundedReceiver<T> {
+    channel: BoundedChannelPtr<T>,
+}
+
+impl<T> BoundedReceiver<T> {
+    /// Returns None once all the senders are dropped and the queue is empty
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let mut channel = self.channel.lock();
+        let value = channel.queue.dequeue()?;
+        channel.send_waiters.wake_one();
+        Some(value)
+    }
+
+    /// Senders fail from now on, the values already in the queue can still be received
+    pub fn close(&mut self) {
+        let mut channel = self.channel.lock();
+        channel.receiver_closed = true;
+        channel.send_waiters.wake_all();
+    }
+}
+
+impl<T> Stream for BoundedReceiver<T> {
+    type Item = T;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut channel = self.channel.lock();
+        if let Some(value) = channel.queue.dequeue() {
+            channel.send_waiters.wake_one();
+            Poll::Ready(Some(value))
+        } else if channel.senders == 0 {
+            Poll::Ready(None)
+        } else {
+            channel.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Channel that holds at most `capacity` values
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    assert!(capacity > 0, "Bounded channel needs a capacity");
+    let channel = Arc::new(Spinlock::new(BoundedChannel {
+        queue: HeapRingQueue::new(capacity),
+        senders: 1,
+        receiver_closed: false,
+        receiver_waker: None,
+        send_waiters: WaiterList::new(),
+    }));
+    (
+        BoundedSender {
+            channel: channel.clone(),
+        },
+        BoundedReceiver { channel },
+    )
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    async fn bounded_backpressure() {
+        let (tx, mut rx) = bounded(2);
+        assert_eq!(tx.try_send(0), Ok(()));
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+        let producer = spawn_future(
+            async move {
+                for i in 2..8 {
+                    tx.send(i).await.unwrap();
+                }
+            },
+            "[bounded-producer]",
+        );
+        delay(Duration::from_millis(5)).await;
+        let mut received = Vec::new();
+        while let Some(v) = rx.recv().await {
+            received.push(v);
+        }
+        assert_eq!(received, (0..8).collect::<Vec<_>>());
+        assert_eq!(producer.await, Ok(()));
+    }
+
+    #[kernel_test]
+    async fn bounded_receiver_dropped() {
+        let (tx, rx) = bounded(1);
+        tx.send(0).await.unwrap();
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(1).await, Err(SendError(1)));
+    }
+}
@@ -61,7 +61,7 @@ impl<T> Sender<T> {
                 self.send(f.await);
             },
             name,
-        )
+        );
     }
 }
 
@@ -86,6 +86,7 @@ impl<T> Future for Receiver<T> {
         let mut data = self.channel.lock();
         match replace(&mut data.state, ChannelState::Dropped) {
             ChannelState::Pending => {
+                data.state = ChannelState::Pending;
                 data.waker = Some(cx.waker().clone());
                 Poll::Pending
             }
@@ -118,3 +119,25 @@ pub macro call_with_sender {
         recv
     }},
 }
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    async fn send_after_pending_poll() {
+        let (sender, recv) = channel();
+        spawn_future(
+            async move {
+                delay(Duration::from_millis(10)).await;
+                sender.send(42);
+            },
+            "[test:oneshot-sender]",
+        );
+        assert_eq!(recv.await, 42);
+    }
+}
@@ -0,0 +1,304 @@
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use chos_lib::sync::Spinlock;
+
+use super::sem::{Waiter, WaiterList};
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    // Writers that are waiting, new readers wait behind them
+    writers_waiting: usize,
+    read_waiters: WaiterList,
+    write_waiters: WaiterList,
+}
+
+impl RwLockState {
+    fn can_read(&self) -> bool {
+        !self.writer && self.writers_waiting == 0
+    }
+
+    fn can_write(&self) -> bool {
+        !self.writer && self.readers == 0
+    }
+
+    fn wake(&mut self) {
+        if self.writers_waiting != 0 {
+            if self.can_write() {
+                self.write_waiters.wake_one();
+            }
+        } else if !self.writer {
+            self.read_waiters.wake_all();
+        }
+    }
+}
+
+/// Reader-writer lock for async code. Writers have priority, once a writer waits no new reader
+/// can take the lock.
+pub struct AsyncRwLock<T: ?Sized> {
+    state: Spinlock<RwLockState>,
+    value: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for AsyncRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for AsyncRwLock<T> {}
+
+impl<T> AsyncRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Spinlock::new(RwLockState {
+                readers: 0,
+                writer: false,
+                writers_waiting: 0,
+                read_waiters: WaiterList::new(),
+                write_waiters: WaiterList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncRwLock<T> {
+    pub fn as_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn read(&self) -> AsyncReadFut<'_, T> {
+        AsyncReadFut {
+            lock: self,
+            waiter: Waiter::one(),
+            queued: false,
+            pinned: PhantomPinned,
+        }
+    }
+
+    pub fn write(&self) -> AsyncWriteFut<'_, T> {
+        AsyncWriteFut {
+            lock: self,
+            waiter: Waiter::one(),
+            queued: false,
+            pinned: PhantomPinned,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<AsyncReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        state.can_read().then(|| {
+            state.readers += 1;
+            AsyncReadGuard { lock: self }
+        })
+    }
+
+    pub fn try_write(&self) -> Option<AsyncWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        state.can_write().then(|| {
+            state.writer = true;
+            AsyncWriteGuard { lock: self }
+        })
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct AsyncReadFut<'lock, T: ?Sized> {
+    lock: &'lock AsyncRwLock<T>,
+    waiter: Waiter,
+    queued: bool,
+    pinned: PhantomPinned,
+}
+
+impl<'lock, T: ?Sized> Future for AsyncReadFut<'lock, T> {
+    type Output = AsyncReadGuard<'lock, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut state = this.lock.state.lock();
+        if state.can_read() {
+            state.readers += 1;
+            if this.queued {
+                this.queued = false;
+                state
+                    .read_waiters
+                    .remove(unsafe { Pin::new_unchecked(&mut this.waiter) });
+            }
+            Poll::Ready(AsyncReadGuard { lock: this.lock })
+        } else {
+            this.queued = true;
+            state.read_waiters.add_to_waitlist(
+                unsafe { Pin::new_unchecked(&mut this.waiter) },
+                cx.waker().clone(),
+            );
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncReadFut<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            let mut state = self.lock.state.lock();
+            let waiter = unsafe { Pin::new_unchecked(&mut self.waiter) };
+            // We might have been woken instead of someone else
+            if !state.read_waiters.remove(waiter) {
+                state.wake();
+            }
+        }
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct AsyncWriteFut<'lock, T: ?Sized> {
+    lock: &'lock AsyncRwLock<T>,
+    waiter: Waiter,
+    // Counted in writers_waiting
+    queued: bool,
+    pinned: PhantomPinned,
+}
+
+impl<'lock, T: ?Sized> Future for AsyncWriteFut<'lock, T> {
+    type Output = AsyncWriteGuard<'lock, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut state = this.lock.state.lock();
+        if state.can_write() {
+            state.writer = true;
+            if this.queued {
+                this.queued = false;
+                state.writers_waiting -= 1;
+                state
+                    .write_waiters
+                    .remove(unsafe { Pin::new_unchecked(&mut this.waiter) });
+            }
+            Poll::Ready(AsyncWriteGuard { lock: this.lock })
+        } else {
+            if !this.queued {
+                this.queued = true;
+                state.writers_waiting += 1;
+            }
+            state.write_waiters.add_to_waitlist(
+                unsafe { Pin::new_unchecked(&mut this.waiter) },
+                cx.waker().clone(),
+            );
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncWriteFut<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            let mut state = self.lock.state.lock();
+            state.writers_waiting -= 1;
+            state
+                .write_waiters
+                .remove(unsafe { Pin::new_unchecked(&mut self.waiter) });
+            // Either we were woken instead of another writer, or the readers can go
+            state.wake();
+        }
+    }
+}
+
+pub struct AsyncReadGuard<'lock, T: ?Sized> {
+    lock: &'lock AsyncRwLock<T>,
+}
+
+impl<T: ?Sized> Drop for AsyncReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.wake();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for AsyncReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+pub struct AsyncWriteGuard<'lock, T: ?Sized> {
+    lock: &'lock AsyncRwLock<T>,
+}
+
+impl<T: ?Sized> Drop for AsyncWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.writer = false;
+        state.wake();
+    }
+}
+
+impl<T: ?Sized> Deref for AsyncWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AsyncWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::sync::Arc;
+    use core::time::Duration;
+
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    async fn readers_share_the_lock() {
+        let lock = AsyncRwLock::new(1);
+        let r1 = lock.read().await;
+        let r2 = lock.read().await;
+        assert_eq!(*r1 + *r2, 2);
+        assert!(lock.try_write().is_none());
+        drop((r1, r2));
+        *lock.write().await += 1;
+        assert_eq!(*lock.try_read().unwrap(), 2);
+    }
+
+    #[kernel_test]
+    async fn writer_preference() {
+        let lock = Arc::new(AsyncRwLock::new(0));
+        let reader = lock.read().await;
+        let writer = spawn_future(
+            {
+                let lock = lock.clone();
+                async move { *lock.write().await = 1 }
+            },
+            "[rwlock-writer]",
+        );
+        while lock.state.lock().writers_waiting == 0 {
+            delay(Duration::from_millis(1)).await;
+        }
+        // The waiting writer goes first
+        assert!(lock.try_read().is_none());
+        let late_reader = spawn_future(
+            {
+                let lock = lock.clone();
+                async move { *lock.read().await }
+            },
+            "[rwlock-reader]",
+        );
+        drop(reader);
+        assert_eq!(writer.await, Ok(()));
+        assert_eq!(late_reader.await, Ok(1));
+    }
+}
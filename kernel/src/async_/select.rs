@@ -0,0 +1,189 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[doc(hidden)]
+pub use super::join::poll_fn;
+
+/// Output of a [`select!`], `This` for the branch that completed or `Next` for a later one
+#[doc(hidden)]
+pub enum Branch<T, N> {
+    This(T),
+    Next(N),
+}
+
+static NEXT_START: AtomicUsize = AtomicUsize::new(0);
+
+#[doc(hidden)]
+pub fn start_branch(count: usize) -> usize {
+    NEXT_START.fetch_add(1, Ordering::Relaxed) % count
+}
+
+#[doc(hidden)]
+pub macro count {
+    () => { 0 },
+    (_ $($rest:tt)*) => { 1 + $crate::async_::select::count!($($rest)*) },
+}
+
+#[doc(hidden)]
+pub macro branch_ty {
+    () => { core::convert::Infallible },
+    (_ $($rest:tt)*) => {
+        $crate::async_::select::Branch<_, $crate::async_::select::branch_ty!($($rest)*)>
+    },
+}
+
+#[doc(hidden)]
+pub macro branch_wrap {
+    (() $value:expr) => { $crate::async_::select::Branch::This($value) },
+    ((_ $($rest:tt)*) $value:expr) => {
+        $crate::async_::select::Branch::Next(
+            $crate::async_::select::branch_wrap!(($($rest)*) $value)
+        )
+    },
+}
+
+#[doc(hidden)]
+pub macro branch_pat {
+    (() $pat:pat) => { $crate::async_::select::Branch::This($pat) },
+    ((_ $($rest:tt)*) $pat:pat) => {
+        $crate::async_::select::Branch::Next($crate::async_::select::branch_pat!(($($rest)*) $pat))
+    },
+}
+
+/// Waits on several futures at once, and runs the branch of the first one to complete.
+///
+/// ```ignore
+/// select! {
+///     Some(msg) = rx.recv() => handle(msg),
+///     () = delay(Duration::from_secs(1)) => return Err(Elapsed),
+/// }
+/// ```
+///
+/// A branch whose output doesn't match its pattern is disabled and the others are still polled,
+/// it panics if all the branches get disabled. The other futures are dropped once a branch runs.
+/// The branches are polled in a different order every time so that none of them starves the others,
+/// `biased;` before the first branch polls them in the order they are written.
+pub macro select {
+    (@ biased($biased:literal) {
+        // One `_` per branch
+        ( $($count:tt)* )
+        // Each branch with one `_` per branch before it, to find it in the tuple
+        $( ( $($skip:tt)* ) $pat:pat = $fut:expr => $body:expr, )*
+    }) => {{
+        const COUNT: usize = $crate::async_::select::count!($($count)*);
+        let mut futures = ( $( Some($fut), )* );
+        // Shadowed so that the futures can not be moved anymore
+        let futures = &mut futures;
+        // The patterns are only checked in the poll, their bindings are used in the match
+        #[allow(unused_variables)]
+        let output: $crate::async_::select::branch_ty!($($count)*) =
+            $crate::async_::select::poll_fn(|cx| {
+                let start = match $biased {
+                    true => 0,
+                    false => $crate::async_::select::start_branch(COUNT),
+                };
+                let mut pending = false;
+                for i in 0..COUNT {
+                    let branch = (start + i) % COUNT;
+                    $(
+                        if branch == $crate::async_::select::count!($($skip)*) {
+                            let ( $($skip,)* fut, .. ) = &mut *futures;
+                            if let Some(inner) = fut.as_mut() {
+                                let inner = unsafe { core::pin::Pin::new_unchecked(inner) };
+                                match core::future::Future::poll(inner, cx) {
+                                    core::task::Poll::Ready(output) => {
+                                        *fut = None;
+                                        if matches!(&output, $pat) {
+                                            return core::task::Poll::Ready(
+                                                $crate::async_::select::branch_wrap!(
+                                                    ($($skip)*) output
+                                                ),
+                                            );
+                                        }
+                                    }
+                                    core::task::Poll::Pending => pending = true,
+                                }
+                            }
+                        }
+                    )*
+                }
+                if !pending {
+                    panic!("All the branches of select! are disabled");
+                }
+                core::task::Poll::Pending
+            })
+            .await;
+        #[allow(unreachable_patterns)]
+        match output {
+            $( $crate::async_::select::branch_pat!(($($skip)*) $pat) => $body, )*
+            _ => unreachable!("The output matched its pattern"),
+        }
+    }},
+    (@ biased($biased:literal) { ( $($s:tt)* ) $($t:tt)* } $pat:pat = $fut:expr => $body:expr, $($rest:tt)*) => {
+        $crate::async_::select::select!(
+            @ biased($biased) { ($($s)* _) $($t)* ($($s)*) $pat = $fut => $body, } $($rest)*
+        )
+    },
+    (biased; $($pat:pat = $fut:expr => $body:expr),+ $(,)?) => {
+        $crate::async_::select::select!(@ biased(true) { () } $($pat = $fut => $body,)+)
+    },
+    ($($pat:pat = $fut:expr => $body:expr),+ $(,)?) => {
+        $crate::async_::select::select!(@ biased(false) { () } $($pat = $fut => $body,)+)
+    },
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::vec::Vec;
+    use core::future::{pending, ready};
+    use core::time::Duration;
+
+    use super::*;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    async fn select_first_ready() {
+        let res = select! {
+            () = delay(Duration::from_secs(10)) => 0,
+            v = async {
+                delay(Duration::from_millis(5)).await;
+                2
+            } => v,
+            () = pending::<()>() => 1,
+        };
+        assert_eq!(res, 2);
+    }
+
+    #[kernel_test]
+    async fn select_disables_unmatched() {
+        let res = select! {
+            biased;
+            Some(v) = ready(None::<u32>) => v,
+            Ok(v) = async {
+                delay(Duration::from_millis(5)).await;
+                Ok::<_, ()>(3)
+            } => v,
+        };
+        assert_eq!(res, 3);
+    }
+
+    #[kernel_test]
+    async fn select_biased_and_fair() {
+        for _ in 0..8 {
+            let res = select! {
+                biased;
+                v = ready(0) => v,
+                v = ready(1) => v,
+            };
+            assert_eq!(res, 0);
+        }
+        let mut seen = Vec::new();
+        for _ in 0..8 {
+            seen.push(select! {
+                v = ready(0) => v,
+                v = ready(1) => v,
+            });
+        }
+        assert!(seen.contains(&0) && seen.contains(&1));
+    }
+}
@@ -29,6 +29,17 @@ impl WaiterList {
             .push_back(unsafe { UnsafeMut::from_raw(waiter) });
     }
 
+    /// Returns false if the waiter was not in the list, e.g. because it was already woken
+    pub fn remove(&mut self, waiter: Pin<&mut Waiter>) -> bool {
+        let waiter = unsafe { waiter.get_unchecked_mut() };
+        if !waiter.link.is_linked() {
+            return false;
+        }
+        unsafe { self.waiters.cursor_mut_from_ptr(waiter).remove() };
+        waiter.list = None;
+        true
+    }
+
     pub fn wake_one(&mut self) {
         self.wake_count(1)
     }
@@ -86,6 +97,11 @@ impl Waiter {
     pub const fn one() -> Self {
         Self::new(1)
     }
+
+    /// Must be called with the list locked
+    pub fn is_linked(&self) -> bool {
+        self.link.is_linked()
+    }
 }
 
 impl Drop for Waiter {
@@ -0,0 +1,222 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use chos_lib::sync::{NoSchedLockPolicy, Spinlock, SpinlockGuard};
+
+use super::sem::{Waiter, WaiterList};
+
+/// The sender was dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Shared<T> {
+    value: T,
+    // Incremented on every send
+    version: u64,
+    closed: bool,
+    waiters: WaiterList,
+}
+type SharedPtr<T> = Arc<Spinlock<Shared<T>>>;
+
+/// Reference to the current value, the channel is locked until it is dropped
+pub struct Ref<'a, T> {
+    guard: SpinlockGuard<'a, NoSchedLockPolicy, Shared<T>>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard.value
+    }
+}
+
+/// Sending half of a watch channel, the receivers only see the latest value
+pub struct Sender<T> {
+    shared: SharedPtr<T>,
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, value: T) {
+        self.send_modify(|v| *v = value)
+    }
+
+    pub fn send_modify(&self, f: impl FnOnce(&mut T)) {
+        let mut shared = self.shared.lock_noirq();
+        f(&mut shared.value);
+        shared.version += 1;
+        shared.waiters.wake_all();
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            guard: self.shared.lock(),
+        }
+    }
+
+    /// New receiver, the current value is already seen
+    pub fn subscribe(&self) -> Receiver<T> {
+        let version = self.shared.lock().version;
+        Receiver {
+            shared: self.shared.clone(),
+            version,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.closed = true;
+        shared.waiters.wake_all();
+    }
+}
+
+pub struct Receiver<T> {
+    shared: SharedPtr<T>,
+    // Last version seen
+    version: u64,
+}
+
+impl<T> Receiver<T> {
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            guard: self.shared.lock(),
+        }
+    }
+
+    /// Like [`Receiver::borrow`], and marks the value as seen
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        let guard = self.shared.lock();
+        self.version = guard.version;
+        Ref { guard }
+    }
+
+    pub fn has_changed(&self) -> Result<bool, RecvError> {
+        let shared = self.shared.lock();
+        if shared.version != self.version {
+            Ok(true)
+        } else if shared.closed {
+            Err(RecvError)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Waits until a value that was not seen yet is sent, and marks it as seen
+    pub fn changed(&mut self) -> ChangedFut<'_, T> {
+        ChangedFut {
+            receiver: self,
+            waiter: Waiter::one(),
+            queued: false,
+            pinned: PhantomPinned,
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            version: self.version,
+        }
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct ChangedFut<'a, T> {
+    receiver: &'a mut Receiver<T>,
+    waiter: Waiter,
+    queued: bool,
+    pinned: PhantomPinned,
+}
+
+impl<T> Future for ChangedFut<'_, T> {
+    type Output = Result<(), RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let shared = this.receiver.shared.clone();
+        let mut shared = shared.lock();
+        let res = if shared.version != this.receiver.version {
+            this.receiver.version = shared.version;
+            Ok(())
+        } else if shared.closed {
+            Err(RecvError)
+        } else {
+            this.queued = true;
+            shared.waiters.add_to_waitlist(
+                unsafe { Pin::new_unchecked(&mut this.waiter) },
+                cx.waker().clone(),
+            );
+            return Poll::Pending;
+        };
+        if this.queued {
+            this.queued = false;
+            shared
+                .waiters
+                .remove(unsafe { Pin::new_unchecked(&mut this.waiter) });
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<T> Drop for ChangedFut<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            self.receiver
+                .shared
+                .lock()
+                .waiters
+                .remove(unsafe { Pin::new_unchecked(&mut self.waiter) });
+        }
+    }
+}
+
+/// Watch channel that starts with `value`
+pub fn channel<T>(value: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Spinlock::new(Shared {
+        value,
+        version: 0,
+        closed: false,
+        waiters: WaiterList::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, version: 0 },
+    )
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn watch_latest_value() {
+        let (tx, mut rx) = channel(0);
+        assert_eq!(rx.has_changed(), Ok(false));
+        tx.send(1);
+        tx.send(2);
+        assert_eq!(rx.has_changed(), Ok(true));
+        assert_eq!(rx.changed().await, Ok(()));
+        assert_eq!(*rx.borrow(), 2);
+        let waiter = spawn_future(
+            async move {
+                rx.changed().await.unwrap();
+                let value = *rx.borrow_and_update();
+                (value, rx.changed().await)
+            },
+            "[watch]",
+        );
+        tx.send_modify(|v| *v += 1);
+        drop(tx);
+        assert_eq!(waiter.await, Ok((3, Err(RecvError))));
+    }
+}
@@ -1,7 +1,9 @@
 pub mod domain {
-    use chos_lib::log::domain;
+    use chos_lib::log::{domain, Domain};
     domain! {
         PALLOC = false,
         GLOBAL_ALLOC = false,
     }
+
+    pub static DOMAINS: &[&dyn Domain] = &[&PALLOC, &GLOBAL_ALLOC];
 }
@@ -22,6 +22,11 @@ pub fn all() -> Cpumask {
     *ALL_CPUS.try_get().expect("Cpumask not initialized")
 }
 
+/// Returns None before the CPUs are known
+pub fn try_all() -> Option<Cpumask> {
+    ALL_CPUS.try_get().copied()
+}
+
 pub fn this_cpu() -> Cpumask {
     Cpumask::for_cpu(this_cpu_info().id as u8)
 }
@@ -71,6 +71,13 @@ static mut STACKS_BASE: VFrame<FrameSize4K> = virt::STACK_BASE;
 pub fn entry(info: &KernelBootInfo, id: usize) -> ! {
     static mut EARLY_DATA: MaybeUninit<EarlyData> = MaybeUninit::uninit();
 
+    // Before the first spinlock of this CPU
+    #[cfg(feature = "spin-debug")]
+    unsafe {
+        use chos_lib::arch::apic::initial_apic_id;
+        chos_lib::sync::spin::lock::debug::set_cpu_apic_id(initial_apic_id());
+    }
+
     if id == 0 {
         unsafe { chos_lib::log::set_handler(info.early_log) };
 
@@ -6,15 +6,25 @@ use chos_lib::tar::Tar;
 use crate::async_::oneshot::call_with_sender;
 use crate::fs::path::{Component, Path};
 use crate::fs::{with_filesystem, Filesystem, InodeAttributes, InodeMode};
+use crate::param::kernel_param;
 use crate::resource::DirectoryArc;
 
 const RAMFS_FS_NAME: &'static str = "ramfs";
 
+kernel_param!(
+    "initrd.mount",
+    /// Directory of the ramfs where the initrd is extracted
+    static INITRD_MOUNT: &'static str = "/",
+);
+
 async fn create_file(path: &Path, root: &DirectoryArc, contents: &[u8]) {
     let filename = path.file_name().expect("Should have a file name");
     let dirname = path.parent().unwrap_or(Path::new("."));
     let mut dir = root.clone();
-    for c in dirname.components() {
+    for c in Path::new(INITRD_MOUNT.get())
+        .components()
+        .chain(dirname.components())
+    {
         match c {
             Component::CurDir | Component::RootDir => (),
             Component::ParentDir => panic!("ParentDir not supported"),
@@ -1,4 +1,9 @@
-use crate::arch::intr::{arch_init_interrupts, arch_init_interrupts_cpu};
+use chos_lib::cpumask::Cpumask;
+
+use crate::arch::intr::{
+    arch_init_interrupts, arch_init_interrupts_cpu, arch_send_call_function, arch_send_nmi,
+    arch_send_wakeup,
+};
 use crate::kmain::KernelArgs;
 
 pub unsafe fn init_interrupts(args: &KernelArgs) {
@@ -8,3 +13,18 @@ pub unsafe fn init_interrupts(args: &KernelArgs) {
 pub unsafe fn init_interrupts_cpu(args: &KernelArgs) {
     arch_init_interrupts_cpu(args);
 }
+
+/// Gets `cpu` out of its idle sleep
+pub fn send_wakeup(cpu: usize) {
+    arch_send_wakeup(cpu);
+}
+
+/// Makes the CPUs in `cpus` run their queued calls, see [`crate::smp`]
+pub fn send_call_function(cpus: Cpumask) {
+    arch_send_call_function(cpus);
+}
+
+/// Sends an NMI to the CPUs in `cpus`, they get it even with their interrupts disabled
+pub fn send_nmi(cpus: Cpumask) {
+    arch_send_nmi(cpus);
+}
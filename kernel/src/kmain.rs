@@ -1,30 +1,37 @@
 use alloc::boxed::Box;
 use alloc::format;
 use alloc::string::String;
-use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
 use core::mem::MaybeUninit;
 
 use chos_config::arch::mm::virt;
 use chos_lib::arch::serial::Serial;
 use chos_lib::boot::KernelMemInfo;
 use chos_lib::elf::Elf;
-use chos_lib::log::{debug, LogHandler, TermColorLogHandler};
+use chos_lib::log::{debug, info, Domain, LogHandler, LogLevel, TermColorLogHandler};
 use chos_lib::sync::Spinlock;
 
 use crate::arch::early::{init_non_early_memory, unmap_early_lower_memory};
 use crate::arch::kmain::ArchKernelArgs;
 use crate::arch::mm::virt::init_kernel_virt;
-use crate::async_::AsyncSem;
+use crate::async_::join_all;
 use crate::cpumask::init_cpumask;
 use crate::initrd::load_initrd;
 use crate::intr::{init_interrupts, init_interrupts_cpu};
 use crate::mm::this_cpu_info;
 use crate::mm::virt::stack::Stack;
+use crate::mm::virt::vmalloc::start_vmalloc_purge;
 use crate::module::{get_modules_for_elf, Module};
+use crate::param::{
+    dump_params, get_params_for_elf, kernel_param, kernel_param_decl, parse_command_line,
+    KernelParam, ParamError, ParamValue,
+};
 use crate::sched::enter_schedule;
 use crate::sched::ktask::{init_ktask_stack, spawn, spawn_future};
+use crate::sched::watchdog::start_watchdog;
 use crate::symbols::add_elf_symbols;
-use crate::timer::init_timer;
+use crate::timer::{init_timer, init_timer_cpu, SystemTime};
 use crate::util::{barrier, do_once};
 
 #[derive(Debug)]
@@ -42,17 +49,62 @@ struct Logger {
     serial: TermColorLogHandler<Spinlock<Serial>>,
 }
 
+// Timestamp, once the RTC has been read, and CPU id
+struct LogPrefix;
+
+impl fmt::Display for LogPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(now) = SystemTime::try_now() {
+            write!(f, "[{:.6}]", now)?;
+        }
+        write!(f, "[{}]", this_cpu_info().id)
+    }
+}
+
 impl LogHandler for Logger {
     fn log(&self, args: core::fmt::Arguments<'_>, lvl: chos_lib::log::LogLevel) {
-        self.serial
-            .log(format_args!("[{}] {}", this_cpu_info().id, args), lvl)
+        self.serial.log(format_args!("{} {}", LogPrefix, args), lvl)
     }
     unsafe fn log_unsafe(&self, args: core::fmt::Arguments<'_>, lvl: chos_lib::log::LogLevel) {
         self.serial
-            .log_unsafe(format_args!("[{}] {}", this_cpu_info().id, args), lvl)
+            .log_unsafe(format_args!("{} {}", LogPrefix, args), lvl)
+    }
+}
+
+kernel_param!("log.level", static LOG_LEVEL: LogLevel = LogLevel::Debug);
+
+// Consumed by the bootloader
+kernel_param!("output", static OUTPUT: &'static str = "serial");
+
+struct LogDomains;
+
+impl LogDomains {
+    fn all() -> impl Iterator<Item = &'static dyn Domain> {
+        chos_lib::config::domain::DOMAINS
+            .iter()
+            .chain(crate::config::domain::DOMAINS)
+            .copied()
+    }
+}
+
+impl KernelParam for LogDomains {
+    unsafe fn set(&self, key: Option<&str>, value: Option<&'static str>) -> Result<(), ParamError> {
+        let domain = key
+            .and_then(|key| Self::all().find(|domain| domain.name() == key))
+            .ok_or(ParamError::Unknown)?;
+        domain.set_enabled(bool::parse(value).ok_or(ParamError::InvalidValue)?);
+        Ok(())
+    }
+
+    fn dump(&self, name: &str) {
+        for domain in Self::all() {
+            info!("    {}.{} = {:?}", name, domain.name(), domain.enabled());
+        }
     }
 }
 
+kernel_param_decl!("log.domain", &LogDomains);
+
 fn setup_logger() {
     static mut LOGGER: MaybeUninit<Logger> = MaybeUninit::uninit();
     unsafe {
@@ -90,6 +142,15 @@ pub fn kernel_main(id: usize, args: &KernelArgs) -> ! {
             virt::STATIC_BASE.addr(),
             &Elf::new(&args.kernel_elf).expect("Should be a valid elf"),
         );
+
+        let params = get_params_for_elf(
+            &Elf::new(&args.kernel_elf).unwrap(),
+            virt::STATIC_BASE.addr(),
+        )
+        .expect("Static parameters are invalid");
+        unsafe { parse_command_line(params, args.command_line.as_deref().unwrap_or("")) };
+        chos_lib::log::set_level(LOG_LEVEL.get());
+        dump_params(params);
     }
 
     barrier!(args.core_count);
@@ -107,35 +168,45 @@ pub fn kernel_main(id: usize, args: &KernelArgs) -> ! {
     if id == 0 {
         init_timer(args);
     }
+    barrier!(args.core_count);
+    init_timer_cpu();
 
     init_ktask_stack(args.early_stacks[id]);
 
     do_once!({
+        start_vmalloc_purge();
+        start_watchdog();
         let mods = get_modules_for_elf(
             &Elf::new(&args.kernel_elf).unwrap(),
             virt::STATIC_BASE.addr(),
         )
         .expect("Static modules are invalid");
-        let mods_count = mods.len();
-        let sem = Arc::new(AsyncSem::zero());
-        for m in mods {
-            let module = Module { decl: m };
-            if let Some(init) = m.init() {
-                let sem = sem.clone();
-                spawn(
-                    move || {
-                        init(module);
-                        sem.signal();
-                    },
+        let mod_inits: Vec<_> = mods
+            .iter()
+            .filter_map(|m| {
+                let module = Module { decl: m };
+                let init = m.init()?;
+                Some(spawn(
+                    move || init(module),
                     format!("[mod-init:{}]", m.name()),
-                );
-            }
-        }
+                ))
+            })
+            .collect();
+        #[cfg(feature = "kernel-test")]
+        let tests = crate::test::get_tests_for_elf(
+            &Elf::new(&args.kernel_elf).unwrap(),
+            virt::STATIC_BASE.addr(),
+        )
+        .expect("Static tests are invalid");
         let initrd = args.initrd.clone();
         spawn_future(
             async move {
-                sem.wait_count(mods_count).await;
+                for res in join_all(mod_inits).await {
+                    res.expect("Module init failed");
+                }
                 load_initrd(&initrd).await;
+                #[cfg(feature = "kernel-test")]
+                crate::test::run_kernel_tests(tests).await;
             },
             "[initrd]",
         );
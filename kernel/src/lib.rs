@@ -33,6 +33,7 @@
 #![allow(incomplete_features)]
 
 extern crate alloc;
+extern crate self as chos;
 
 pub mod arch;
 pub mod async_;
@@ -48,9 +49,12 @@ mod kmain;
 pub mod mm;
 pub mod module;
 mod panic;
+pub mod param;
 pub mod resource;
 pub mod sched;
+pub mod smp;
 mod symbols;
+pub mod test;
 pub mod timer;
 pub mod util;
 
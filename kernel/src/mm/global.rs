@@ -1,9 +1,9 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem::align_of;
-use core::ptr::null_mut;
+use core::ptr::{null_mut, NonNull};
 use core::slice;
 
-use chos_lib::arch::mm::PAGE_SHIFT;
+use chos_lib::arch::mm::{PAGE_SHIFT, PAGE_SIZE};
 use chos_lib::int::ceil_log2u64;
 use chos_lib::log::domain_debug;
 use chos_lib::mm::{PFrame, VAddr, VFrame};
@@ -13,6 +13,7 @@ use super::phys::MMSlabAllocator;
 use super::slab::RawObjectAllocator;
 use crate::config::domain;
 use crate::mm::phys::raw_alloc::{self, AllocFlags};
+use crate::mm::virt::vmalloc::{is_vmalloc_addr, vfree, vmalloc_populated};
 use crate::mm::virt::{map_pframe, paddr_of};
 
 macro_rules! kalloc_sizes {
@@ -72,6 +73,10 @@ kalloc_sizes!(
     ]
 );
 
+fn page_order(size: usize) -> u8 {
+    ceil_log2u64(size as u64).saturating_sub(PAGE_SHIFT) as u8
+}
+
 struct KAlloc;
 
 unsafe impl GlobalAlloc for KAlloc {
@@ -79,30 +84,35 @@ unsafe impl GlobalAlloc for KAlloc {
         if layout.size() == 0 {
             return layout.align() as _;
         }
-        assert!(
-            layout.align() <= align_of::<usize>(),
-            "Invalid alignment, use specialized slab allocator"
-        );
-        for &(s, alloc) in &KALLOC_SIZES {
-            if s >= layout.size() {
-                let ptr = alloc.alloc();
-                domain_debug!(
-                    domain::GLOBAL_ALLOC,
-                    "alloc(size={}, align={}) = {:p}",
-                    layout.size(),
-                    layout.align(),
-                    ptr,
-                );
-                return ptr;
+        // Pages are only aligned on PAGE_SIZE
+        if layout.align() > PAGE_SIZE {
+            return null_mut();
+        }
+        if layout.align() <= align_of::<usize>() {
+            for &(s, alloc) in &KALLOC_SIZES {
+                if s >= layout.size() {
+                    let ptr = alloc.alloc();
+                    domain_debug!(
+                        domain::GLOBAL_ALLOC,
+                        "alloc(size={}, align={}) = {:p}",
+                        layout.size(),
+                        layout.align(),
+                        ptr,
+                    );
+                    return ptr;
+                }
             }
         }
-        let order = ceil_log2u64(layout.size() as u64) - PAGE_SHIFT;
-        let ptr = raw_alloc::alloc_pages(order as u8, AllocFlags::empty())
+        // Contiguous pages are in the physical map, they can be touched from anywhere. vmalloc is
+        // only used when the physical memory is too fragmented, and its pages are allocated
+        // right away so that the first access doesn't need to allocate.
+        let ptr = raw_alloc::alloc_pages(page_order(layout.size()), AllocFlags::empty())
             .map(|paddr| {
                 let vaddr = map_pframe(paddr, crate::mm::virt::MemoryRegionType::Normal)
                     .unwrap_or(VFrame::null());
                 vaddr.addr().as_mut_ptr()
             })
+            .or_else(|_| vmalloc_populated(layout.size()).map(NonNull::as_ptr))
             .unwrap_or(null_mut());
         domain_debug!(
             domain::GLOBAL_ALLOC,
@@ -124,22 +134,22 @@ unsafe impl GlobalAlloc for KAlloc {
             layout.size(),
             layout.align()
         );
-        assert!(
-            layout.align() <= align_of::<usize>(),
-            "Invalid alignment, use specialized slab allocator"
-        );
-        for &(s, alloc) in &KALLOC_SIZES {
-            if s >= layout.size() {
-                return alloc.dealloc(ptr);
+        if layout.align() <= align_of::<usize>() {
+            for &(s, alloc) in &KALLOC_SIZES {
+                if s >= layout.size() {
+                    return alloc.dealloc(ptr);
+                }
             }
         }
-        let order = ceil_log2u64(layout.size() as u64) - PAGE_SHIFT;
+        if is_vmalloc_addr(VAddr::new(ptr as u64)) {
+            return vfree(NonNull::new_unchecked(ptr));
+        }
         let paddr = paddr_of(
             VAddr::new(ptr as u64),
             crate::mm::virt::MemoryRegionType::Normal,
         )
         .expect("Should exist");
-        raw_alloc::dealloc_pages(PFrame::new(paddr), order as u8);
+        raw_alloc::dealloc_pages(PFrame::new(paddr), page_order(layout.size()));
     }
 }
 
@@ -154,3 +164,26 @@ fn out_of_memory_handler(layout: Layout) -> ! {
         layout.align()
     );
 }
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::alloc::{alloc, dealloc};
+
+    use super::*;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    fn page_aligned_allocs() {
+        for size in [64, 3 * PAGE_SIZE] {
+            let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+            unsafe {
+                let ptr = alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % PAGE_SIZE, 0);
+                dealloc(ptr, layout);
+            }
+        }
+        let layout = Layout::from_size_align(PAGE_SIZE, 2 * PAGE_SIZE).unwrap();
+        assert!(unsafe { alloc(layout) }.is_null());
+    }
+}
@@ -358,15 +358,16 @@ pub unsafe fn dealloc_pages_unlocked(page: PFrame, order: u8) {
     panic!("Invalid address {:?}", page);
 }
 
+// Page faults can allocate page tables from interrupts, keep them disabled while holding it
 static ALLOC_LOCK: Spinlock<()> = Spinlock::INIT;
 
 pub fn alloc_pages(order: u8, flags: AllocFlags) -> Result<PFrame, AllocError> {
-    let _guard = ALLOC_LOCK.lock();
+    let _guard = ALLOC_LOCK.lock_noirq();
     unsafe { alloc_pages_unlocked(order, flags) }
 }
 
 pub unsafe fn dealloc_pages(pframe: PFrame, order: u8) {
-    let _guard = ALLOC_LOCK.lock();
+    let _guard = ALLOC_LOCK.lock_noirq();
     dealloc_pages_unlocked(pframe, order)
 }
 
@@ -379,7 +380,7 @@ pub struct RegionInfo {
 }
 
 pub fn get_regions_info(mut callback: impl FnMut(RegionInfo)) {
-    let _guard = ALLOC_LOCK.lock();
+    let _guard = ALLOC_LOCK.lock_noirq();
     for region in unsafe { &REGIONS } {
         callback(RegionInfo {
             biggest_order: region.meta.biggest_order,
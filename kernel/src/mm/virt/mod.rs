@@ -1,4 +1,5 @@
 pub mod stack;
+pub mod vmalloc;
 
 use core::mem::MaybeUninit;
 
@@ -6,7 +7,10 @@ use chos_config::arch::mm::{phys, virt};
 use chos_lib::mm::{PAddr, PFrame, VAddr, VFrame, VFrameRange};
 
 use self::stack::StackMemoryRegion;
+use self::vmalloc::VmallocMemoryRegion;
 use super::phys::Page;
+use crate::arch::mm::virt::unmap_frames_on;
+use crate::cpumask;
 use crate::kmain::KernelArgs;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -17,6 +21,7 @@ pub enum MemoryRegionType {
     IoMem,
     Static,
     Stack,
+    Vmalloc,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -138,9 +143,10 @@ static mut STATIC_REGION: StaticMemoryRegion = StaticMemoryRegion {
     vbase: virt::STATIC_BASE,
     size: 0,
 };
-static mut ALL_MEMORY_REGIONS: MaybeUninit<[&'static (dyn MemoryRegion + Sync); 5]> = unsafe {
+static mut ALL_MEMORY_REGIONS: MaybeUninit<[&'static (dyn MemoryRegion + Sync); 6]> = unsafe {
     MaybeUninit::new([
         &ALLOC_REGION,
+        &VmallocMemoryRegion,
         &HEAP_REGION,
         &IOMEM_REGION,
         &STATIC_REGION,
@@ -180,8 +186,10 @@ pub fn paddr_of(vaddr: VAddr, typ: MemoryRegionType) -> Option<PAddr> {
     get_memory_region_by_type(typ).and_then(|r| r.paddr_of(vaddr))
 }
 
-pub unsafe fn unmap_page(_: VAddr) {
-    // Nothing
+/// Unmaps the page on every CPU
+pub unsafe fn unmap_page(vaddr: VAddr) {
+    let (vframe, _) = vaddr.frame_offset();
+    unmap_frames_on(VFrameRange::new(vframe, vframe.add(1)), cpumask::all());
 }
 
 pub fn handle_kernel_page_fault(vaddr: VAddr, reason: PageFaultReason) -> PageFaultResult {
@@ -197,6 +205,7 @@ pub unsafe fn init_kernel_virt(args: &KernelArgs) {
     STATIC_REGION.size = args.mem_info.code.size as u64;
     ALL_MEMORY_REGIONS = MaybeUninit::new([
         &ALLOC_REGION,
+        &VmallocMemoryRegion,
         &HEAP_REGION,
         &IOMEM_REGION,
         &STATIC_REGION,
@@ -0,0 +1,361 @@
+use core::alloc::AllocError;
+use core::cell::Cell;
+use core::ptr::{write_bytes, NonNull};
+use core::slice;
+use core::task::{Poll, Waker};
+
+use chos_config::arch::mm::virt;
+use chos_lib::arch::mm::{FrameSize4K, PAGE_SHIFT, PAGE_SIZE};
+use chos_lib::cpumask::Cpumask;
+use chos_lib::init::ConstInit;
+use chos_lib::int::{ceil_log2u64, CeilDiv};
+use chos_lib::log::warn;
+use chos_lib::mm::{MapFlags, PAddr, PFrame, PFrameRange, VAddr, VFrame, VFrameRange};
+use chos_lib::pool;
+use chos_lib::pool::PoolBox;
+use chos_lib::sync::Spinlock;
+use futures::future::poll_fn;
+use intrusive_collections::{intrusive_adapter, linked_list, rbtree, Bound, KeyAdapter, UnsafeRef};
+
+use super::{
+    map_pframe, MemoryMapError, MemoryRegion, MemoryRegionType, PageFaultReason, PageFaultResult,
+};
+use crate::arch::mm::virt::{map_frames, unmap_frames_on};
+use crate::cpumask;
+use crate::mm::phys::{raw_alloc, AllocFlags, MMPoolObjectAllocator};
+use crate::sched::ktask::spawn_future;
+
+/// Physical page of each virtual page of an area, null until the first access.
+/// It is read by the page fault handler, so it can't be in vmalloc memory itself.
+struct PageList {
+    frame: PFrame,
+    order: u8,
+    len: usize,
+}
+
+impl PageList {
+    fn new(len: usize) -> Result<Self, AllocError> {
+        let size = (len * core::mem::size_of::<Cell<PFrame>>()) as u64;
+        let order = ceil_log2u64(size).saturating_sub(PAGE_SHIFT) as u8;
+        let frame = raw_alloc::alloc_pages(order, AllocFlags::empty())?;
+        let list = Self { frame, order, len };
+        for page in list.pages() {
+            page.set(PFrame::null());
+        }
+        Ok(list)
+    }
+
+    fn pages(&self) -> &[Cell<PFrame>] {
+        let vframe = map_pframe(self.frame, MemoryRegionType::Alloc).expect("Should be mapped");
+        unsafe { slice::from_raw_parts(vframe.addr().as_ptr(), self.len) }
+    }
+}
+
+impl Drop for PageList {
+    fn drop(&mut self) {
+        unsafe { raw_alloc::dealloc_pages(self.frame, self.order) };
+    }
+}
+
+struct VmallocArea {
+    link: rbtree::AtomicLink,
+    purge_link: linked_list::AtomicLink,
+    // Set by vfree, the area keeps its range until it is unmapped everywhere
+    freed: Cell<bool>,
+    // Guard page, the area starts on the next page
+    base: VFrame,
+    pages: PageList,
+    // CPUs that have some of the pages in their page table
+    mapped_on: Cell<Cpumask>,
+}
+static VMALLOC_AREA_ALLOCATOR: MMPoolObjectAllocator<VmallocArea, 0> = ConstInit::INIT;
+pool!(struct VmallocAreaPool: VmallocArea => &VMALLOC_AREA_ALLOCATOR);
+
+impl VmallocArea {
+    fn range(&self) -> VFrameRange {
+        VFrameRange::new(self.base.add(1), self.base.add(self.pages.len as u64 + 1))
+    }
+
+    fn page_for(&self, vaddr: VAddr) -> &Cell<PFrame> {
+        let idx = (vaddr - self.range().start().addr()).as_u64() >> PAGE_SHIFT;
+        &self.pages.pages()[idx as usize]
+    }
+
+    fn populate(&self) -> Result<(), AllocError> {
+        for page in self.pages.pages() {
+            page.set(alloc_zeroed_page()?);
+        }
+        Ok(())
+    }
+
+    // The pages must not be mapped anymore
+    unsafe fn free_pages(&self) {
+        for page in self.pages.pages() {
+            if page.get() != PFrame::null() {
+                raw_alloc::dealloc_pages(page.get(), 0);
+            }
+        }
+    }
+}
+
+type VmallocAreaBox = PoolBox<VmallocArea, VmallocAreaPool>;
+
+chos_lib::intrusive_adapter!(VmallocAreaAdapter = VmallocAreaBox: VmallocArea { link: rbtree::AtomicLink });
+
+impl<'a> KeyAdapter<'a> for VmallocAreaAdapter {
+    type Key = VAddr;
+    fn get_key(&self, value: &'a VmallocArea) -> VAddr {
+        value.base.addr()
+    }
+}
+
+intrusive_adapter!(VmallocPurgeAdapter = UnsafeRef<VmallocArea>: VmallocArea { purge_link: linked_list::AtomicLink });
+
+struct AllAreas {
+    // The free ranges are the gaps between the areas
+    area_tree: rbtree::RBTree<VmallocAreaAdapter>,
+    // Freed areas that still need to be unmapped, they are also in the tree
+    purge_list: linked_list::LinkedList<VmallocPurgeAdapter>,
+    purge_waker: Option<Waker>,
+}
+// The areas are only accessed with the lock held
+unsafe impl Send for AllAreas {}
+
+impl AllAreas {
+    // First gap that fits `count` pages and the guard page
+    fn find_free_base(&self, count: u64) -> Option<VFrame> {
+        let mut base = virt::VMALLOC_BASE;
+        for area in self.area_tree.iter() {
+            if area.base >= base.add(count + 1) {
+                return Some(base);
+            }
+            base = area.range().end();
+        }
+        (base.add(count + 1) <= virt::VMALLOC_BASE.add(virt::VMALLOC_FRAMES)).then(|| base)
+    }
+}
+
+// Also taken by the page fault handler, which can happen in an interrupt
+static ALL_AREAS: Spinlock<AllAreas> = Spinlock::new(AllAreas {
+    area_tree: rbtree::RBTree::new(VmallocAreaAdapter::new()),
+    purge_list: linked_list::LinkedList::new(VmallocPurgeAdapter::NEW),
+    purge_waker: None,
+});
+
+fn alloc_area(size: usize, populate: bool) -> Result<NonNull<u8>, AllocError> {
+    let count = size.ceil_div(PAGE_SIZE).max(1);
+    let pages = PageList::new(count)?;
+    let mut area = PoolBox::try_new(VmallocArea {
+        link: rbtree::AtomicLink::new(),
+        purge_link: linked_list::AtomicLink::new(),
+        freed: Cell::new(false),
+        base: VFrame::null(),
+        pages,
+        mapped_on: Cell::new(Cpumask::empty()),
+    })?;
+    if populate {
+        if let Err(err) = area.populate() {
+            unsafe { area.free_pages() };
+            return Err(err);
+        }
+    }
+    let mut all_areas = ALL_AREAS.lock_noirq();
+    match all_areas.find_free_base(count as u64) {
+        Some(base) => area.base = base,
+        None => {
+            drop(all_areas);
+            unsafe { area.free_pages() };
+            return Err(AllocError);
+        }
+    }
+    let start = area.range().start();
+    all_areas.area_tree.insert(area);
+    Ok(NonNull::new(start.addr().as_mut_ptr()).unwrap())
+}
+
+/// Allocates `size` bytes that are contiguous in virtual memory only. The physical pages are
+/// allocated and mapped on the first access, so this can succeed even when the physical memory
+/// is too fragmented for [`raw_alloc`].
+pub fn vmalloc(size: usize) -> Result<NonNull<u8>, AllocError> {
+    alloc_area(size, false)
+}
+
+/// Like [`vmalloc`], but the physical pages are allocated right away. The first access on a CPU
+/// still faults to map them, but the fault never allocates.
+pub fn vmalloc_populated(size: usize) -> Result<NonNull<u8>, AllocError> {
+    alloc_area(size, true)
+}
+
+pub fn is_vmalloc_addr(vaddr: VAddr) -> bool {
+    VmallocMemoryRegion.vaddr_range().contains_address(vaddr)
+}
+
+/// Frees memory from [`vmalloc`] or [`vmalloc_populated`]. The pages are unmapped later by a
+/// ktask, the TLB shootdown waits for the other CPUs and the caller might hold a lock or be in an
+/// interrupt.
+pub unsafe fn vfree(ptr: NonNull<u8>) {
+    let base = VFrame::new(VAddr::new(ptr.as_ptr() as u64)).sub(1);
+    let waker = {
+        let mut all_areas = ALL_AREAS.lock_noirq();
+        let area = all_areas
+            .area_tree
+            .find(&base.addr())
+            .get()
+            .expect("Not allocated with vmalloc");
+        assert!(!area.freed.replace(true), "Already freed");
+        let area = UnsafeRef::from_raw(area);
+        all_areas.purge_list.push_back(area);
+        all_areas.purge_waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+async fn purge_areas() {
+    loop {
+        let (base, range, mapped_on) = poll_fn(|cx| {
+            let mut all_areas = ALL_AREAS.lock_noirq();
+            match all_areas.purge_list.pop_front() {
+                Some(area) => Poll::Ready((area.base, area.range(), area.mapped_on.get())),
+                None => {
+                    all_areas.purge_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        // The pages and the range can't be reused until every CPU that touched them unmapped them
+        unmap_frames_on(range, mapped_on);
+        let area = ALL_AREAS
+            .lock_noirq()
+            .area_tree
+            .find_mut(&base.addr())
+            .remove()
+            .expect("Freed area should be in the tree");
+        unsafe { area.free_pages() };
+    }
+}
+
+/// Starts the ktask that unmaps the memory freed by [`vfree`]
+pub fn start_vmalloc_purge() {
+    spawn_future(purge_areas(), "[vmalloc-purge]");
+}
+
+fn alloc_zeroed_page() -> Result<PFrame, AllocError> {
+    let pframe = raw_alloc::alloc_pages(0, AllocFlags::empty())?;
+    match map_pframe(pframe, MemoryRegionType::Alloc) {
+        Ok(vframe) => {
+            unsafe { write_bytes(vframe.addr().as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+            Ok(pframe)
+        }
+        Err(_) => {
+            unsafe { raw_alloc::dealloc_pages(pframe, 0) };
+            Err(AllocError)
+        }
+    }
+}
+
+pub struct VmallocMemoryRegion;
+
+impl VmallocMemoryRegion {
+    fn find_area_for<R>(&self, vaddr: VAddr, f: impl FnOnce(&VmallocArea) -> R) -> Option<R> {
+        let all_areas = ALL_AREAS.lock_noirq();
+        let cursor = all_areas.area_tree.upper_bound(Bound::Included(&vaddr));
+        let area = cursor.get()?;
+        (area.range().contains_address(vaddr) && !area.freed.get()).then(|| f(area))
+    }
+}
+
+impl MemoryRegion for VmallocMemoryRegion {
+    fn typ(&self) -> MemoryRegionType {
+        MemoryRegionType::Vmalloc
+    }
+    fn name(&self) -> &str {
+        "vmalloc"
+    }
+
+    fn vaddr_range(&self) -> VFrameRange {
+        VFrameRange::new(
+            virt::VMALLOC_BASE,
+            virt::VMALLOC_BASE.add(virt::VMALLOC_FRAMES),
+        )
+    }
+
+    fn paddr_of(&self, vaddr: VAddr) -> Option<PAddr> {
+        self.find_area_for(vaddr, |area| {
+            let (_, off) = vaddr.frame_offset::<FrameSize4K>();
+            let pframe = area.page_for(vaddr).get();
+            (pframe != PFrame::null()).then(|| pframe.addr() + off)
+        })
+        .flatten()
+    }
+
+    fn map_paddr(&self, _: PFrame) -> Result<VFrame, MemoryMapError> {
+        Err(MemoryMapError::CannotMap)
+    }
+
+    fn handle_page_fault(&self, vaddr: VAddr, _: PageFaultReason) -> PageFaultResult {
+        self.find_area_for(vaddr, |area| {
+            let (vframe, _) = vaddr.frame_offset();
+            let page = area.page_for(vaddr);
+            if page.get() == PFrame::null() {
+                match alloc_zeroed_page() {
+                    Ok(pframe) => page.set(pframe),
+                    Err(_) => {
+                        warn!("Could not allocate a page for {:#x}", vframe);
+                        return PageFaultResult::NotMapped;
+                    }
+                }
+            }
+            // Every CPU has its own page table, the page might already be mapped on another one
+            let pframe = page.get();
+            let range = PFrameRange::new(pframe, pframe.add(1));
+            match map_frames(range, vframe, MapFlags::WRITE | MapFlags::GLOBAL) {
+                Ok(()) => {
+                    area.mapped_on
+                        .set(area.mapped_on.get() | cpumask::this_cpu());
+                    PageFaultResult::Mapped(pframe.addr())
+                }
+                Err(_) => PageFaultResult::NotMapped,
+            }
+        })
+        .unwrap_or(PageFaultResult::NotMapped)
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    fn vmalloc_maps_on_access() {
+        const SIZE: usize = 16 * PAGE_SIZE;
+        let ptr = vmalloc(SIZE).unwrap();
+        let vaddr = VAddr::new(ptr.as_ptr() as u64);
+        assert!(VmallocMemoryRegion.paddr_of(vaddr).is_none());
+        let bytes = unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), SIZE) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        bytes.fill(0xaa);
+        assert!(VmallocMemoryRegion.paddr_of(vaddr).is_some());
+        assert!(bytes.iter().all(|&b| b == 0xaa));
+        unsafe { vfree(ptr) };
+    }
+
+    #[kernel_test]
+    async fn vfree_unmaps_later() {
+        let ptr = vmalloc(PAGE_SIZE).unwrap();
+        let vaddr = VAddr::new(ptr.as_ptr() as u64);
+        unsafe { ptr.as_ptr().write(1) };
+        unsafe { vfree(ptr) };
+        assert!(VmallocMemoryRegion.paddr_of(vaddr).is_none());
+        let base = VFrame::new(vaddr).sub(1).addr();
+        while !ALL_AREAS.lock_noirq().area_tree.find(&base).is_null() {
+            delay(Duration::from_millis(1)).await;
+        }
+    }
+}
@@ -62,26 +62,32 @@ pub enum InvalidModuleSection {
     BadSize,
 }
 
-pub fn get_modules_for_elf(
+pub(crate) fn get_decls_for_elf<T>(
     elf: &Elf,
     base: VAddr,
-) -> Result<&'static [ModuleDecl], InvalidModuleSection> {
+    section: &str,
+) -> Result<&'static [T], InvalidModuleSection> {
     for sec in elf.sections() {
-        if sec.name(elf) == Some(__module_section!()) {
-            if (sec.addr_align() as usize) < align_of::<ModuleDecl>() {
+        if sec.name(elf) == Some(section) {
+            if (sec.addr_align() as usize) < align_of::<T>() {
                 return Err(InvalidModuleSection::BadAlignment);
             }
-            if (sec.size() as usize) % size_of::<ModuleDecl>() != 0 {
+            if (sec.size() as usize) % size_of::<T>() != 0 {
                 return Err(InvalidModuleSection::BadSize);
             }
             let base = base + sec.addr();
-            if base.as_usize() % align_of::<ModuleDecl>() != 0 {
+            if base.as_usize() % align_of::<T>() != 0 {
                 return Err(InvalidModuleSection::BadAlignment);
             }
-            return Ok(unsafe {
-                base.from_raw_parts((sec.size() as usize) / size_of::<ModuleDecl>())
-            });
+            return Ok(unsafe { base.from_raw_parts((sec.size() as usize) / size_of::<T>()) });
         }
     }
     Ok(unsafe { from_raw_parts(dangling(), 0) })
 }
+
+pub fn get_modules_for_elf(
+    elf: &Elf,
+    base: VAddr,
+) -> Result<&'static [ModuleDecl], InvalidModuleSection> {
+    get_decls_for_elf(elf, base, __module_section!())
+}
@@ -1,14 +1,27 @@
+use core::hint::spin_loop;
 use core::panic::PanicInfo;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use chos_lib::arch::regs::AllRegs;
+use chos_lib::arch::tables::StackFrame;
 use chos_lib::arch::x64::qemu::exit_qemu;
 use chos_lib::log::*;
-use rustc_demangle::demangle;
+use chos_lib::sync::Spinlock;
 
-use crate::symbols::lookup_symbol;
+use crate::cpumask;
+use crate::intr::send_nmi;
+use crate::mm::this_cpu_info;
+use crate::symbols::print_frame_unlocked;
 
 static IN_PANIC: AtomicBool = AtomicBool::new(false);
 
+// Taken while printing a CPU's state so that the reports don't get mixed
+static REPORT_LOCK: Spinlock<()> = Spinlock::new(());
+// CPUs that were sent an NMI and did not print their state yet
+static CPUS_TO_STOP: AtomicU64 = AtomicU64::new(0);
+// A CPU could be stuck with its NMIs blocked, don't wait forever
+const STOP_WAIT_SPINS: usize = 100_000_000;
+
 pub fn in_panic() -> bool {
     IN_PANIC.load(Ordering::Relaxed)
 }
@@ -19,21 +32,72 @@ fn panic(info: &PanicInfo) -> ! {
         .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
         .is_ok()
     {
+        let report = REPORT_LOCK.lock_nodisable();
+        // They wait on REPORT_LOCK, they will print their state after ours
+        stop_other_cpus();
         unsafe {
             unsafe_error!("========================");
             unsafe_error!("PANIC: {}", info);
             unsafe_info!("Backtrace");
             for frame in chos_lib::arch::x64::backtrace() {
-                if lookup_symbol(frame, |name, _, off| {
-                    unsafe_info!("  {:#016x} [{:#} + {:#x}]", frame, demangle(name), off);
-                })
-                .is_none()
-                {
-                    unsafe_info!("  {:#016x} [?]", frame.as_u64());
-                }
+                print_frame_unlocked(frame);
             }
             unsafe_error!("========================");
         }
+        drop(report);
+        wait_other_cpus();
+        #[cfg(feature = "kernel-test")]
+        unsafe {
+            crate::test::on_panic();
+        }
     }
     exit_qemu(chos_lib::arch::x64::qemu::QemuStatus::Error)
 }
+
+fn stop_other_cpus() {
+    let others = match cpumask::try_all() {
+        Some(all) => all - cpumask::this_cpu(),
+        None => return,
+    };
+    if others == cpumask::Cpumask::empty() {
+        return;
+    }
+    CPUS_TO_STOP.store(others.raw(), Ordering::Release);
+    send_nmi(others);
+}
+
+fn wait_other_cpus() {
+    for _ in 0..STOP_WAIT_SPINS {
+        if CPUS_TO_STOP.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        spin_loop();
+    }
+    unsafe {
+        unsafe_error!(
+            "CPUs {:?} did not stop",
+            cpumask::Cpumask::from_raw(CPUS_TO_STOP.load(Ordering::Relaxed))
+        )
+    };
+}
+
+/// Called from the NMI handler after another CPU panicked, prints the registers this CPU had when
+/// the NMI came and halts it
+pub fn stop_this_cpu(frame: &StackFrame<AllRegs>) -> ! {
+    let cpu = this_cpu_info().id;
+    {
+        let _report = REPORT_LOCK.lock_nodisable();
+        unsafe {
+            unsafe_error!("CPU {} stopped", cpu);
+            unsafe_info!("{:#x?}", frame);
+            unsafe_info!("Backtrace");
+            print_frame_unlocked(frame.intr.rip);
+            for frame in chos_lib::arch::x64::backtrace() {
+                print_frame_unlocked(frame);
+            }
+            unsafe_error!("========================");
+        }
+    }
+    CPUS_TO_STOP.fetch_and(!(1 << cpu), Ordering::Release);
+    chos_lib::arch::x64::hlt_loop()
+}
@@ -0,0 +1,227 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::fmt;
+
+use chos_lib::elf::Elf;
+use chos_lib::log::{info, warn, LogLevel};
+use chos_lib::mm::VAddr;
+
+use crate::module::{get_decls_for_elf, InvalidModuleSection};
+
+pub trait ParamValue: Copy + fmt::Debug + Sync + 'static {
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value {
+            None | Some("on" | "true" | "yes" | "1") => Some(true),
+            Some("off" | "false" | "no" | "0") => Some(false),
+            _ => None,
+        }
+    }
+}
+
+macro impl_param_value_int($($ty:ty),* $(,)?) {
+    $(
+        impl ParamValue for $ty {
+            fn parse(value: Option<&'static str>) -> Option<Self> {
+                value?.parse().ok()
+            }
+        }
+    )*
+}
+impl_param_value_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value
+    }
+}
+
+impl ParamValue for LogLevel {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value? {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            "critical" => Some(LogLevel::Critical),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamError {
+    Unknown,
+    InvalidValue,
+}
+
+pub trait KernelParam: Sync {
+    /// `key` is what follows the declared name, e.g. `PALLOC` for `log.domain.PALLOC`.
+    ///
+    /// # Safety
+    /// Must only be called while parsing the command line, before anyone reads the value.
+    unsafe fn set(&self, key: Option<&str>, value: Option<&'static str>) -> Result<(), ParamError>;
+    fn dump(&self, name: &str);
+}
+
+/// A typed parameter, written once when the command line is parsed and read-only afterwards.
+pub struct Param<T> {
+    value: UnsafeCell<T>,
+}
+unsafe impl<T: Sync> Sync for Param<T> {}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(default: T) -> Self {
+        Self {
+            value: UnsafeCell::new(default),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> T {
+        unsafe { *self.value.get() }
+    }
+}
+
+impl<T: ParamValue> KernelParam for Param<T> {
+    unsafe fn set(&self, key: Option<&str>, value: Option<&'static str>) -> Result<(), ParamError> {
+        if key.is_some() {
+            return Err(ParamError::Unknown);
+        }
+        *self.value.get() = T::parse(value).ok_or(ParamError::InvalidValue)?;
+        Ok(())
+    }
+
+    fn dump(&self, name: &str) {
+        info!("    {} = {:?}", name, self.get());
+    }
+}
+
+pub struct KernelParamDecl {
+    name: &'static str,
+    param: &'static dyn KernelParam,
+}
+
+impl KernelParamDecl {
+    pub const fn new(name: &'static str, param: &'static dyn KernelParam) -> Self {
+        Self { name, param }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn matches<'a>(&self, name: &'a str) -> Option<Option<&'a str>> {
+        let rest = name.strip_prefix(self.name)?;
+        match rest {
+            "" => Some(None),
+            _ => rest.strip_prefix('.').map(Some),
+        }
+    }
+}
+
+pub macro __param_section() {
+    ".chos.param"
+}
+
+pub macro kernel_param_decl($name:literal, $param:expr) {
+    const _: () = {
+        #[used]
+        #[link_section = $crate::param::__param_section!()]
+        static __CHOS_KERNEL_PARAM: $crate::param::KernelParamDecl =
+            $crate::param::KernelParamDecl::new($name, $param);
+    };
+}
+
+/// Declares a typed kernel parameter, set with `<name>=<value>` on the command line.
+pub macro kernel_param($name:literal, $(#[$attr:meta])* $vis:vis static $id:ident: $ty:ty = $default:expr $(,)?) {
+    $(#[$attr])*
+    $vis static $id: $crate::param::Param<$ty> = $crate::param::Param::new($default);
+    $crate::param::kernel_param_decl!($name, &$id);
+}
+
+pub fn get_params_for_elf(
+    elf: &Elf,
+    base: VAddr,
+) -> Result<&'static [KernelParamDecl], InvalidModuleSection> {
+    get_decls_for_elf(elf, base, __param_section!())
+}
+
+fn split_param(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (arg, None),
+    }
+}
+
+/// # Safety
+/// Must be called once, before any parameter is read.
+pub unsafe fn parse_command_line(params: &'static [KernelParamDecl], command_line: &str) {
+    // Values are borrowed for the lifetime of the kernel
+    let command_line: &'static str = Box::leak(String::from(command_line).into_boxed_str());
+    parse_params(params, command_line)
+}
+
+/// # Safety
+/// Must be called before any of `params` is read.
+unsafe fn parse_params(params: &[KernelParamDecl], command_line: &'static str) {
+    for arg in command_line.split_ascii_whitespace() {
+        let (name, value) = split_param(arg);
+        let res = params
+            .iter()
+            .find_map(|decl| decl.matches(name).map(|key| (decl, key)))
+            .map_or(Err(ParamError::Unknown), |(decl, key)| {
+                decl.param.set(key, value)
+            });
+        match res {
+            Ok(()) => (),
+            Err(ParamError::Unknown) => warn!("Unknown kernel parameter '{}'", name),
+            Err(ParamError::InvalidValue) => {
+                warn!("Invalid value {:?} for kernel parameter '{}'", value, name)
+            }
+        }
+    }
+}
+
+pub fn dump_params(params: &[KernelParamDecl]) {
+    info!("Kernel parameters:");
+    for decl in params {
+        decl.param.dump(decl.name);
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::test::kernel_test;
+
+    static NUMBER: Param<u32> = Param::new(1);
+    static FLAG: Param<bool> = Param::new(false);
+    static OFF: Param<bool> = Param::new(true);
+    static NAME: Param<&'static str> = Param::new("default");
+    static PARAMS: [KernelParamDecl; 4] = [
+        KernelParamDecl::new("test.number", &NUMBER),
+        KernelParamDecl::new("test.flag", &FLAG),
+        KernelParamDecl::new("test.off", &OFF),
+        KernelParamDecl::new("test.name", &NAME),
+    ];
+
+    #[kernel_test]
+    fn parse_params_sets_values() {
+        unsafe {
+            parse_params(
+                &PARAMS,
+                "test.number=42 test.flag test.off=off test.name=abc test.number=abc \
+                test.number.x=1 test.unknown",
+            )
+        };
+        assert_eq!(NUMBER.get(), 42);
+        assert!(FLAG.get());
+        assert!(!OFF.get());
+        assert_eq!(NAME.get(), "abc");
+    }
+}
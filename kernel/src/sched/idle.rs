@@ -1,9 +1,17 @@
-use chos_lib::arch::intr::wait_for_interrupt;
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+
+use chos_lib::arch::intr::{
+    disable_interrups, enable_interrupts, enable_interrupts_and_wait, wait_for_interrupt,
+};
 use chos_lib::log::debug;
 
-use super::{schedule, Task, TaskArc, TaskOps};
+use super::{disable_sched_save, ktask, restore_sched, schedule, watchdog, Task, TaskArc, TaskOps};
+use crate::cpumask::Cpumask;
+use crate::intr::send_wakeup;
 use crate::mm::virt::stack::alloc_kernel_stack;
-use crate::mm::{per_cpu_lazy, PerCpu};
+use crate::mm::{per_cpu, per_cpu_lazy, this_cpu_info, PerCpu};
+use crate::param::kernel_param;
+use crate::timer::{restart_tick, stop_tick};
 
 #[cfg(debug_assertions)]
 const IDLE_STACK_ORDER: u8 = 1;
@@ -28,10 +36,72 @@ per_cpu_lazy! {
     };
 }
 
+kernel_param!(
+    "nohz",
+    /// Stop the periodic tick of idle CPUs
+    static NOHZ: bool = true,
+);
+
+per_cpu! {
+    // Set while the CPU sleeps without its tick, it needs an IPI to notice new work
+    static mut ref TICK_STOPPED: AtomicBool = AtomicBool::new(false);
+}
+
+pub(super) fn tick_stopped(cpu: usize) -> &'static AtomicBool {
+    unsafe { &*TICK_STOPPED.get_for(cpu) }
+}
+
+/// Must be called after giving work to `cpu`, so that it doesn't sleep through it
+pub(super) fn kick_cpu(cpu: usize) {
+    // Pairs with the fence in idle_nohz(), either we see the flag or it sees the work
+    fence(Ordering::SeqCst);
+    if cpu != this_cpu_info().id && tick_stopped(cpu).load(Ordering::Relaxed) {
+        send_wakeup(cpu);
+    }
+}
+
+/// Wakes one of the sleeping CPUs of `mask`, it will look for work to steal
+pub(super) fn kick_idle_cpu(mask: Cpumask) {
+    if let Some(cpu) = mask
+        .into_iter()
+        .find(|&cpu| tick_stopped(cpu as usize).load(Ordering::Relaxed))
+    {
+        send_wakeup(cpu as usize);
+    }
+}
+
+fn idle_nohz() {
+    let stopped = tick_stopped(this_cpu_info().id);
+    // An interrupt must not switch away from idle while the tick is stopped
+    let sched = disable_sched_save();
+    disable_interrups();
+    stopped.store(true, Ordering::Relaxed);
+    fence(Ordering::SeqCst);
+    if ktask::has_queued_work() || ktask::has_ready_worker() {
+        stopped.store(false, Ordering::Relaxed);
+        enable_interrupts();
+    } else {
+        stop_tick();
+        enable_interrupts_and_wait();
+        disable_interrups();
+        stopped.store(false, Ordering::Relaxed);
+        restart_tick();
+        // Sleeping is not a lockup
+        watchdog::touch();
+        enable_interrupts();
+    }
+    restore_sched(sched);
+}
+
 fn idle_loop() -> ! {
     loop {
+        ktask::steal_work();
         schedule();
-        wait_for_interrupt();
+        if NOHZ.get() {
+            idle_nohz();
+        } else {
+            wait_for_interrupt();
+        }
     }
 }
 
@@ -0,0 +1,127 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::mem::replace;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use chos_lib::sync::{Sem, Spinlock};
+
+use super::AbortHandle;
+use crate::sched::sync::SchedSem;
+
+/// Why a ktask did not produce its result. There is no variant for a panic: the kernel is built
+/// without unwinding, so a panicking ktask can't be cleaned up and the panic stops every CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// The ktask was aborted or dropped before completing
+    Cancelled,
+}
+
+enum JoinState<T> {
+    Running,
+    Done(Result<T, JoinError>),
+    Joined,
+}
+
+struct JoinData<T> {
+    state: JoinState<T>,
+    waker: Option<Waker>,
+}
+
+pub(super) struct JoinInner<T> {
+    data: Spinlock<JoinData<T>>,
+    done: SchedSem,
+}
+
+impl<T> JoinInner<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            data: Spinlock::new(JoinData {
+                state: JoinState::Running,
+                waker: None,
+            }),
+            done: SchedSem::zero(),
+        }
+    }
+
+    pub(super) fn finish(&self, res: Result<T, JoinError>) {
+        let waker = {
+            let mut data = self.data.lock();
+            if !matches!(data.state, JoinState::Running) {
+                return;
+            }
+            data.state = JoinState::Done(res);
+            data.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        self.done.signal();
+    }
+}
+
+/// Type-erased side of the join, used by the ktask when it can't complete normally.
+pub(super) trait JoinNotify: Send + Sync {
+    fn fail(&self, err: JoinError);
+}
+
+impl<T: Send> JoinNotify for JoinInner<T> {
+    fn fail(&self, err: JoinError) {
+        self.finish(Err(err))
+    }
+}
+
+/// Owned permission to wait for a ktask. Dropping it detaches the ktask.
+pub struct JoinHandle<T> {
+    inner: Arc<JoinInner<T>>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(inner: Arc<JoinInner<T>>, abort: AbortHandle) -> Self {
+        Self { inner, abort }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.inner.data.lock().state, JoinState::Running)
+    }
+
+    /// Blocks the current task until the ktask completes
+    pub fn join(self) -> Result<T, JoinError> {
+        self.inner.done.wait();
+        let mut data = self.inner.data.lock();
+        match replace(&mut data.state, JoinState::Joined) {
+            JoinState::Done(res) => res,
+            JoinState::Running | JoinState::Joined => unreachable!("KTask should be done"),
+        }
+    }
+
+    /// Lets the ktask run to completion without anyone waiting for it
+    pub fn detach(self) {}
+
+    /// Aborts the ktask, the handle still needs to be awaited or joined to know if it completed first
+    pub fn abort(&self) {
+        self.abort.abort()
+    }
+
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut data = self.inner.data.lock();
+        match replace(&mut data.state, JoinState::Joined) {
+            JoinState::Running => {
+                data.state = JoinState::Running;
+                data.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            JoinState::Done(res) => Poll::Ready(res),
+            JoinState::Joined => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
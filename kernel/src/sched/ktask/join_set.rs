@@ -0,0 +1,153 @@
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::{spawn_future, AbortHandle, JoinError, JoinHandle, KTaskOutput};
+
+/// Set of ktasks whose results are collected in the order they complete.
+/// The remaining ktasks are aborted when the set is dropped.
+pub struct JoinSet<T> {
+    handles: Vec<JoinHandle<T>>,
+}
+
+impl<T: KTaskOutput> JoinSet<T> {
+    pub fn new() -> Self {
+        Self {
+            handles: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    pub fn spawn<F: Future<Output = T> + Send + 'static>(
+        &mut self,
+        fut: F,
+        name: impl Into<Cow<'static, str>>,
+    ) -> AbortHandle {
+        self.insert(spawn_future(fut, name))
+    }
+
+    /// Adds a ktask that was already spawned
+    pub fn insert(&mut self, handle: JoinHandle<T>) -> AbortHandle {
+        let abort = handle.abort_handle();
+        self.handles.push(handle);
+        abort
+    }
+
+    /// Waits for the next ktask to complete, returns None if the set is empty
+    pub fn join_next(&mut self) -> JoinNext<'_, T> {
+        JoinNext { set: self }
+    }
+
+    /// Waits for all the ktasks, the results are in the order they completed
+    pub async fn join_all(mut self) -> Vec<Result<T, JoinError>> {
+        let mut results = Vec::with_capacity(self.len());
+        while let Some(res) = self.join_next().await {
+            results.push(res);
+        }
+        results
+    }
+
+    pub fn abort_all(&self) {
+        for handle in &self.handles {
+            handle.abort();
+        }
+    }
+
+    /// Removes all the ktasks from the set without aborting them
+    pub fn detach_all(&mut self) {
+        self.handles.clear();
+    }
+}
+
+impl<T: KTaskOutput> Default for JoinSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for JoinSet<T> {
+    fn drop(&mut self) {
+        for handle in &self.handles {
+            handle.abort();
+        }
+    }
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct JoinNext<'a, T> {
+    set: &'a mut JoinSet<T>,
+}
+
+impl<T> Future for JoinNext<'_, T> {
+    type Output = Option<Result<T, JoinError>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let handles = &mut self.set.handles;
+        if handles.is_empty() {
+            return Poll::Ready(None);
+        }
+        for i in 0..handles.len() {
+            if let Poll::Ready(res) = Pin::new(&mut handles[i]).poll(cx) {
+                // The handle can't be polled again once it is ready
+                drop(handles.swap_remove(i));
+                return Poll::Ready(Some(res));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    async fn join_set_completion_order() {
+        let mut set = JoinSet::new();
+        for i in (0..4u64).rev() {
+            set.spawn(
+                async move {
+                    delay(Duration::from_millis(10 * i)).await;
+                    i
+                },
+                "[join-set]",
+            );
+        }
+        assert_eq!(set.len(), 4);
+        let results: Vec<_> = set
+            .join_all()
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, [0, 1, 2, 3]);
+    }
+
+    #[kernel_test]
+    async fn join_set_abort() {
+        let mut set = JoinSet::new();
+        let abort = set.spawn(core::future::pending::<()>(), "[join-set-pending]");
+        set.spawn(async {}, "[join-set-ready]");
+        abort.abort();
+        let mut results = Vec::new();
+        while let Some(res) = set.join_next().await {
+            results.push(res);
+        }
+        results.sort_by_key(Result::is_ok);
+        assert_eq!(results, [Err(JoinError::Cancelled), Ok(())]);
+        assert!(set.join_next().await.is_none());
+    }
+}
@@ -0,0 +1,769 @@
+mod join;
+mod join_set;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use chos_config::arch::mm::stack::KERNEL_STACK_PAGE_ORDER;
+use chos_lib::log::{debug, info, warn};
+use chos_lib::pool::{iarc_adapter_weak, IArc, IArcCountWeak, IWeak};
+use chos_lib::sync::Spinlock;
+use intrusive_collections::linked_list;
+use pin_project::pin_project;
+
+pub use self::join::{JoinError, JoinHandle};
+use self::join::{JoinInner, JoinNotify};
+pub use self::join_set::{JoinNext, JoinSet};
+use super::sync::SchedQueue;
+use super::{idle, Task, TaskArc, TaskOps, TaskRunningState};
+use crate::cpumask::{self, Cpumask};
+use crate::mm::slab::object_pool;
+use crate::mm::virt::stack::{alloc_kernel_stack, Stack};
+use crate::mm::{per_cpu, per_cpu_lazy, this_cpu_info, PerCpu};
+use crate::param::kernel_param;
+
+pub trait KTaskFn: 'static + Send {
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()>;
+}
+
+pub trait KTaskOutput: 'static + Send {}
+impl<T: 'static + Send> KTaskOutput for T {}
+
+#[pin_project]
+struct KTaskFnOnce<F, R> {
+    fun: Option<F>,
+    join: Arc<JoinInner<R>>,
+}
+impl<R: KTaskOutput, F: FnOnce() -> R + Send + 'static> KTaskFn for KTaskFnOnce<F, R> {
+    fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+        let this = self.project();
+        let res = (this.fun.take().expect("Should not have been called again"))();
+        this.join.finish(Ok(res));
+        Poll::Ready(())
+    }
+}
+
+#[pin_project]
+struct KTaskFuture<F: Future> {
+    #[pin]
+    fut: F,
+    join: Arc<JoinInner<F::Output>>,
+}
+impl<F: Future<Output: KTaskOutput> + Send + 'static> KTaskFn for KTaskFuture<F> {
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        let this = self.project();
+        let join = this.join;
+        this.fut.poll(ctx).map(|res| join.finish(Ok(res)))
+    }
+}
+
+// Replaces the function of an aborted ktask, so that it's dropped right away
+struct KTaskAborted;
+impl KTaskFn for KTaskAborted {
+    fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+        Poll::Ready(())
+    }
+}
+
+static KTASK_OPS: TaskOps = TaskOps { wake: |_| {} };
+
+per_cpu! {
+    static mut ref KTASK_STACK: Option<Stack> = None;
+}
+
+// When a ktask is preempted, the other workers of the CPU keep running the other ktasks
+const KTASK_INITIAL_WORKERS: usize = 2;
+
+kernel_param!(
+    "ktask.max_workers",
+    /// Most worker tasks per CPU, a new one is started when all the others are busy or blocked
+    static KTASK_MAX_WORKERS: usize = 8,
+);
+
+per_cpu! {
+    static mut ref NEXT_KTASK_WORKER: usize = 0;
+}
+
+fn create_worker(i: usize, stack: Stack) -> TaskArc {
+    debug!(
+        "Using {:#x}-{:#x} for ktask stack",
+        stack.range.start(),
+        stack.range.end()
+    );
+    Task::with_fn(
+        stack,
+        ktask_loop,
+        format!("[ktask:{}]", i),
+        &KTASK_OPS,
+        None,
+    )
+    .expect("KTask Task::new() should not fail")
+}
+
+per_cpu_lazy! {
+    static mut ref KTASK_WORKER_TASKS: Vec<TaskArc> = (0..KTASK_INITIAL_WORKERS).map(|i| {
+        let stack = match i {
+            0 => KTASK_STACK.copy().expect("KTask stack not set"),
+            _ => alloc_kernel_stack(KERNEL_STACK_PAGE_ORDER).expect("Stack alloc should not fail"),
+        };
+        create_worker(i, stack)
+    }).collect();
+}
+
+struct KTaskImpl {
+    link: linked_list::AtomicLink,
+    count: IArcCountWeak,
+    fun: Spinlock<Pin<Box<dyn KTaskFn>>>,
+    name: Cow<'static, str>,
+    mask: Cpumask,
+    join: Arc<dyn JoinNotify>,
+    // Set once the ktask returned Ready or was aborted, it can still be woken afterwards
+    done: AtomicBool,
+    aborted: AtomicBool,
+    sched_state: AtomicU8,
+}
+
+// A ktask is in at most one run queue, a wake while it is polled queues it again after the poll
+const KTASK_IDLE: u8 = 0;
+const KTASK_QUEUED: u8 = 1;
+const KTASK_RUNNING: u8 = 2;
+const KTASK_NOTIFIED: u8 = 3;
+
+impl Drop for KTaskImpl {
+    fn drop(&mut self) {
+        // Does nothing if the ktask completed
+        self.join.fail(JoinError::Cancelled);
+    }
+}
+iarc_adapter_weak!(KTaskImpl: count);
+object_pool!(struct KTaskImplPool : KTaskImpl);
+type KTaskImplArc = IArc<KTaskImpl, KTaskImplPool>;
+type KTaskImplWeak = IWeak<KTaskImpl, KTaskImplPool>;
+
+chos_lib::intrusive_adapter!(KTaskAdapter = KTaskImplArc : KTaskImpl { link: linked_list::AtomicLink });
+
+struct KTaskRunQueue {
+    queue: SchedQueue<KTaskAdapter>,
+    len: AtomicUsize,
+    // Only touched by the workers of the CPU
+    workers: AtomicUsize,
+    idle_workers: AtomicUsize,
+    pushed: AtomicU64,
+    stolen: AtomicU64,
+    steals: AtomicU64,
+}
+
+impl KTaskRunQueue {
+    const fn new() -> Self {
+        Self {
+            queue: SchedQueue::new(KTaskAdapter::NEW),
+            len: AtomicUsize::new(0),
+            workers: AtomicUsize::new(KTASK_INITIAL_WORKERS),
+            idle_workers: AtomicUsize::new(0),
+            pushed: AtomicU64::new(0),
+            stolen: AtomicU64::new(0),
+            steals: AtomicU64::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, task: KTaskImplArc) {
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.pushed.fetch_add(1, Ordering::Relaxed);
+        self.queue.push(task);
+    }
+
+    fn pop_wait(&self) -> KTaskImplArc {
+        self.idle_workers.fetch_add(1, Ordering::Relaxed);
+        let task = self.queue.pop_wait();
+        self.idle_workers.fetch_sub(1, Ordering::Relaxed);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        task
+    }
+
+    // Takes a slot for a new worker if every worker is busy and there is room for one more
+    fn reserve_worker(&self) -> Option<usize> {
+        if self.idle_workers.load(Ordering::Relaxed) != 0 {
+            return None;
+        }
+        self.workers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |workers| {
+                (workers < KTASK_MAX_WORKERS.get()).then(|| workers + 1)
+            })
+            .ok()
+    }
+
+    fn try_steal(&self, cpu: Cpumask) -> Option<KTaskImplArc> {
+        let task = self.queue.try_find_pop(|ktask| ktask.mask.contains(cpu))?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.stolen.fetch_add(1, Ordering::Relaxed);
+        Some(task)
+    }
+}
+
+per_cpu! {
+    static mut ref KTASK_RUN_QUEUE: KTaskRunQueue = KTaskRunQueue::new();
+}
+
+fn run_queue(cpu: usize) -> &'static KTaskRunQueue {
+    // The queues are only accessed through atomics and locks, they can be shared between CPUs
+    unsafe { &*KTASK_RUN_QUEUE.get_for(cpu) }
+}
+
+fn this_run_queue() -> &'static KTaskRunQueue {
+    run_queue(this_cpu_info().id)
+}
+
+// Stay on this CPU if it has nothing queued, else go to the least loaded CPU allowed
+fn select_cpu(mask: Cpumask) -> usize {
+    let this = this_cpu_info().id;
+    let allowed = mask & cpumask::all();
+    if allowed.contains(Cpumask::for_cpu(this as u8)) && run_queue(this).len() == 0 {
+        return this;
+    }
+    allowed
+        .iter()
+        .map(|cpu| cpu as usize)
+        .min_by_key(|&cpu| (run_queue(cpu).len(), cpu != this))
+        .expect("KTask mask doesn't contain any CPU")
+}
+
+fn push_to_run_queue(task: KTaskImplArc) {
+    let cpu = select_cpu(task.mask);
+    run_queue(cpu).push(task);
+    idle::kick_cpu(cpu);
+}
+
+fn enqueue(task: KTaskImplArc) {
+    let mut state = task.sched_state.load(Ordering::Acquire);
+    loop {
+        let new = match state {
+            KTASK_IDLE => KTASK_QUEUED,
+            KTASK_RUNNING => KTASK_NOTIFIED,
+            // Already queued or will be
+            _ => return,
+        };
+        match task.sched_state.compare_exchange_weak(
+            state,
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break,
+            Err(cur) => state = cur,
+        }
+    }
+    if state == KTASK_IDLE {
+        push_to_run_queue(task);
+    }
+}
+
+// Queues the ktask again if it was woken while it was polled
+fn finish_poll(task: KTaskImplArc) {
+    if task
+        .sched_state
+        .compare_exchange(
+            KTASK_RUNNING,
+            KTASK_IDLE,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        task.sched_state.store(KTASK_QUEUED, Ordering::Release);
+        push_to_run_queue(task);
+    }
+}
+
+pub(super) fn has_queued_work() -> bool {
+    this_run_queue().len() != 0
+}
+
+/// A worker of this CPU was woken up after it blocked
+pub(super) fn has_ready_worker() -> bool {
+    KTASK_WORKER_TASKS.with(|workers| workers.iter_mut().any(is_worker_ready))
+}
+
+/// Called from the tick, wakes a sleeping CPU so that it steals the ktasks waiting here
+pub(super) fn balance_tick() {
+    if this_run_queue().len() != 0 {
+        idle::kick_idle_cpu(cpumask::all_but_this_cpu());
+    }
+}
+
+/// Called from the idle task, takes a queued ktask from the busiest CPU that allows it to run here.
+pub(super) fn steal_work() -> bool {
+    let this = this_run_queue();
+    if this.len() != 0 {
+        return false;
+    }
+    let this_cpu = cpumask::this_cpu();
+    let mut victims: Vec<usize> = cpumask::all_but_this_cpu()
+        .iter()
+        .map(|cpu| cpu as usize)
+        .filter(|&cpu| run_queue(cpu).len() != 0)
+        .collect();
+    victims.sort_unstable_by_key(|&cpu| core::cmp::Reverse(run_queue(cpu).len()));
+    for cpu in victims {
+        if let Some(task) = run_queue(cpu).try_steal(this_cpu) {
+            debug!("KTask steal '{}' from CPU {}", task.name, cpu);
+            this.steals.fetch_add(1, Ordering::Relaxed);
+            this.push(task);
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KTaskQueueStats {
+    /// Ktasks currently waiting in the queue
+    pub queued: usize,
+    /// Ktasks pushed to the queue, either spawned or woken
+    pub pushed: u64,
+    /// Ktasks taken from this queue by other CPUs
+    pub stolen: u64,
+    /// Ktasks this CPU took from the queues of other CPUs
+    pub steals: u64,
+}
+
+pub fn queue_stats(cpu: usize) -> KTaskQueueStats {
+    let queue = run_queue(cpu);
+    KTaskQueueStats {
+        queued: queue.len(),
+        pushed: queue.pushed.load(Ordering::Relaxed),
+        stolen: queue.stolen.load(Ordering::Relaxed),
+        steals: queue.steals.load(Ordering::Relaxed),
+    }
+}
+
+pub fn dump_queue_stats() {
+    for cpu in cpumask::all() {
+        info!("KTask queue {}: {:?}", cpu, queue_stats(cpu as usize));
+    }
+}
+
+static KTASK_WAKER_VTABLE: RawWakerVTable = {
+    unsafe fn waker_clone(waker: *const ()) -> RawWaker {
+        let waker_data = KTaskImplArc::from_raw(waker.cast());
+        let new_waker_data = waker_data.clone();
+        drop(KTaskImplArc::into_raw(waker_data));
+        let new_ptr = KTaskImplArc::into_raw(new_waker_data);
+        RawWaker::new(new_ptr.cast(), &KTASK_WAKER_VTABLE)
+    }
+
+    unsafe fn waker_wake(waker: *const ()) {
+        let waker_data = KTaskImplArc::from_raw(waker.cast());
+        enqueue(waker_data);
+    }
+
+    unsafe fn waker_wake_by_ref(waker: *const ()) {
+        let waker_data = KTaskImplArc::from_raw(waker.cast());
+        enqueue(waker_data.clone());
+        drop(KTaskImplArc::into_raw(waker_data));
+    }
+
+    unsafe fn waker_drop(waker: *const ()) {
+        let waker_data = KTaskImplArc::from_raw(waker.cast());
+        drop(waker_data);
+    }
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop)
+};
+
+// Drops the function of an aborted ktask.
+// If another worker is polling it, it will see the abort once it releases the lock.
+fn cancel_ktask(task: &KTaskImpl) {
+    if let Some(mut fun) = task.fun.try_lock_nodisable() {
+        if !task.done.swap(true, Ordering::AcqRel) {
+            debug!("KTask '{}' aborted", task.name);
+            *fun = Box::pin(KTaskAborted);
+            task.join.fail(JoinError::Cancelled);
+        }
+    }
+}
+
+// Keeps a worker waiting for work, so that a ktask that blocks or keeps running doesn't hold back
+// the ones queued after it
+fn add_spare_worker(queue: &KTaskRunQueue) {
+    let i = match queue.reserve_worker() {
+        Some(i) => i,
+        None => return,
+    };
+    match alloc_kernel_stack(KERNEL_STACK_PAGE_ORDER) {
+        Ok(stack) => {
+            let worker = create_worker(i, stack);
+            KTASK_WORKER_TASKS.with(|workers| workers.push(worker));
+        }
+        Err(_) => {
+            queue.workers.fetch_sub(1, Ordering::Relaxed);
+            warn!("Could not allocate a stack for a new ktask worker");
+        }
+    }
+}
+
+fn ktask_loop() -> ! {
+    let queue = this_run_queue();
+    loop {
+        let task = queue.pop_wait();
+        if task.done.load(Ordering::Acquire) {
+            task.sched_state.store(KTASK_IDLE, Ordering::Release);
+            continue;
+        }
+        if task.aborted.load(Ordering::Acquire) {
+            cancel_ktask(&task);
+            task.sched_state.store(KTASK_IDLE, Ordering::Release);
+            continue;
+        }
+        task.sched_state.store(KTASK_RUNNING, Ordering::Release);
+        add_spare_worker(queue);
+        let task_clone_ptr = KTaskImplArc::into_raw(task.clone());
+        let waker =
+            unsafe { Waker::from_raw(RawWaker::new(task_clone_ptr.cast(), &KTASK_WAKER_VTABLE)) };
+        let mut ctx: Context = Context::from_waker(&waker);
+        debug!("KTask run '{}'", task.name);
+        // Don't disable interrupts, the task must stay preemptible
+        if let Some(mut fun) = task.fun.try_lock_nodisable() {
+            match fun.as_mut().poll(&mut ctx) {
+                Poll::Pending => (), // Go to next task,
+                Poll::Ready(_) => task.done.store(true, Ordering::Release),
+            }
+        } else {
+            panic!("KTask should never be locked except from this function");
+        }
+        // The abort might have happened during the poll
+        if task.aborted.load(Ordering::Acquire) {
+            cancel_ktask(&task);
+        }
+        finish_poll(task);
+    }
+}
+
+fn is_worker_ready(task: &mut TaskArc) -> bool {
+    let running_state = IArc::get_mut(task)
+        .map(|task| task.state.get_mut().running_state)
+        .unwrap_or_else(|| {
+            let state = task.state.lock();
+            state.running_state
+        });
+    match running_state {
+        TaskRunningState::Blocked => false,
+        TaskRunningState::Ready => true,
+        TaskRunningState::Zombie => panic!("KTask should never exit"),
+    }
+}
+
+// Round-robin between the workers, so that a preempted worker lets the others run
+pub(super) fn find_next_task() -> Option<TaskArc> {
+    KTASK_WORKER_TASKS.with(|workers| {
+        NEXT_KTASK_WORKER.with(|next| {
+            let count = workers.len();
+            let idx = (0..count)
+                .map(|i| (*next + i) % count)
+                .find(|&idx| is_worker_ready(&mut workers[idx]))?;
+            *next = (idx + 1) % count;
+            Some(workers[idx].clone())
+        })
+    })
+}
+
+fn do_spawn(task: KTaskImplArc) {
+    enqueue(task);
+}
+
+fn create_ktask<R: KTaskOutput, F: KTaskFn>(
+    make_fun: impl FnOnce(Arc<JoinInner<R>>) -> F,
+    name: impl Into<Cow<'static, str>>,
+    mask: Cpumask,
+) -> KTask<R> {
+    let join = Arc::new(JoinInner::new());
+    let ktask = KTaskImplArc::new(KTaskImpl {
+        link: linked_list::AtomicLink::new(),
+        count: IArcCountWeak::new(),
+        fun: Spinlock::new(Box::pin(make_fun(join.clone()))),
+        name: name.into(),
+        mask,
+        join: join.clone(),
+        done: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        sched_state: AtomicU8::new(KTASK_IDLE),
+    });
+    let abort = AbortHandle {
+        ktask: IArc::downgrade(&ktask),
+    };
+    KTask {
+        ktask,
+        handle: JoinHandle::new(join, abort),
+    }
+}
+
+pub fn init_ktask_stack(stack: Stack) {
+    KTASK_STACK.with(|ktask_stack| {
+        if ktask_stack.is_some() {
+            panic!("KTask stack already set");
+        }
+        *ktask_stack = Some(stack);
+    });
+}
+
+/// Dropping a KTask without spawning it cancels it
+pub struct KTask<T = ()> {
+    ktask: KTaskImplArc,
+    handle: JoinHandle<T>,
+}
+
+impl<T> KTask<T> {
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.handle.abort_handle()
+    }
+}
+
+/// Stops a ktask from outside. Doesn't keep the ktask alive.
+#[derive(Clone)]
+pub struct AbortHandle {
+    ktask: KTaskImplWeak,
+}
+
+impl AbortHandle {
+    /// The function of the ktask is dropped instead of being polled again and its JoinHandle
+    /// reports [`JoinError::Cancelled`]. Does nothing if the ktask already completed.
+    pub fn abort(&self) {
+        if let Some(ktask) = self.ktask.upgrade() {
+            if !ktask.done.load(Ordering::Acquire) && !ktask.aborted.swap(true, Ordering::AcqRel) {
+                // Wake it up, it might be waiting for something that will never happen
+                enqueue(ktask);
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.ktask
+            .upgrade()
+            .map_or(true, |ktask| ktask.done.load(Ordering::Acquire))
+    }
+}
+
+pub fn ktask_from_fn_mask<R: KTaskOutput>(
+    fun: impl FnOnce() -> R + Send + 'static,
+    name: impl Into<Cow<'static, str>>,
+    mask: Cpumask,
+) -> KTask<R> {
+    create_ktask(
+        |join| KTaskFnOnce {
+            fun: Some(fun),
+            join,
+        },
+        name,
+        mask,
+    )
+}
+
+pub fn ktask_from_fn<R: KTaskOutput>(
+    fun: impl FnOnce() -> R + Send + 'static,
+    name: impl Into<Cow<'static, str>>,
+) -> KTask<R> {
+    ktask_from_fn_mask(fun, name, cpumask::all())
+}
+
+pub fn ktask_from_future_mask<F: Future<Output: KTaskOutput> + Send + 'static>(
+    fut: F,
+    name: impl Into<Cow<'static, str>>,
+    mask: Cpumask,
+) -> KTask<F::Output> {
+    create_ktask(|join| KTaskFuture { fut, join }, name, mask)
+}
+
+pub fn ktask_from_future<F: Future<Output: KTaskOutput> + Send + 'static>(
+    fut: F,
+    name: impl Into<Cow<'static, str>>,
+) -> KTask<F::Output> {
+    ktask_from_future_mask(fut, name, cpumask::all())
+}
+
+pub fn spawn_mask<R: KTaskOutput>(
+    fun: impl FnOnce() -> R + Send + 'static,
+    name: impl Into<Cow<'static, str>>,
+    mask: Cpumask,
+) -> JoinHandle<R> {
+    spawn_task(ktask_from_fn_mask(fun, name, mask))
+}
+
+pub fn spawn<R: KTaskOutput>(
+    f: impl FnOnce() -> R + Send + 'static,
+    name: impl Into<Cow<'static, str>>,
+) -> JoinHandle<R> {
+    spawn_mask(f, name, cpumask::all())
+}
+
+pub fn spawn_future_mask<F: Future<Output: KTaskOutput> + Send + 'static>(
+    fut: F,
+    name: impl Into<Cow<'static, str>>,
+    mask: Cpumask,
+) -> JoinHandle<F::Output> {
+    spawn_task(ktask_from_future_mask(fut, name, mask))
+}
+
+pub fn spawn_future<F: Future<Output: KTaskOutput> + Send + 'static>(
+    fut: F,
+    name: impl Into<Cow<'static, str>>,
+) -> JoinHandle<F::Output> {
+    spawn_future_mask(fut, name, cpumask::all())
+}
+
+pub fn spawn_task<T>(task: KTask<T>) -> JoinHandle<T> {
+    do_spawn(task.ktask);
+    task.handle
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use core::hint::spin_loop;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use chos_lib::sync::Sem;
+
+    use super::*;
+    use crate::async_::{join_all, oneshot};
+    use crate::sched::sync::SchedSem;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn busy_ktask_is_preempted() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let mask = cpumask::this_cpu();
+        let (sender, recv) = oneshot::channel();
+        spawn_mask(
+            || {
+                while !STOP.load(Ordering::Relaxed) {
+                    spin_loop();
+                }
+            },
+            "[busy]",
+            mask,
+        );
+        spawn_mask(
+            move || {
+                STOP.store(true, Ordering::Relaxed);
+                sender.send(());
+            },
+            "[stop]",
+            mask,
+        );
+        recv.await;
+    }
+
+    #[kernel_test]
+    async fn blocked_ktasks_get_new_workers() {
+        const BLOCKED: usize = KTASK_INITIAL_WORKERS + 1;
+        let mask = cpumask::this_cpu();
+        let sem = Arc::new(SchedSem::zero());
+        let blocked: Vec<_> = (0..BLOCKED)
+            .map(|_| {
+                let sem = sem.clone();
+                spawn_mask(move || sem.wait(), "[blocked]", mask)
+            })
+            .collect();
+        let signal = spawn_mask(
+            move || {
+                for _ in 0..BLOCKED {
+                    sem.signal();
+                }
+            },
+            "[signal]",
+            mask,
+        );
+        assert_eq!(signal.await, Ok(()));
+        for res in join_all(blocked).await {
+            assert_eq!(res, Ok(()));
+        }
+    }
+
+    #[kernel_test]
+    async fn ktask_respects_mask() {
+        for cpu in cpumask::all() {
+            let (sender, recv) = oneshot::channel();
+            spawn_mask(
+                move || sender.send(this_cpu_info().id),
+                "[mask]",
+                Cpumask::for_cpu(cpu),
+            );
+            assert_eq!(recv.await, cpu as usize);
+        }
+    }
+
+    #[kernel_test]
+    async fn join_returns_value() {
+        assert_eq!(spawn(|| 42, "[join]").await, Ok(42));
+        assert_eq!(spawn_future(async { 43 }, "[join]").await, Ok(43));
+    }
+
+    #[kernel_test]
+    fn join_blocking() {
+        assert_eq!(spawn(|| 42, "[join]").join(), Ok(42));
+    }
+
+    #[kernel_test]
+    async fn abort_drops_future() {
+        struct SetOnDrop(Arc<AtomicBool>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Release);
+            }
+        }
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = SetOnDrop(dropped.clone());
+        let handle = spawn_future(
+            async move {
+                let _guard = guard;
+                core::future::pending::<()>().await
+            },
+            "[abort]",
+        );
+        handle.abort();
+        assert_eq!(handle.await, Err(JoinError::Cancelled));
+        assert!(dropped.load(Ordering::Acquire));
+    }
+
+    #[kernel_test]
+    async fn wake_twice_while_polled() {
+        struct WakeTwice(usize);
+        impl Future for WakeTwice {
+            type Output = ();
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+                if self.0 == 0 {
+                    return Poll::Ready(());
+                }
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+        assert_eq!(spawn_future(WakeTwice(16), "[wake-twice]").await, Ok(()));
+    }
+
+    #[kernel_test]
+    async fn abort_after_completion() {
+        let handle = spawn(|| 42, "[abort-done]");
+        let abort = handle.abort_handle();
+        assert_eq!(handle.await, Ok(42));
+        abort.abort();
+        assert!(abort.is_finished());
+    }
+
+    #[kernel_test]
+    async fn join_reports_cancel() {
+        let KTask { ktask, handle } = ktask_from_fn(|| 42, "[join-cancel]");
+        drop(ktask);
+        assert_eq!(handle.await, Err(JoinError::Cancelled));
+    }
+}
@@ -1,13 +1,17 @@
 mod idle;
 pub mod ktask;
 pub mod sync;
+pub mod watchdog;
 
 use alloc::borrow::Cow;
 use core::intrinsics::likely;
 use core::ptr::NonNull;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use chos_config::timer::TICKS_HZ;
+use chos_lib::arch::intr::without_interrupts;
 use chos_lib::init::ConstInit;
+use chos_lib::int::CeilDiv;
 use chos_lib::log::debug;
 use chos_lib::pool::{iarc_adapter, IArc, IArcCount};
 use chos_lib::sync::Spinlock;
@@ -16,7 +20,18 @@ use intrusive_collections::LinkedListAtomicLink;
 use crate::arch::sched::ArchTaskState;
 use crate::mm::slab::DefaultPoolObjectAllocator;
 use crate::mm::virt::stack::Stack;
-use crate::mm::{per_cpu, PerCpu};
+use crate::mm::{per_cpu, this_cpu_info, PerCpu};
+use crate::param::kernel_param;
+
+kernel_param!(
+    "sched.timeslice_ms",
+    /// How long a task runs before being preempted if another task is ready
+    static TIMESLICE_MS: u64 = 10,
+);
+
+fn timeslice_ticks() -> u64 {
+    (TIMESLICE_MS.get() * TICKS_HZ).ceil_div(1000).max(1)
+}
 
 pub struct TaskOps {
     pub wake: fn(&Task),
@@ -45,6 +60,12 @@ pub struct Task {
     pub state: Spinlock<TaskState>,
     data: Option<NonNull<()>>,
     ops: &'static TaskOps,
+    // Ticks left in the time slice, only touched by the CPU running the task
+    slice: AtomicU64,
+    // Set while a CPU runs the task, for the locks that spin while their owner runs
+    on_cpu: AtomicBool,
+    // Tasks stay on the CPU that created them
+    cpu: usize,
 }
 unsafe impl Send for Task {}
 unsafe impl Sync for Task {}
@@ -78,6 +99,9 @@ impl Task {
                 }),
                 data,
                 ops,
+                slice: AtomicU64::new(0),
+                on_cpu: AtomicBool::new(false),
+                cpu: this_cpu_info().id,
             })
             .ok()?,
         )
@@ -97,6 +121,17 @@ impl Task {
         ArchTaskState::switch_to(cur, new);
     }
 
+    fn reset_slice(&self) {
+        self.slice.store(timeslice_ticks(), Ordering::Relaxed);
+    }
+
+    // Returns true when the slice is used up
+    fn tick_slice(&self) -> bool {
+        let left = self.slice.load(Ordering::Relaxed).saturating_sub(1);
+        self.slice.store(left, Ordering::Relaxed);
+        left == 0
+    }
+
     fn mark_blocked_and_schedule(this: TaskArc) {
         {
             let mut state = this.state.lock();
@@ -114,6 +149,8 @@ impl Task {
             state.running_state = TaskRunningState::Ready;
         }
         (this.ops.wake)(&this);
+        // The CPU might be sleeping without its tick
+        idle::kick_cpu(this.cpu);
     }
 }
 
@@ -133,21 +170,30 @@ fn find_next_task() -> TaskArc {
 }
 
 fn do_schedule(cur: TaskArc) {
+    NEED_RESCHED.with(|need_resched| *need_resched = false);
+    watchdog::touch();
     let new = find_next_task();
+    new.reset_slice();
     if cur.get_ptr() != new.get_ptr() {
         CURRENT_TASK.with(|cur| *cur = Some(new.clone()));
+        cur.on_cpu.store(false, Ordering::Relaxed);
+        new.on_cpu.store(true, Ordering::Relaxed);
         Task::switch_to(cur, new);
     }
 }
 
 pub fn schedule() {
-    do_schedule(current_task_arc())
+    // Don't get preempted while switching
+    without_interrupts(|| do_schedule(current_task_arc()))
 }
 
 pub fn enter_schedule() -> ! {
     IN_SCHED.store(true, core::sync::atomic::Ordering::Relaxed);
     debug!("enter_schedule()");
+    watchdog::touch();
     let task = find_next_task();
+    task.reset_slice();
+    task.on_cpu.store(true, Ordering::Relaxed);
     CURRENT_TASK.with(|cur| {
         debug_assert!(cur.is_none());
         *cur = Some(task.clone());
@@ -155,8 +201,34 @@ pub fn enter_schedule() -> ! {
     Task::enter_first_task(task)
 }
 
+per_cpu! {
+    static mut ref NEED_RESCHED: bool = false;
+}
+
+/// Called from the timer interrupt on every CPU
 pub fn schedule_tick() {
-    // TODO
+    if !in_sched() {
+        return;
+    }
+    watchdog::on_tick();
+    ktask::balance_tick();
+    let expired = CURRENT_TASK.with(|task| task.as_deref().map_or(false, Task::tick_slice));
+    if expired {
+        NEED_RESCHED.with(|need_resched| *need_resched = true);
+    }
+}
+
+/// Called at the end of an interrupt, once it has been acknowledged.
+/// If the current task used up its slice, switch to another one unless scheduling is disabled,
+/// in which case we'll try again on the next interrupt.
+pub fn schedule_irq_exit() {
+    if !in_sched() {
+        return;
+    }
+    let preempt = NEED_RESCHED.copy() && SCHED_DISABLE.copy() == 0;
+    if preempt {
+        schedule();
+    }
 }
 
 #[inline]
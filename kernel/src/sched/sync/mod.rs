@@ -1,6 +1,10 @@
 
+mod mutex;
 mod queue;
+mod rwlock;
 mod sem;
 
+pub use mutex::*;
 pub use queue::*;
+pub use rwlock::*;
 pub use sem::*;
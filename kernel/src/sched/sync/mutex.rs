@@ -0,0 +1,184 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::Ordering;
+
+use chos_lib::sync::Spinlock;
+
+use super::sem::{block_current_task, wake_first, WaitList, WaitNodeAdapter};
+use crate::sched::{current_task_arc, in_sched, TaskArc};
+
+// How many times we check on a running owner before blocking
+pub(super) const SPIN_TRIES: usize = 1000;
+
+/// Spin only while the owner runs, it will probably release the lock soon. Before the scheduler
+/// starts there is no task to block so we always spin.
+pub(super) fn should_spin(owner: Option<&TaskArc>, spins: usize) -> bool {
+    !in_sched() || (spins < SPIN_TRIES && owner.map_or(false, |o| o.on_cpu.load(Ordering::Relaxed)))
+}
+
+#[cfg(debug_assertions)]
+pub(super) fn check_not_owner(owner: Option<&TaskArc>, name: &str) {
+    if let Some(owner) = owner {
+        if in_sched() && crate::sched::with_current_task_ref(|cur| core::ptr::eq(cur, &**owner)) {
+            panic!(
+                "{} already locked by the current task '{}'",
+                name,
+                owner.debug_name().unwrap_or("?")
+            );
+        }
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    // None if the lock was taken before the scheduler started
+    owner: Option<TaskArc>,
+    waiters: WaitList,
+}
+
+/// Mutex that blocks the task while it waits, instead of spinning like [`Spinlock`].
+/// Must not be used from interrupts.
+pub struct SchedMutex<T: ?Sized> {
+    state: Spinlock<MutexState>,
+    value: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for SchedMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SchedMutex<T> {}
+
+impl<T> SchedMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Spinlock::new(MutexState {
+                locked: false,
+                owner: None,
+                waiters: WaitList::new(WaitNodeAdapter::NEW),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SchedMutex<T> {
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn lock(&self) -> SchedMutexGuard<'_, T> {
+        let mut spins = 0;
+        loop {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                state.owner = in_sched().then(current_task_arc);
+                return SchedMutexGuard { mutex: self };
+            }
+            #[cfg(debug_assertions)]
+            check_not_owner(state.owner.as_ref(), "SchedMutex");
+            if should_spin(state.owner.as_ref(), spins) {
+                drop(state);
+                spins += 1;
+                spin_loop();
+                continue;
+            }
+            block_current_task(state, |state| &mut state.waiters);
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SchedMutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        state.owner = in_sched().then(current_task_arc);
+        Some(SchedMutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().locked
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SchedMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("SchedMutex")
+            .field("locked", &state.locked)
+            .field(
+                "owner",
+                &state.owner.as_ref().and_then(|owner| owner.debug_name()),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct SchedMutexGuard<'a, T: ?Sized> {
+    mutex: &'a SchedMutex<T>,
+}
+// The owner is the task that locked it
+impl<T: ?Sized> !Send for SchedMutexGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SchedMutexGuard<'_, T> {}
+
+impl<T: ?Sized> Drop for SchedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        state.owner = None;
+        wake_first(&mut state.waiters);
+    }
+}
+
+impl<T: ?Sized> Deref for SchedMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SchedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::sync::Arc;
+    use core::time::Duration;
+
+    use super::*;
+    use crate::async_::join_all;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+    use crate::timer::spin_delay;
+
+    #[kernel_test]
+    async fn sched_mutex_excludes() {
+        let mutex = Arc::new(SchedMutex::new(0usize));
+        let tasks = (0..8).map(|_| {
+            let mutex = mutex.clone();
+            spawn_future(
+                async move {
+                    for _ in 0..16 {
+                        let mut value = mutex.lock();
+                        let v = *value;
+                        spin_delay(Duration::from_micros(10));
+                        *value = v + 1;
+                    }
+                },
+                "[sched-mutex]",
+            )
+        });
+        for res in join_all(tasks).await {
+            assert_eq!(res, Ok(()));
+        }
+        assert!(!mutex.is_locked());
+        assert_eq!(*mutex.try_lock().unwrap(), 8 * 16);
+    }
+}
@@ -1,4 +1,5 @@
-use chos_lib::sync::{Spinlock, Sem};
+use chos_lib::sync::sem::TrySem;
+use chos_lib::sync::{Sem, Spinlock};
 use intrusive_collections::{linked_list, Adapter, PointerOps};
 
 use super::SchedSem;
@@ -30,7 +31,10 @@ impl<A: Adapter<LinkOps: linked_list::LinkedListOps>> SchedQueue<A> {
         list.pop_front().unwrap()
     }
 
-    pub fn find_pop_wait(&self, mut filter: impl FnMut(&<A::PointerOps as PointerOps>::Value) -> bool) -> Option<<A::PointerOps as PointerOps>::Pointer> {
+    pub fn find_pop_wait(
+        &self,
+        mut filter: impl FnMut(&<A::PointerOps as PointerOps>::Value) -> bool,
+    ) -> Option<<A::PointerOps as PointerOps>::Pointer> {
         self.sem.wait();
         {
             let mut list = self.list.lock();
@@ -45,4 +49,25 @@ impl<A: Adapter<LinkOps: linked_list::LinkedListOps>> SchedQueue<A> {
         self.sem.signal(); // We ended not taking an element from the list
         None
     }
+
+    pub fn try_find_pop(
+        &self,
+        mut filter: impl FnMut(&<A::PointerOps as PointerOps>::Value) -> bool,
+    ) -> Option<<A::PointerOps as PointerOps>::Pointer> {
+        if !self.sem.try_wait() {
+            return None;
+        }
+        {
+            let mut list = self.list.lock();
+            let mut cursor = list.front_mut();
+            while let Some(value) = cursor.get() {
+                if filter(value) {
+                    return Some(cursor.remove().unwrap());
+                }
+                cursor.move_next();
+            }
+        }
+        self.sem.signal(); // We ended not taking an element from the list
+        None
+    }
 }
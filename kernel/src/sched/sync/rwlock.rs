@@ -0,0 +1,231 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+
+use chos_lib::sync::Spinlock;
+
+#[cfg(debug_assertions)]
+use super::mutex::check_not_owner;
+use super::mutex::should_spin;
+use super::sem::{block_current_task, wake_all, wake_first, WaitList, WaitNodeAdapter};
+use crate::sched::{current_task_arc, in_sched, TaskArc};
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    // Only the writer is tracked, None if it was taken before the scheduler started
+    owner: Option<TaskArc>,
+    // Writers that are blocked, counted until they take the lock, new readers wait behind them
+    writers_waiting: usize,
+    read_waiters: WaitList,
+    write_waiters: WaitList,
+}
+
+impl RwLockState {
+    // Writers have priority, new readers wait behind a waiting writer. A woken writer is not in
+    // write_waiters anymore, it is still counted until it runs.
+    fn can_read(&self) -> bool {
+        !self.writer && self.writers_waiting == 0
+    }
+
+    fn can_write(&self) -> bool {
+        !self.writer && self.readers == 0
+    }
+
+    fn wake(&mut self) {
+        if self.writers_waiting != 0 {
+            if self.can_write() {
+                wake_first(&mut self.write_waiters);
+            }
+        } else if !self.writer {
+            wake_all(&mut self.read_waiters);
+        }
+    }
+}
+
+/// Reader-writer version of [`SchedMutex`](super::SchedMutex), writers have priority
+pub struct SchedRwLock<T: ?Sized> {
+    state: Spinlock<RwLockState>,
+    value: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for SchedRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for SchedRwLock<T> {}
+
+impl<T> SchedRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Spinlock::new(RwLockState {
+                readers: 0,
+                writer: false,
+                owner: None,
+                writers_waiting: 0,
+                read_waiters: WaitList::new(WaitNodeAdapter::NEW),
+                write_waiters: WaitList::new(WaitNodeAdapter::NEW),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SchedRwLock<T> {
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn read(&self) -> SchedReadGuard<'_, T> {
+        let mut spins = 0;
+        loop {
+            let mut state = self.state.lock();
+            if state.can_read() {
+                state.readers += 1;
+                return SchedReadGuard { lock: self };
+            }
+            #[cfg(debug_assertions)]
+            check_not_owner(state.owner.as_ref(), "SchedRwLock");
+            if state.writer && should_spin(state.owner.as_ref(), spins) {
+                drop(state);
+                spins += 1;
+                spin_loop();
+                continue;
+            }
+            block_current_task(state, |state| &mut state.read_waiters);
+        }
+    }
+
+    pub fn write(&self) -> SchedWriteGuard<'_, T> {
+        let mut spins = 0;
+        // Counted in writers_waiting
+        let mut queued = false;
+        loop {
+            let mut state = self.state.lock();
+            if state.can_write() {
+                if queued {
+                    state.writers_waiting -= 1;
+                }
+                state.writer = true;
+                state.owner = in_sched().then(current_task_arc);
+                return SchedWriteGuard { lock: self };
+            }
+            #[cfg(debug_assertions)]
+            check_not_owner(state.owner.as_ref(), "SchedRwLock");
+            // The readers aren't tracked, we can only spin on a writer
+            if state.writer && should_spin(state.owner.as_ref(), spins) {
+                drop(state);
+                spins += 1;
+                spin_loop();
+                continue;
+            }
+            if !queued {
+                queued = true;
+                state.writers_waiting += 1;
+            }
+            block_current_task(state, |state| &mut state.write_waiters);
+        }
+    }
+
+    pub fn try_read(&self) -> Option<SchedReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        state.can_read().then(|| {
+            state.readers += 1;
+            SchedReadGuard { lock: self }
+        })
+    }
+
+    pub fn try_write(&self) -> Option<SchedWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        state.can_write().then(|| {
+            state.writer = true;
+            state.owner = in_sched().then(current_task_arc);
+            SchedWriteGuard { lock: self }
+        })
+    }
+}
+
+pub struct SchedReadGuard<'a, T: ?Sized> {
+    lock: &'a SchedRwLock<T>,
+}
+impl<T: ?Sized> !Send for SchedReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SchedReadGuard<'_, T> {}
+
+impl<T: ?Sized> Drop for SchedReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.wake();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for SchedReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+pub struct SchedWriteGuard<'a, T: ?Sized> {
+    lock: &'a SchedRwLock<T>,
+}
+impl<T: ?Sized> !Send for SchedWriteGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SchedWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Drop for SchedWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.writer = false;
+        state.owner = None;
+        state.wake();
+    }
+}
+
+impl<T: ?Sized> Deref for SchedWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SchedWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::sync::Arc;
+    use core::mem::forget;
+    use core::time::Duration;
+
+    use super::*;
+    use crate::sched::ktask::spawn_future;
+    use crate::test::kernel_test;
+    use crate::timer::delay;
+
+    #[kernel_test]
+    async fn sched_rwlock_writer_preference() {
+        let lock = Arc::new(SchedRwLock::new(0));
+        // The guards can't be held across an await, the read lock is released by hand below
+        forget(lock.read());
+        let writer = spawn_future(
+            {
+                let lock = lock.clone();
+                async move { *lock.write() = 1 }
+            },
+            "[sched-rwlock-writer]",
+        );
+        while lock.state.lock().writers_waiting == 0 {
+            delay(Duration::from_millis(1)).await;
+        }
+        // The blocked writer goes first
+        assert!(lock.try_read().is_none());
+        drop(SchedReadGuard { lock: &*lock });
+        assert_eq!(writer.await, Ok(()));
+        assert_eq!(*lock.try_read().unwrap(), 1);
+    }
+}
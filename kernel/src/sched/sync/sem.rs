@@ -2,16 +2,50 @@ use chos_lib::sync::sem::TrySem;
 use chos_lib::sync::{Sem, SpinSem, Spinlock};
 use intrusive_collections::{intrusive_adapter, linked_list, UnsafeMut};
 
-use crate::sched::{current_task_arc, Task, TaskArc};
+use crate::sched::{current_task_arc, schedule, Task, TaskArc, TaskRunningState};
 
 const TRIES: usize = 64;
 
-struct WaitNode {
+pub(super) struct WaitNode {
     link: linked_list::AtomicLink,
     count: usize,
     task: Option<TaskArc>,
 }
-intrusive_adapter!(WaitNodeAdapter = UnsafeMut<WaitNode>: WaitNode { link: linked_list::AtomicLink });
+intrusive_adapter!(pub(super) WaitNodeAdapter = UnsafeMut<WaitNode>: WaitNode { link: linked_list::AtomicLink });
+pub(super) type WaitList = linked_list::LinkedList<WaitNodeAdapter>;
+
+/// Blocks the current task until it is removed from the list by [`wake_first`] or [`wake_all`].
+/// `guard` is the lock of the list, it is released before switching to another task.
+pub(super) fn block_current_task<G>(mut guard: G, list: impl FnOnce(&mut G) -> &mut WaitList) {
+    let task = current_task_arc();
+    let mut node = WaitNode {
+        link: linked_list::AtomicLink::new(),
+        count: 1,
+        task: Some(task.clone()),
+    };
+    list(&mut guard).push_back(unsafe { UnsafeMut::from_raw(&mut node) });
+    // Blocked before the list is unlocked, otherwise the wakeup could come first and be lost
+    task.state.lock().running_state = TaskRunningState::Blocked;
+    drop(guard);
+    schedule();
+    assert!(!node.link.is_linked());
+}
+
+/// Returns false if the list was empty
+pub(super) fn wake_first(list: &mut WaitList) -> bool {
+    match list.pop_front() {
+        Some(mut node) => {
+            let task = node.task.take().unwrap();
+            Task::wake(task);
+            true
+        }
+        None => false,
+    }
+}
+
+pub(super) fn wake_all(list: &mut WaitList) {
+    while wake_first(list) {}
+}
 
 pub struct SchedSem {
     inner: SpinSem,
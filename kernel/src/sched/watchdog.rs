@@ -0,0 +1,213 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use chos_config::timer::TICKS_HZ;
+use chos_lib::arch::regs::AllRegs;
+use chos_lib::arch::tables::StackFrame;
+use chos_lib::log::{unsafe_error, unsafe_info, warn};
+
+use super::idle::tick_stopped;
+use crate::cpumask::{self, Cpumask};
+use crate::intr::send_nmi;
+use crate::mm::{per_cpu, this_cpu_info, PerCpu};
+use crate::param::kernel_param;
+use crate::symbols::print_frame_unlocked;
+use crate::timer::{start_periodic_nmi, ticks};
+
+kernel_param!(
+    "watchdog",
+    /// Report the CPUs that are stuck
+    static WATCHDOG: bool = true,
+);
+
+kernel_param!(
+    "watchdog.soft_lockup_secs",
+    /// How long a CPU can go without scheduling before it is reported
+    static SOFT_LOCKUP_SECS: u64 = 10,
+);
+
+kernel_param!(
+    "watchdog.hard_lockup_secs",
+    /// How long a CPU can stay with interrupts disabled before it is reported
+    static HARD_LOCKUP_SECS: u64 = 5,
+);
+
+// How many NMIs must find the CPU stuck in a row, the first one might come right after a nohz
+// sleep during which the tick didn't run
+const HARD_LOCKUP_MISSES: u64 = 2;
+
+// Set when the NMIs come from a timer, every CPU then gets them whatever the other CPUs are doing
+static PERIODIC_NMI: AtomicBool = AtomicBool::new(false);
+
+struct WatchdogState {
+    // Tick of the last time the scheduler ran
+    last_schedule: AtomicU64,
+    // Only counts the ticks this CPU received
+    ticks: AtomicU64,
+    // Value of `ticks` on the previous NMI
+    nmi_ticks: AtomicU64,
+    nmi_misses: AtomicU64,
+    // Only report once per lockup
+    soft_reported: AtomicBool,
+    hard_reported: AtomicBool,
+}
+
+per_cpu! {
+    static mut ref WATCHDOG_STATE: WatchdogState = WatchdogState {
+        last_schedule: AtomicU64::new(0),
+        ticks: AtomicU64::new(0),
+        nmi_ticks: AtomicU64::new(0),
+        nmi_misses: AtomicU64::new(0),
+        soft_reported: AtomicBool::new(false),
+        hard_reported: AtomicBool::new(false),
+    };
+}
+
+fn watchdog_state(cpu: usize) -> &'static WatchdogState {
+    // Only atomics, it can be shared between CPUs
+    unsafe { &*WATCHDOG_STATE.get_for(cpu) }
+}
+
+/// Tells the soft-lockup watchdog that the scheduler ran on this CPU
+pub fn touch() {
+    let state = watchdog_state(this_cpu_info().id);
+    state.last_schedule.store(ticks(), Ordering::Relaxed);
+    state.soft_reported.store(false, Ordering::Relaxed);
+}
+
+/// Starts the NMIs of the hard-lockup detector, must be called once every CPU handles interrupts
+pub fn start_watchdog() {
+    if !WATCHDOG.get() {
+        return;
+    }
+    let period_ms = (HARD_LOCKUP_SECS.get() * 1000 / HARD_LOCKUP_MISSES).max(1);
+    if start_periodic_nmi(Duration::from_millis(period_ms), cpumask::all()) {
+        PERIODIC_NMI.store(true, Ordering::Relaxed);
+    } else {
+        warn!("No timer can send the watchdog NMI, hard lockups are only seen from another CPU");
+    }
+}
+
+// Without a timer NMI, each CPU checks the next one from its tick, a stuck CPU can't check itself
+fn next_cpu(cpu: usize) -> Option<usize> {
+    let all = cpumask::try_all()?;
+    let next = all
+        .into_iter()
+        .find(|&next| next as usize > cpu)
+        .or_else(|| all.into_iter().next())? as usize;
+    (next != cpu).then(|| next)
+}
+
+/// Called from the tick on every CPU once the scheduler runs
+pub(super) fn on_tick() {
+    if !WATCHDOG.get() {
+        return;
+    }
+    let cpu = this_cpu_info().id;
+    let state = watchdog_state(cpu);
+    let count = state.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let since = ticks().saturating_sub(state.last_schedule.load(Ordering::Relaxed));
+    if since > SOFT_LOCKUP_SECS.get() * TICKS_HZ
+        && !state.soft_reported.swap(true, Ordering::Relaxed)
+    {
+        let secs = since / TICKS_HZ;
+        unsafe {
+            unsafe_error!("Soft lockup on CPU {}, did not schedule for {}s", cpu, secs);
+            unsafe_info!("Backtrace");
+            for frame in chos_lib::arch::x64::backtrace() {
+                print_frame_unlocked(frame);
+            }
+        }
+    }
+
+    // This fallback misses a lockup when there is a single CPU, or when every CPU is stuck, like
+    // in an ABBA deadlock, since no tick runs to send the NMI
+    let period = (HARD_LOCKUP_SECS.get() * TICKS_HZ / HARD_LOCKUP_MISSES).max(1);
+    if !PERIODIC_NMI.load(Ordering::Relaxed) && count % period == 0 {
+        if let Some(next) = next_cpu(cpu) {
+            // A CPU without its tick is idle, it isn't stuck
+            if !tick_stopped(next).load(Ordering::Relaxed) {
+                send_nmi(Cpumask::for_cpu(next as u8));
+            }
+        }
+    }
+}
+
+/// Called from the periodic NMI, or the one sent by another CPU's tick, reports this CPU if its
+/// tick did not run since the last NMI while interrupts are disabled
+pub fn on_nmi(frame: &StackFrame<AllRegs>) {
+    let cpu = this_cpu_info().id;
+    let state = watchdog_state(cpu);
+    let count = state.ticks.load(Ordering::Relaxed);
+    let stuck =
+        count == state.nmi_ticks.swap(count, Ordering::Relaxed) && !frame.intr.rflags.intr_enable();
+    if !stuck {
+        state.nmi_misses.store(0, Ordering::Relaxed);
+        state.hard_reported.store(false, Ordering::Relaxed);
+        return;
+    }
+    let misses = state.nmi_misses.fetch_add(1, Ordering::Relaxed) + 1;
+    if misses >= HARD_LOCKUP_MISSES && !state.hard_reported.swap(true, Ordering::Relaxed) {
+        // The CPU might be stuck with the log or the symbols lock
+        unsafe {
+            unsafe_error!("Hard lockup on CPU {}, interrupts are disabled", cpu);
+            unsafe_info!("{:#x?}", frame);
+            unsafe_info!("Backtrace");
+            print_frame_unlocked(frame.intr.rip);
+            for frame in chos_lib::arch::x64::backtrace() {
+                print_frame_unlocked(frame);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "spin-debug")]
+#[no_mangle]
+fn __spin_lock_timeout(
+    lock: *const (),
+    owner_apic_id: Option<u8>,
+    backtrace: &[chos_lib::mm::VAddr],
+) {
+    use chos_lib::arch::apic::initial_apic_id;
+
+    // Can happen before the per-CPU data is initialized
+    unsafe {
+        unsafe_error!(
+            "APIC id {} is spinning on lock {:p} for too long",
+            initial_apic_id(),
+            lock
+        );
+        match owner_apic_id {
+            Some(owner) => unsafe_info!("Owner APIC id {}, locked at", owner),
+            None => unsafe_info!("Owner unknown, locked at"),
+        }
+        for &frame in backtrace {
+            print_frame_unlocked(frame);
+        }
+        unsafe_info!("Waiting at");
+        for frame in chos_lib::arch::x64::backtrace() {
+            print_frame_unlocked(frame);
+        }
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    fn watchdog_ring_covers_every_cpu() {
+        let mut checked = Cpumask::empty();
+        for cpu in cpumask::all() {
+            if let Some(next) = next_cpu(cpu as usize) {
+                assert_ne!(next, cpu as usize);
+                checked |= Cpumask::for_cpu(next as u8);
+            }
+        }
+        if cpumask::cpu_count() > 1 {
+            assert!(checked == cpumask::all());
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::mem::transmute;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use chos_lib::arch::intr::without_interrupts;
+use chos_lib::sync::Spinlock;
+use intrusive_collections::{intrusive_adapter, linked_list, UnsafeRef};
+
+use crate::arch::mm::virt::handle_tlb_shootdown;
+use crate::cpumask::{self, Cpumask};
+use crate::intr::send_call_function;
+use crate::mm::{per_cpu, this_cpu_info, PerCpu};
+
+// Shared by the entries of a call, the caller waits until every CPU is done
+struct CallGroup {
+    remaining: AtomicUsize,
+    waker: Spinlock<Option<Waker>>,
+}
+
+impl CallGroup {
+    const fn new(count: usize) -> Self {
+        Self {
+            remaining: AtomicUsize::new(count),
+            waker: Spinlock::new(None),
+        }
+    }
+
+    // The caller can free the group as soon as the lock is released
+    fn complete(&self, count: usize) {
+        let waker = {
+            let mut waker = self.waker.lock_noirq();
+            if self.remaining.fetch_sub(count, Ordering::AcqRel) != count {
+                return;
+            }
+            waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        // Also waits for the last CPU to release the lock in complete()
+        let _waker = self.waker.lock_noirq();
+        self.remaining.load(Ordering::Acquire) == 0
+    }
+
+    fn poll_done(&self, cx: &Context) -> bool {
+        let mut waker = self.waker.lock_noirq();
+        if self.remaining.load(Ordering::Acquire) == 0 {
+            true
+        } else {
+            *waker = Some(cx.waker().clone());
+            false
+        }
+    }
+}
+
+type CallFn = dyn Fn() + Sync;
+
+struct CallEntry {
+    link: linked_list::AtomicLink,
+    func: *const CallFn,
+    group: *const CallGroup,
+}
+unsafe impl Send for CallEntry {}
+unsafe impl Sync for CallEntry {}
+
+impl CallEntry {
+    /// # Safety
+    /// `func` and `group` must stay alive until the group is done
+    unsafe fn new(func: &CallFn, group: &CallGroup) -> Self {
+        Self {
+            link: linked_list::AtomicLink::new(),
+            func: transmute(func),
+            group,
+        }
+    }
+}
+
+intrusive_adapter!(CallEntryAdapter = UnsafeRef<CallEntry>: CallEntry { link: linked_list::AtomicLink });
+
+type CallQueue = Spinlock<linked_list::LinkedList<CallEntryAdapter>>;
+
+per_cpu! {
+    static mut ref CALL_QUEUE: CallQueue = Spinlock::new(linked_list::LinkedList::new(CallEntryAdapter::NEW));
+}
+
+fn call_queue(cpu: usize) -> &'static CallQueue {
+    // The queues are only accessed through their lock, they can be shared between CPUs
+    unsafe { &*CALL_QUEUE.get_for(cpu) }
+}
+
+/// # Safety
+/// The entries must not move until their group is done
+unsafe fn queue_calls<'a>(entries: impl IntoIterator<Item = (usize, &'a CallEntry)>) {
+    let mut mask = Cpumask::empty();
+    for (cpu, entry) in entries {
+        call_queue(cpu)
+            .lock_noirq()
+            .push_back(UnsafeRef::from_raw(entry));
+        mask |= Cpumask::for_cpu(cpu as u8);
+    }
+    if mask != Cpumask::empty() {
+        send_call_function(mask);
+    }
+}
+
+/// Called from the call function IPI, runs the calls queued for this CPU
+pub fn handle_call_function() {
+    let queue = call_queue(this_cpu_info().id);
+    loop {
+        let entry = match queue.lock_noirq().pop_front() {
+            Some(entry) => UnsafeRef::into_raw(entry),
+            None => break,
+        };
+        unsafe {
+            let group = &*(*entry).group;
+            (*(*entry).func)();
+            group.complete(1);
+        }
+    }
+}
+
+/// Spins until `done` returns true. Handles the requests of the other CPUs in the meantime, they
+/// might be waiting on us while our interrupts are disabled.
+pub fn spin_until_handling_ipis(mut done: impl FnMut() -> bool) {
+    while !done() {
+        handle_call_function();
+        handle_tlb_shootdown();
+        spin_loop();
+    }
+}
+
+/// Runs `f` on `cpu` and waits for its result.
+/// `f` runs in an interrupt with interrupts disabled, so it must not sleep.
+/// Does not sleep either, it can be called with interrupts disabled.
+pub fn call_on<R: Send>(cpu: usize, f: impl FnOnce() -> R + Send) -> R {
+    assert!(
+        cpumask::all().contains(Cpumask::for_cpu(cpu as u8)),
+        "CPU {} is not online",
+        cpu
+    );
+    let f = Spinlock::new(Some(f));
+    let res = Spinlock::new(None);
+    let func = || {
+        let f = f
+            .lock_nodisable()
+            .take()
+            .expect("Should only be called once");
+        *res.lock_nodisable() = Some(f());
+    };
+    without_interrupts(|| {
+        if cpu == this_cpu_info().id {
+            return func();
+        }
+        let group = CallGroup::new(1);
+        let entry = unsafe { CallEntry::new(&func, &group) };
+        unsafe { queue_calls([(cpu, &entry)]) };
+        spin_until_handling_ipis(|| group.is_done());
+    });
+    res.into_inner().expect("Should have been called")
+}
+
+/// Runs `f` on every CPU in `mask` (including this one) and waits until they are all done.
+/// `f` runs in an interrupt with interrupts disabled, so it must not sleep.
+pub fn call_on_mask<F: Fn() + Sync>(mask: Cpumask, f: F) -> CallOnMaskFut<F> {
+    CallOnMaskFut {
+        mask,
+        state: Box::new(CallOnMaskState {
+            func: f,
+            group: CallGroup::new(0),
+            entries: Vec::new(),
+        }),
+        queued: false,
+    }
+}
+
+struct CallOnMaskState<F> {
+    func: F,
+    group: CallGroup,
+    entries: Vec<(usize, CallEntry)>,
+}
+
+#[must_use = "Future do nothing unless awaited"]
+pub struct CallOnMaskFut<F> {
+    mask: Cpumask,
+    // Boxed, the queued entries point into it
+    state: Box<CallOnMaskState<F>>,
+    queued: bool,
+}
+
+impl<F: Fn() + Sync> Future for CallOnMaskFut<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        if !this.queued {
+            this.queued = true;
+            let mask = this.mask;
+            let state = &mut *this.state;
+            without_interrupts(|| {
+                let cpus = mask & cpumask::all_but_this_cpu();
+                let entries = cpus
+                    .into_iter()
+                    .map(|cpu| {
+                        (cpu as usize, unsafe {
+                            CallEntry::new(&state.func, &state.group)
+                        })
+                    })
+                    .collect();
+                state.entries = entries;
+                state.group = CallGroup::new(state.entries.len());
+                unsafe { queue_calls(state.entries.iter().map(|(cpu, entry)| (*cpu, entry))) };
+                if mask.contains(cpumask::this_cpu()) {
+                    (state.func)();
+                }
+            });
+        }
+        if this.state.group.poll_done(cx) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<F> Drop for CallOnMaskFut<F> {
+    fn drop(&mut self) {
+        let state = &*self.state;
+        let mut removed = 0;
+        for (cpu, entry) in &state.entries {
+            let mut queue = call_queue(*cpu).lock_noirq();
+            if entry.link.is_linked() {
+                unsafe { queue.cursor_mut_from_ptr(entry).remove() };
+                removed += 1;
+            }
+        }
+        if removed != 0 {
+            state.group.complete(removed);
+        }
+        // The calls that already started still use the state
+        spin_until_handling_ipis(|| state.group.is_done());
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn call_on_every_cpu() {
+        let count = AtomicUsize::new(0);
+        call_on_mask(cpumask::all(), || {
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+        .await;
+        assert_eq!(count.load(Ordering::Relaxed), cpumask::cpu_count());
+
+        let cpu = cpumask::cpu_count() - 1;
+        assert_eq!(call_on(cpu, || this_cpu_info().id), cpu);
+    }
+}
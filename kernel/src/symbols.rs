@@ -3,11 +3,12 @@ use alloc::vec::Vec;
 use chos_lib::mm::VAddr;
 use chos_lib::elf::{Elf, SymtabEntryType};
 use chos_lib::init::ConstInit;
-use chos_lib::log::debug;
+use chos_lib::log::{debug, unsafe_info};
 use chos_lib::pool::PoolBox;
 use chos_lib::sync::SpinRWLock;
 use intrusive_collections::rbtree::{self, RBTree};
 use intrusive_collections::{Bound, KeyAdapter};
+use rustc_demangle::demangle;
 
 use crate::mm::slab::DefaultPoolObjectAllocator;
 
@@ -139,3 +140,15 @@ pub unsafe fn lookup_symbol_unlocked<R>(
 ) -> Option<R> {
     lookup_symbol_impl(&*SYMBOLS.get_ptr(), address, callback)
 }
+
+/// Prints one line of a backtrace, without taking the symbols lock or the log lock. For when
+/// the locks might be held by a CPU that is stuck or stopped.
+pub unsafe fn print_frame_unlocked(frame: VAddr) {
+    if lookup_symbol_unlocked(frame, |name, _, off| {
+        unsafe_info!("  {:#016x} [{:#} + {:#x}]", frame, demangle(name), off);
+    })
+    .is_none()
+    {
+        unsafe_info!("  {:#016x} [?]", frame.as_u64());
+    }
+}
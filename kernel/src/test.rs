@@ -0,0 +1,143 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::time::Duration;
+
+use chos_lib::arch::x64::qemu::{exit_qemu, QemuStatus};
+use chos_lib::elf::Elf;
+use chos_lib::log::{println, unsafe_println};
+use chos_lib::mm::VAddr;
+pub use chos_macros::kernel_test;
+use futures::future::{select, Either};
+
+use crate::module::{get_decls_for_elf, InvalidModuleSection};
+use crate::sched::ktask::{spawn, spawn_future};
+use crate::timer::delay;
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type KernelTestFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[derive(Clone, Copy)]
+pub enum KernelTestFn {
+    Sync(fn()),
+    Async(fn() -> KernelTestFuture),
+}
+
+pub struct KernelTestDecl {
+    name: &'static str,
+    fun: KernelTestFn,
+}
+
+impl KernelTestDecl {
+    pub const fn new(name: &'static str, fun: KernelTestFn) -> Self {
+        Self { name, fun }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn package(&self) -> &'static str {
+        self.name.split("::").next().unwrap_or(self.name)
+    }
+
+    pub fn fun(&self) -> KernelTestFn {
+        self.fun
+    }
+}
+
+pub fn __box_test(fut: impl Future<Output = ()> + Send + 'static) -> KernelTestFuture {
+    Box::pin(fut)
+}
+
+pub macro __test_section() {
+    ".chos.test"
+}
+
+// Tests are only compiled in a test kernel, the same way #[test] is behind cfg(test).
+// This is a macro so that the feature of this crate is checked, not the one of the caller.
+#[cfg(feature = "kernel-test")]
+pub macro __kernel_test($($item:tt)*) {
+    $($item)*
+}
+
+#[cfg(not(feature = "kernel-test"))]
+pub macro __kernel_test($($item:tt)*) {}
+
+pub fn get_tests_for_elf(
+    elf: &Elf,
+    base: VAddr,
+) -> Result<&'static [KernelTestDecl], InvalidModuleSection> {
+    get_decls_for_elf(elf, base, __test_section!())
+}
+
+// Set by the build tool, see build/src/test.rs
+fn env_list(var: Option<&'static str>) -> impl Iterator<Item = &'static str> {
+    var.unwrap_or("").split(',').filter(|s| !s.is_empty())
+}
+
+fn is_selected(test: &KernelTestDecl) -> bool {
+    let mut packages = env_list(option_env!("CHOS_TEST_PACKAGES")).peekable();
+    let mut filters = env_list(option_env!("CHOS_TEST_FILTERS")).peekable();
+    (packages.peek().is_none() || packages.any(|p| p == test.package()))
+        && (filters.peek().is_none() || filters.any(|f| test.name.contains(f)))
+}
+
+static CURRENT_TEST: AtomicPtr<KernelTestDecl> = AtomicPtr::new(null_mut());
+
+pub(crate) unsafe fn on_panic() {
+    let test = CURRENT_TEST.load(Ordering::Relaxed);
+    if let Some(test) = test.as_ref() {
+        unsafe_println!("chos-test: {} ... FAILED", test.name);
+    }
+}
+
+async fn run_test(test: &'static KernelTestDecl) -> bool {
+    // A panicking test stops the kernel, it is reported by on_panic()
+    let handle = match test.fun {
+        KernelTestFn::Sync(fun) => spawn(fun, test.name),
+        KernelTestFn::Async(fun) => spawn_future(async move { fun().await }, test.name),
+    };
+    match select(handle, delay(TEST_TIMEOUT)).await {
+        Either::Left((Ok(()), _)) => {
+            println!("chos-test: {} ... ok", test.name);
+            true
+        }
+        Either::Left((Err(_), _)) => {
+            println!("chos-test: {} ... FAILED", test.name);
+            false
+        }
+        Either::Right(_) => {
+            println!("chos-test: {} ... TIMEOUT", test.name);
+            false
+        }
+    }
+}
+
+pub async fn run_kernel_tests(tests: &'static [KernelTestDecl]) -> ! {
+    let selected: Vec<_> = tests.iter().filter(|test| is_selected(test)).collect();
+    println!("chos-test: running {} tests", selected.len());
+    let mut failed = 0;
+    for &test in &selected {
+        CURRENT_TEST.store(test as *const _ as *mut _, Ordering::Relaxed);
+        if !run_test(test).await {
+            failed += 1;
+        }
+    }
+    CURRENT_TEST.store(null_mut(), Ordering::Relaxed);
+    println!(
+        "chos-test: result: {} passed; {} failed; {} filtered out",
+        selected.len() - failed,
+        failed,
+        tests.len() - selected.len(),
+    );
+    exit_qemu(if failed == 0 {
+        QemuStatus::Success
+    } else {
+        QemuStatus::Error
+    })
+}
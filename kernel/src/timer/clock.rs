@@ -0,0 +1,25 @@
+//! Monotonic clock of the system, with nanosecond precision.
+
+use chos_lib::log::info;
+use chos_lib::sync::SpinOnceCell;
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    /// Nanoseconds since the clock was started, never goes backward and is the same on all CPUs
+    fn read_ns(&self) -> u64;
+}
+
+static CLOCKSOURCE: SpinOnceCell<&'static dyn ClockSource> = SpinOnceCell::new();
+
+pub(super) fn set_clocksource(clock: &'static dyn ClockSource) {
+    info!("Using {} clocksource", clock.name());
+    assert!(
+        SpinOnceCell::force_set(&CLOCKSOURCE, clock).is_ok(),
+        "Clocksource already set"
+    );
+}
+
+/// 0 until the timer is initialized
+pub fn clock_ns() -> u64 {
+    CLOCKSOURCE.try_get().map_or(0, |clock| clock.read_ns())
+}
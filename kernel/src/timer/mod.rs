@@ -0,0 +1,537 @@
+pub mod clock;
+pub mod system;
+mod wheel;
+
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::pin::Pin;
+use core::sync::atomic::AtomicUsize;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use core::{fmt, ops};
+
+use chos_config::timer::TICKS_HZ;
+use chos_lib::arch::intr::without_interrupts;
+use chos_lib::cpumask::Cpumask;
+use chos_lib::sync::Spinlock;
+use intrusive_collections::linked_list;
+use pin_project::pin_project;
+
+pub use self::clock::ClockSource;
+use self::clock::{clock_ns, set_clocksource};
+use self::system::init_system_time;
+pub use self::system::SystemTime;
+use self::wheel::TimerWheel;
+use crate::arch::timer::{
+    arch_init_timer, arch_init_timer_cpu, arch_set_next_event, arch_start_periodic_nmi,
+};
+use crate::kmain::KernelArgs;
+use crate::mm::{per_cpu, this_cpu_info, PerCpu};
+use crate::sched::ktask::{ktask_from_future, ktask_from_future_mask, KTask};
+use crate::sched::schedule_tick;
+
+pub const NS_PER_TICKS: u64 = 1_000_000_000 / TICKS_HZ;
+
+struct TimerData {
+    waker: Option<Waker>,
+    ready: bool,
+}
+
+struct Timer {
+    link: linked_list::AtomicLink,
+    // Slot in the wheel, only accessed with the wheel locked
+    slot: AtomicUsize,
+    deadline: Instant,
+    // The timer fires on the CPU that armed it
+    cpu: usize,
+    data: Spinlock<TimerData>,
+}
+
+per_cpu! {
+    static mut ref TIMER_WHEEL: Spinlock<TimerWheel> = Spinlock::new(TimerWheel::new());
+    // None while the tick of this CPU is stopped
+    static mut ref NEXT_TICK: Option<Instant> = None;
+}
+
+fn timer_wheel(cpu: usize) -> &'static Spinlock<TimerWheel> {
+    // The wheels are only accessed with their lock held, they can be shared between CPUs
+    unsafe { &*TIMER_WHEEL.get_for(cpu) }
+}
+
+fn fire(timer: Arc<Timer>) {
+    let mut data = timer.data.lock();
+    data.ready = true;
+    if let Some(waker) = data.waker.take() {
+        waker.wake();
+    }
+}
+
+// Must be called on the CPU of the wheel, with interrupts disabled
+fn program_next_event(wheel: &TimerWheel) {
+    let next = NEXT_TICK
+        .copy()
+        .into_iter()
+        .chain(wheel.next_expiry())
+        .min();
+    arch_set_next_event(next);
+}
+
+// Must be called with interrupts disabled, returns true if the tick was due
+fn run_timers() -> bool {
+    let now = Instant::now();
+    let tick = NEXT_TICK.with(|next| match next {
+        Some(deadline) if *deadline <= now => {
+            // Skip the ticks we missed instead of running for each of them
+            *deadline = Instant::from_ticks(now.ticks() + 1);
+            true
+        }
+        _ => false,
+    });
+    let mut wheel = timer_wheel(this_cpu_info().id).lock();
+    wheel.advance(now, fire);
+    program_next_event(&wheel);
+    tick
+}
+
+/// Called from the local timer interrupt, on a tick or when a timer is due
+pub fn on_timer_interrupt() {
+    if run_timers() {
+        schedule_tick()
+    }
+}
+
+pub fn ticks() -> u64 {
+    Instant::now().ticks()
+}
+
+pub fn init_timer(args: &KernelArgs) {
+    set_clocksource(arch_init_timer(args));
+    init_system_time();
+}
+
+/// Starts the tick of the current CPU, must be called after [`init_timer`]
+pub fn init_timer_cpu() {
+    arch_init_timer_cpu();
+    without_interrupts(restart_tick);
+}
+
+/// Sends an NMI to `cpus` every `period` from a timer that doesn't depend on the ticks, returns
+/// false if there is none
+pub fn start_periodic_nmi(period: Duration, cpus: Cpumask) -> bool {
+    arch_start_periodic_nmi(period.as_nanos() as u64, cpus)
+}
+
+/// Stops the periodic tick of this CPU until [`restart_tick`], only the next timer armed on this
+/// CPU will interrupt it. Must be called with interrupts disabled.
+pub fn stop_tick() {
+    NEXT_TICK.with(|next| *next = None);
+    program_next_event(&timer_wheel(this_cpu_info().id).lock());
+}
+
+/// Must be called with interrupts disabled
+pub fn restart_tick() {
+    NEXT_TICK.with(|next| *next = Some(Instant::from_ticks(ticks() + 1)));
+    run_timers();
+}
+
+/// Busy-waits for `d`, for when the caller can't sleep or `d` is shorter than a context switch.
+/// Must be called after [`init_timer`].
+pub fn spin_delay(d: Duration) {
+    let deadline = Instant::now() + d;
+    while Instant::now() < deadline {
+        spin_loop();
+    }
+}
+
+/// Dropping the Delay before the deadline cancels the timer
+pub struct Delay {
+    timer: Arc<Timer>,
+}
+
+impl Future for Delay {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut data = self.timer.data.lock();
+        if data.ready {
+            Poll::Ready(())
+        } else {
+            data.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        // Same lock order as run_timers()
+        let mut wheel = timer_wheel(self.timer.cpu).lock_noirq();
+        let waker = {
+            let mut data = self.timer.data.lock();
+            if data.ready {
+                return;
+            }
+            wheel.cancel(&self.timer);
+            data.waker.take()
+        };
+        drop(wheel);
+        drop(waker);
+    }
+}
+
+impl Timer {
+    fn new(deadline: Instant, cpu: usize) -> Arc<Self> {
+        Arc::new(Self {
+            link: linked_list::AtomicLink::new(),
+            slot: AtomicUsize::new(0),
+            deadline,
+            cpu,
+            data: Spinlock::new(TimerData {
+                waker: None,
+                ready: deadline <= Instant::now(),
+            }),
+        })
+    }
+}
+
+pub fn delay_until(deadline: Instant) -> Delay {
+    // Can't move to another CPU before the timer is in the wheel and the next event is programmed,
+    // a CPU with its tick stopped would miss it
+    let timer = without_interrupts(|| {
+        let cpu = this_cpu_info().id;
+        let timer = Timer::new(deadline, cpu);
+        if !timer.data.lock().ready {
+            let mut wheel = timer_wheel(cpu).lock_nodisable();
+            if wheel.insert(timer.clone()) {
+                program_next_event(&wheel);
+            }
+        }
+        timer
+    });
+    Delay { timer }
+}
+
+pub fn delay(d: Duration) -> Delay {
+    delay_until(Instant::now() + d)
+}
+
+/// Error returned by [`timeout`] when the deadline is reached first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+#[pin_project]
+#[must_use = "Future do nothing unless awaited"]
+pub struct Timeout<F> {
+    #[pin]
+    fut: F,
+    delay: Delay,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(res) = this.fut.poll(cx) {
+            return Poll::Ready(Ok(res));
+        }
+        Pin::new(this.delay).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Runs `fut` until it completes or `deadline` is reached, the timer is cancelled if `fut` completes first
+pub fn timeout_at<F: Future>(deadline: Instant, fut: F) -> Timeout<F> {
+    Timeout {
+        fut,
+        delay: delay_until(deadline),
+    }
+}
+
+pub fn timeout<F: Future>(d: Duration, fut: F) -> Timeout<F> {
+    timeout_at(Instant::now() + d, fut)
+}
+
+pub struct CancelToken<'a> {
+    cancel: &'a mut bool,
+}
+
+impl CancelToken<'_> {
+    pub fn cancel(self) {
+        *self.cancel = true;
+    }
+}
+
+#[must_use = "Must spawn the KTask"]
+pub fn periodic_ktask(
+    mut fun: impl FnMut(CancelToken) + Send + 'static,
+    period: Duration,
+    name: impl Into<Cow<'static, str>>,
+) -> KTask {
+    ktask_from_future(
+        async move {
+            let mut cancel = false;
+            while !cancel {
+                delay(period).await;
+                fun(CancelToken {
+                    cancel: &mut cancel,
+                });
+            }
+        },
+        name,
+    )
+}
+
+#[must_use = "Must spawn the KTask"]
+pub fn periodic_ktask_mask(
+    mut fun: impl FnMut(CancelToken) + Send + 'static,
+    period: Duration,
+    name: impl Into<Cow<'static, str>>,
+    mask: Cpumask,
+) -> KTask {
+    ktask_from_future_mask(
+        async move {
+            let mut cancel = false;
+            while !cancel {
+                delay(period).await;
+                fun(CancelToken {
+                    cancel: &mut cancel,
+                });
+            }
+        },
+        name,
+        mask,
+    )
+}
+
+/// Time since the clocksource was started, with nanosecond precision
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    pub const fn zero() -> Self {
+        Self { ns: 0 }
+    }
+
+    pub fn now() -> Self {
+        Self { ns: clock_ns() }
+    }
+
+    pub const fn from_nanos(ns: u64) -> Self {
+        Self { ns }
+    }
+
+    /// Start of the tick
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self {
+            ns: ticks * NS_PER_TICKS,
+        }
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.ns
+    }
+
+    /// Tick this instant is in
+    pub const fn ticks(self) -> u64 {
+        self.ns / NS_PER_TICKS
+    }
+
+    /// Zero if `earlier` is later than self
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.ns.saturating_sub(earlier.ns))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            ns: self.ns + rhs.as_nanos() as u64,
+        }
+    }
+}
+
+impl ops::Add<Instant> for Duration {
+    type Output = Instant;
+    fn add(self, rhs: Instant) -> Instant {
+        rhs + self
+    }
+}
+
+impl ops::AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl ops::Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant {
+            ns: self.ns - rhs.as_nanos() as u64,
+        }
+    }
+}
+
+impl ops::Sub<Instant> for Duration {
+    type Output = Instant;
+    fn sub(self, rhs: Instant) -> Instant {
+        Instant {
+            ns: self.as_nanos() as u64 - rhs.ns,
+        }
+    }
+}
+
+impl ops::SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::cpumask;
+    use crate::sched::ktask::spawn_future_mask;
+    use crate::test::kernel_test;
+
+    #[kernel_test]
+    async fn delay_waits_for_deadline() {
+        let start = Instant::now();
+        delay(Duration::from_millis(20)).await;
+        assert!(Instant::now() >= start + Duration::from_millis(20));
+    }
+
+    #[kernel_test]
+    async fn timeout_result() {
+        assert_eq!(timeout(Duration::from_secs(10), async { 1 }).await, Ok(1));
+        assert_eq!(
+            timeout(Duration::from_millis(10), core::future::pending::<()>()).await,
+            Err(Elapsed)
+        );
+    }
+
+    #[kernel_test]
+    fn dropped_delays_are_removed() {
+        let timers: Vec<Arc<Timer>> = (0..100)
+            .map(|_| delay(Duration::from_secs(3600)).timer.clone())
+            .collect();
+        assert!(timers.iter().all(|tim| !tim.link.is_linked()));
+    }
+
+    #[kernel_test]
+    async fn short_delays_are_precise() {
+        let start = Instant::now();
+        delay(Duration::from_micros(50)).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_micros(50));
+        // Loose bound, the test might run in an emulator
+        assert!(elapsed < Duration::from_nanos(NS_PER_TICKS * 10));
+
+        let start = Instant::now();
+        spin_delay(Duration::from_micros(10));
+        assert!(start.elapsed() >= Duration::from_micros(10));
+    }
+
+    #[kernel_test]
+    fn wheel_next_expiry() {
+        let mut wheel = Box::new(TimerWheel::new());
+        assert_eq!(wheel.next_expiry(), None);
+        let timer = |ticks| Timer::new(Instant::from_ticks(ticks), 0);
+        wheel.insert(timer(100));
+        // Needs to cascade before firing
+        assert_eq!(wheel.next_expiry(), Some(Instant::from_ticks(64)));
+        wheel.insert(timer(10));
+        // Moves to the near list one tick before
+        assert_eq!(wheel.next_expiry(), Some(Instant::from_ticks(9)));
+        wheel.advance(Instant::from_ticks(5), |_| panic!("Should not fire yet"));
+
+        let mut fired = Vec::new();
+        wheel.advance(Instant::from_ticks(100), |tim| {
+            fired.push(tim.deadline.ticks())
+        });
+        assert_eq!(fired, [10, 100]);
+        assert_eq!(wheel.next_expiry(), None);
+    }
+
+    #[kernel_test]
+    fn wheel_fires_between_ticks() {
+        let mut wheel = Box::new(TimerWheel::new());
+        let deadline = Instant::from_nanos(NS_PER_TICKS + NS_PER_TICKS / 4);
+        assert!(!wheel.insert(Timer::new(deadline, 0)));
+        assert_eq!(wheel.next_expiry(), Some(Instant::from_ticks(1)));
+        wheel.advance(Instant::from_ticks(1), |_| panic!("Should not fire yet"));
+        assert_eq!(wheel.next_expiry(), Some(deadline));
+
+        let near = Instant::from_nanos(NS_PER_TICKS + NS_PER_TICKS / 8);
+        assert!(wheel.insert(Timer::new(near, 0)));
+        assert_eq!(wheel.next_expiry(), Some(near));
+
+        let mut fired = Vec::new();
+        wheel.advance(deadline, |tim| fired.push(tim.deadline));
+        assert_eq!(fired, [deadline, near]);
+    }
+
+    #[kernel_test]
+    async fn timer_wheel_stress() {
+        const TIMERS_PER_CPU: usize = 4096;
+        let handles: Vec<_> = cpumask::all()
+            .iter()
+            .map(|cpu| {
+                spawn_future_mask(
+                    async move {
+                        let mut seed = 0x2545_f491_4f6c_dd1d ^ cpu as u64;
+                        let mut next = move || {
+                            seed = seed
+                                .wrapping_mul(6364136223846793005)
+                                .wrapping_add(1442695040888963407);
+                            seed >> 33
+                        };
+                        // Covers every level of the wheel, and past the last one
+                        let mut delays: Vec<Delay> = (0..TIMERS_PER_CPU)
+                            .map(|_| delay(Duration::from_millis(1 + next() % 100_000_000)))
+                            .collect();
+                        let timers: Vec<Arc<Timer>> =
+                            delays.iter().map(|delay| delay.timer.clone()).collect();
+                        // Cancel in a different order than they were armed
+                        while !delays.is_empty() {
+                            let idx = next() as usize % delays.len();
+                            drop(delays.swap_remove(idx));
+                        }
+                        assert!(timers.iter().all(|tim| !tim.link.is_linked()));
+
+                        let start = Instant::now();
+                        let short: Vec<Delay> = (1..=8)
+                            .map(|ms| delay(Duration::from_millis(ms * 5)))
+                            .collect();
+                        for delay in short {
+                            delay.await;
+                        }
+                        assert!(Instant::now() >= start + Duration::from_millis(40));
+                    },
+                    "[timer-stress]",
+                    Cpumask::for_cpu(cpu),
+                )
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.await, Ok(()));
+        }
+    }
+}
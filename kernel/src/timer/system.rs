@@ -0,0 +1,129 @@
+//! Wall-clock time, read from the RTC at boot and kept with the monotonic clock afterwards.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use core::{fmt, ops};
+
+use chos_lib::log::{info, warn};
+use chos_lib::time::DateTime;
+
+use super::Instant;
+use crate::arch::timer::arch_read_rtc;
+
+// Time since the Unix epoch at Instant 0, u64::MAX until the RTC is read
+static BOOT_TIME: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Time since the Unix epoch, in UTC. Unlike [`Instant`] it is not guaranteed to be monotonic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct SystemTime {
+    ns: u64,
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: Self = Self { ns: 0 };
+
+    pub fn now() -> Self {
+        Self::try_now().unwrap_or(Self::UNIX_EPOCH)
+    }
+
+    /// None until the RTC has been read
+    pub fn try_now() -> Option<Self> {
+        match BOOT_TIME.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            boot => Some(Self {
+                ns: boot + Instant::now().as_nanos(),
+            }),
+        }
+    }
+
+    pub const fn from_utc(date: &DateTime) -> Self {
+        Self {
+            ns: date.unix_nanos(),
+        }
+    }
+
+    pub const fn to_utc(self) -> DateTime {
+        DateTime::from_unix_nanos(self.ns)
+    }
+
+    /// None if `earlier` is later than self
+    pub fn duration_since(self, earlier: SystemTime) -> Option<Duration> {
+        self.ns.checked_sub(earlier.ns).map(Duration::from_nanos)
+    }
+}
+
+impl ops::Add<Duration> for SystemTime {
+    type Output = SystemTime;
+    fn add(self, rhs: Duration) -> SystemTime {
+        SystemTime {
+            ns: self.ns + rhs.as_nanos() as u64,
+        }
+    }
+}
+
+impl ops::Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+    fn sub(self, rhs: Duration) -> SystemTime {
+        SystemTime {
+            ns: self.ns - rhs.as_nanos() as u64,
+        }
+    }
+}
+
+/// Formatted as an ISO 8601 date in UTC, see [`DateTime`]
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_utc(), f)
+    }
+}
+
+pub(super) fn init_system_time() {
+    let date = arch_read_rtc();
+    let now = Instant::now();
+    let date = if date.is_valid() {
+        date
+    } else {
+        warn!("Invalid RTC date {:?}, starting at the Unix epoch", date);
+        DateTime::UNIX_EPOCH
+    };
+    BOOT_TIME.store(
+        date.unix_nanos().saturating_sub(now.as_nanos()),
+        Ordering::Relaxed,
+    );
+    info!("Current time is {}", date);
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use crate::test::kernel_test;
+
+    // 'build test' starts QEMU with its RTC set to this date
+    const TEST_RTC_BASE: DateTime = DateTime {
+        year: 2021,
+        month: 3,
+        day: 4,
+        hour: 5,
+        minute: 6,
+        second: 7,
+        nanosecond: 0,
+    };
+
+    #[kernel_test]
+    fn system_time_from_rtc() {
+        let base = SystemTime::from_utc(&TEST_RTC_BASE);
+        let now = SystemTime::now();
+        assert!(now >= base);
+        assert!(now < base + Duration::from_secs(3600));
+        assert!(SystemTime::now() >= now);
+        assert_eq!(
+            alloc::format!("{}", base + Duration::from_millis(8)),
+            "2021-03-04T05:06:07Z"
+        );
+        assert_eq!(
+            alloc::format!("{:.3}", base + Duration::from_millis(8)),
+            "2021-03-04T05:06:07.008Z"
+        );
+    }
+}
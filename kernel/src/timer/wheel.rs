@@ -0,0 +1,165 @@
+//! Hierarchical timer wheel.
+//!
+//! Level `n` has [`WHEEL_SIZE`] slots of `WHEEL_SIZE^n` ticks each. Timers are put in the lowest
+//! level that covers their deadline and moved down a level (cascaded) when the wheel reaches
+//! their slot, so inserting and cancelling are O(1).
+//!
+//! Timers due before the end of the next tick are kept in a separate list and fire at their exact
+//! deadline instead of on a tick.
+
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
+
+use chos_lib::int::ceil_divu64;
+use intrusive_collections::{intrusive_adapter, linked_list};
+
+use super::{Instant, Timer, NS_PER_TICKS};
+
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SIZE: usize = 1 << WHEEL_BITS;
+const WHEEL_MASK: u64 = WHEEL_SIZE as u64 - 1;
+const WHEEL_LEVELS: usize = 4;
+// Timers further than this go in the last level, they are put back when it cascades
+const WHEEL_MAX_DELTA: u64 = (1 << (WHEEL_BITS * WHEEL_LEVELS as u32)) - 1;
+const NEAR_SLOT: usize = WHEEL_LEVELS * WHEEL_SIZE;
+
+intrusive_adapter!(TimerAdapter = Arc<Timer>: Timer { link: linked_list::AtomicLink });
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: linked_list::LinkedList<TimerAdapter> =
+    linked_list::LinkedList::new(TimerAdapter::NEW);
+
+const fn level_shift(level: usize) -> u32 {
+    WHEEL_BITS * level as u32
+}
+
+// First tick at or after the deadline
+fn expires(timer: &Timer) -> u64 {
+    ceil_divu64(timer.deadline.as_nanos(), NS_PER_TICKS)
+}
+
+pub(super) struct TimerWheel {
+    // The last slot is the near list
+    slots: [linked_list::LinkedList<TimerAdapter>; WHEEL_LEVELS * WHEEL_SIZE + 1],
+    // Last tick processed
+    now: u64,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        Self {
+            slots: [EMPTY_SLOT; WHEEL_LEVELS * WHEEL_SIZE + 1],
+            now: 0,
+        }
+    }
+
+    fn slot_for(&self, expires: u64) -> usize {
+        let expires = expires.min(self.now + WHEEL_MAX_DELTA);
+        let delta = expires - self.now;
+        let level = (0..WHEEL_LEVELS)
+            .find(|&level| delta < 1 << level_shift(level + 1))
+            .unwrap_or(WHEEL_LEVELS - 1);
+        level * WHEEL_SIZE + ((expires >> level_shift(level)) & WHEEL_MASK) as usize
+    }
+
+    fn insert_in(&mut self, timer: Arc<Timer>, slot: usize) {
+        timer.slot.store(slot, Ordering::Relaxed);
+        self.slots[slot].push_back(timer);
+    }
+
+    // Returns true if the timer went in the near list
+    fn place(&mut self, timer: Arc<Timer>) -> bool {
+        let expires = expires(&timer);
+        if expires <= self.now + 1 {
+            self.insert_in(timer, NEAR_SLOT);
+            true
+        } else {
+            let slot = self.slot_for(expires);
+            self.insert_in(timer, slot);
+            false
+        }
+    }
+
+    /// Returns true if the timer is due before the end of the next tick,
+    /// the next event might need to be moved earlier
+    pub fn insert(&mut self, timer: Arc<Timer>) -> bool {
+        self.place(timer)
+    }
+
+    /// Returns false if the timer was not in the wheel
+    pub fn cancel(&mut self, timer: &Timer) -> bool {
+        if !timer.link.is_linked() {
+            return false;
+        }
+        let slot = timer.slot.load(Ordering::Relaxed);
+        unsafe { self.slots[slot].cursor_mut_from_ptr(timer).remove() }
+            .expect("Timer should be in its slot");
+        true
+    }
+
+    /// When the wheel needs to be advanced next, to fire a timer or to move one closer
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let near = self.slots[NEAR_SLOT]
+            .iter()
+            .map(|timer| timer.deadline)
+            .min();
+        let wheel = self.next_wheel_tick().map(Instant::from_ticks);
+        near.into_iter().chain(wheel).min()
+    }
+
+    // First tick where advancing moves a timer out of its slot
+    fn next_wheel_tick(&self) -> Option<u64> {
+        (0..WHEEL_LEVELS)
+            .filter_map(|level| {
+                let base = self.now >> level_shift(level);
+                (1..=WHEEL_SIZE as u64)
+                    .map(|i| base + i)
+                    .find(|block| {
+                        let slot = level * WHEEL_SIZE + (block & WHEEL_MASK) as usize;
+                        !self.slots[slot].is_empty()
+                    })
+                    .map(|block| match level {
+                        // Goes to the near list one tick before
+                        0 => block - 1,
+                        _ => block << level_shift(level),
+                    })
+            })
+            .min()
+    }
+
+    // Moves the timers of the current slot of `level` to the lower levels
+    fn cascade(&mut self, level: usize) {
+        let slot = level * WHEEL_SIZE + ((self.now >> level_shift(level)) & WHEEL_MASK) as usize;
+        while let Some(timer) = self.slots[slot].pop_front() {
+            self.place(timer);
+        }
+    }
+
+    /// Processes every tick up to `now`, calling `fire` for each expired timer
+    pub fn advance(&mut self, now: Instant, mut fire: impl FnMut(Arc<Timer>)) {
+        let ticks = now.ticks();
+        while self.now < ticks {
+            // Skip the ticks where no timer moves, the CPU might have been idle for a long time
+            let next = self.next_wheel_tick().unwrap_or(u64::MAX).min(ticks);
+            self.now = self.now.max(next - 1) + 1;
+            for level in 1..WHEEL_LEVELS {
+                if self.now & ((1 << level_shift(level)) - 1) != 0 {
+                    break;
+                }
+                self.cascade(level);
+            }
+            let slot = ((self.now + 1) & WHEEL_MASK) as usize;
+            while let Some(timer) = self.slots[slot].pop_front() {
+                self.insert_in(timer, NEAR_SLOT);
+            }
+        }
+        let mut cursor = self.slots[NEAR_SLOT].front_mut();
+        while let Some(timer) = cursor.get() {
+            if timer.deadline <= now {
+                fire(cursor.remove().unwrap());
+            } else {
+                cursor.move_next();
+            }
+        }
+    }
+}
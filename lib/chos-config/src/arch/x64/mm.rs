@@ -26,6 +26,9 @@ pub mod virt {
     pub const PHYSICAL_MAP_BASE: VFrame<FrameSize4K> = KERNEL_BASE;
     pub const STATIC_BASE: VFrame<FrameSize4K> = KERNEL_BASE.add(1 * MEMORY_ZONE_FRAMES);
     pub const HEAP_BASE: VFrame<FrameSize4K> = KERNEL_BASE.add(2 * MEMORY_ZONE_FRAMES);
+    // Upper half of the heap zone, the lower half maps the physical memory
+    pub const VMALLOC_BASE: VFrame<FrameSize4K> = HEAP_BASE.add(MEMORY_ZONE_FRAMES / 2);
+    pub const VMALLOC_FRAMES: u64 = MEMORY_ZONE_FRAMES / 2;
     pub const DEVICE_BASE: VFrame<FrameSize4K> = KERNEL_BASE.add(3 * MEMORY_ZONE_FRAMES);
     pub const PER_CPU_BASE: VFrame<FrameSize4K> = KERNEL_BASE.add(4 * MEMORY_ZONE_FRAMES);
     pub const STACK_BASE: VFrame<FrameSize4K> = KERNEL_BASE.add(5 * MEMORY_ZONE_FRAMES);
@@ -30,6 +30,30 @@ fn signature_to_bare_fn(sig: &syn::Signature) -> Result<syn::TypeBareFn, TokenSt
     })
 }
 
+// A handler taking a `StackFrame<AllRegs>` also gets the callee-saved registers of the interrupted
+// code, they would be clobbered by the handler otherwise
+fn takes_all_regs(sig: &syn::Signature) -> bool {
+    let frame = match sig.inputs.first() {
+        Some(syn::FnArg::Typed(arg)) => &*arg.ty,
+        _ => return false,
+    };
+    let args = match frame {
+        syn::Type::Path(path) => match path.path.segments.last().map(|seg| &seg.arguments) {
+            Some(syn::PathArguments::AngleBracketed(args)) => &args.args,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    match args.first() {
+        Some(syn::GenericArgument::Type(syn::Type::Path(regs))) => regs
+            .path
+            .segments
+            .last()
+            .map_or(false, |seg| seg.ident == "AllRegs"),
+        _ => false,
+    }
+}
+
 pub fn parse_interrupt(attr: TokenStream, items: TokenStream) -> TokenStream {
     if !attr.is_empty() {
         return error("Attributes must be empty");
@@ -87,10 +111,39 @@ pub fn parse_interrupt(attr: TokenStream, items: TokenStream) -> TokenStream {
             "pushq $-1",
         }
     };
+    let all_regs = takes_all_regs(&sig);
+    let (asm_push_callee, asm_pop_callee, asm_load_extra) = if all_regs {
+        (
+            quote! {
+                "push %rbx",
+                "push %rbp",
+                "push %r12",
+                "push %r13",
+                "push %r14",
+                "push %r15",
+            },
+            quote! {
+                "pop %r15",
+                "pop %r14",
+                "pop %r13",
+                "pop %r12",
+                "pop %rbp",
+                "pop %rbx",
+            },
+            quote! { "mov 120(%rsp), %rsi", },
+        )
+    } else {
+        (
+            proc_macro2::TokenStream::new(),
+            proc_macro2::TokenStream::new(),
+            quote! { "mov 72(%rsp), %rsi", },
+        )
+    };
     let asm_epilogue = if diverges {
         proc_macro2::TokenStream::new()
     } else {
         quote! {
+            #asm_pop_callee
             "pop %rax",
             "pop %r11",
             "pop %r10",
@@ -126,8 +179,9 @@ pub fn parse_interrupt(attr: TokenStream, items: TokenStream) -> TokenStream {
                             "push %r10",
                             "push %r11",
                             "push %rax",
+                            #asm_push_callee
                             "mov %rsp, %rdi",
-                            "mov 72(%rsp), %rsi",
+                            #asm_load_extra
                             "call {handler}",
                             #asm_epilogue
                             handler = sym intr_handler,
@@ -2,6 +2,8 @@ mod command;
 mod interrupt;
 mod reg;
 
+use core::arch::x86_64::__cpuid;
+
 pub use command::*;
 pub use interrupt::*;
 pub use reg::*;
@@ -10,6 +12,11 @@ use crate::cpumask::Cpumask;
 use crate::mm::VAddr;
 use crate::Volatile;
 
+/// APIC id of this CPU given by cpuid, it can be used before the LAPIC is mapped
+pub fn initial_apic_id() -> u8 {
+    unsafe { (__cpuid(1).ebx >> 24) as u8 }
+}
+
 pub struct Apic<'a> {
     regs: &'a mut reg::ApicRegisters,
 }
@@ -37,6 +44,23 @@ impl Apic<'_> {
         self.regs.lvt_lint1.as_volatile_mut()
     }
 
+    pub fn timer_mut(&mut self) -> &mut Volatile<TimerInterrupt> {
+        self.regs.lvt_timer.as_volatile_mut()
+    }
+
+    pub unsafe fn set_timer_divide(&mut self, divide: TimerDivide) {
+        self.regs.divide_config.write(divide as u32);
+    }
+
+    /// Starts the timer in one-shot and periodic modes, 0 stops it
+    pub unsafe fn set_timer_initial_count(&mut self, count: u32) {
+        self.regs.initial_count.write(count);
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.regs.current_count.read()
+    }
+
     pub unsafe fn initialize(&mut self) {
         self.initialize_with_spurious_vector(0xff);
     }
@@ -121,6 +121,19 @@ pub enum TimerMode {
     TscDeadline = 0b10,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
 #[bitfield(bits = 32)]
 #[derive(Clone, Copy, Debug)]
 pub struct TimerInterrupt {
@@ -212,6 +212,21 @@ impl TimerMut<'_> {
         self.registers.comparator.write(value)
     }
 
+    pub fn supports_periodic(&self) -> bool {
+        self.registers.configuration.read().per_int_cap()
+    }
+
+    /// Fires first when the main counter reaches `start`, then every `period` counts
+    pub unsafe fn set_periodic(&mut self, start: u64, period: u64) {
+        self.registers.configuration.update(|config| {
+            config.set_type_cnf(TimerType::Periodic);
+            config.set_val_set_cnf(true);
+        });
+        // With val_set_cnf, the first write sets the comparator and the second the period
+        self.registers.comparator.write(start);
+        self.registers.comparator.write(period);
+    }
+
     pub unsafe fn set_type(&mut self, typ: TimerType) {
         self.registers
             .configuration
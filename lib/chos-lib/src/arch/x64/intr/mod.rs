@@ -41,6 +41,10 @@ cfg_if! {
             }
         }
 
+        pub fn interrupts_enabled() -> bool {
+            Flags::get().intr_enable()
+        }
+
         pub fn breakpoint() {
             unsafe {
                 asm!("int3");
@@ -53,6 +57,14 @@ cfg_if! {
             }
         }
 
+        /// `sti` only takes effect after the next instruction, no interrupt can be missed before `hlt`.
+        /// Not `nomem`, the interrupt handlers change memory that is checked after waking up.
+        pub fn enable_interrupts_and_wait() {
+            unsafe {
+                asm!("sti", "hlt", options(nostack));
+            }
+        }
+
         pub macro int($n:expr) {
             unsafe {
                 core::arch::asm!(
@@ -68,6 +80,10 @@ cfg_if! {
             // Nothing
         }
 
+        pub fn enable_interrupts_and_wait() {
+            // Nothing
+        }
+
         pub fn disable_interrups() {
             // Nothing
         }
@@ -83,6 +99,10 @@ cfg_if! {
         pub fn restore_interrupts(_: IntrStatus) {
             // Nothing
         }
+
+        pub fn interrupts_enabled() -> bool {
+            false
+        }
     }
 }
 
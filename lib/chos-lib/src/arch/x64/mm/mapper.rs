@@ -46,12 +46,10 @@ impl<S: FrameSize> MapperFlush for Flush<S> {
         match (self, rhs) {
             (Self::All, _) | (_, Self::All) => Self::All,
             (Self::Range(r1), Self::Range(r2)) => {
-                if let Some(r) = r1.intersection(r2) {
-                    if r.frame_count() <= FLUSH_MAX_INVLPG_FRAMES {
-                        Self::Range(r)
-                    } else {
-                        Self::All
-                    }
+                // Must cover both ranges
+                let r = r1.span(r2);
+                if r.frame_count() <= FLUSH_MAX_INVLPG_FRAMES {
+                    Self::Range(r)
                 } else {
                     Self::All
                 }
@@ -175,10 +173,43 @@ impl Mapper<FrameSize4K> for OffsetMapper<'_> {
 
     unsafe fn unmap<A: FrameAllocator<FrameSize4K> + ?Sized>(
         &mut self,
-        _frame: VFrame<FrameSize4K>,
-        _alloc: &mut A,
+        frame: VFrame<FrameSize4K>,
+        alloc: &mut A,
     ) -> Result<Self::Flush, UnmapError<A::Error>> {
-        todo!("Unmap(4K)")
+        let (p4i, p3i, p2i, p1i) = frame.split();
+        let base = self.base;
+        let p3 = get_page_table(self.p4, base, p4i).ok_or(UnmapError::NotMapped)?;
+        if p3[p3i].huge_page() {
+            return Err(UnmapError::InvalidSize);
+        }
+        let p2 = get_page_table(p3, base, p3i).ok_or(UnmapError::NotMapped)?;
+        if p2[p2i].huge_page() {
+            return Err(UnmapError::InvalidSize);
+        }
+        let p1 = get_page_table(p2, base, p2i).ok_or(UnmapError::NotMapped)?;
+        let entry = &mut p1[p1i];
+        if !entry.present() {
+            return Err(UnmapError::NotMapped);
+        }
+        *entry = PageEntry::new();
+        // Free the tables that became empty, from the bottom up
+        let mut dealloc_table = |entry: &mut PageEntry| {
+            let vframe = VFrame::new_unchecked(resolve_page_vaddr(base, entry.paddr()));
+            *entry = PageEntry::new();
+            alloc
+                .dealloc_frame(vframe)
+                .map_err(UnmapError::FrameAllocError)
+        };
+        if dec_child_alloc_count(&mut p2[p2i]) {
+            dealloc_table(&mut p2[p2i])?;
+            if dec_child_alloc_count(&mut p3[p3i]) {
+                dealloc_table(&mut p3[p3i])?;
+                if dec_child_alloc_count(&mut self.p4[p4i]) {
+                    dealloc_table(&mut self.p4[p4i])?;
+                }
+            }
+        }
+        Ok(Flush::Range(VFrameRange::new(frame, frame.add(1))))
     }
 }
 
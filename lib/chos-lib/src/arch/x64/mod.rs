@@ -12,8 +12,10 @@ pub mod msr;
 pub mod port;
 pub mod qemu;
 pub mod regs;
+pub mod rtc;
 pub mod serial;
 pub mod tables;
+pub mod tsc;
 
 pub fn hlt_loop() -> ! {
     intr::disable_interrups();
@@ -0,0 +1,116 @@
+use core::hint::spin_loop;
+
+use super::port::{Port, PortWriteOnly};
+use crate::time::DateTime;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+// Not standard, but supported by QEMU and most chipsets
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+const DEFAULT_CENTURY: u16 = 20;
+
+const fn from_bcd(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0xf)
+}
+
+/// CMOS real-time clock, with a precision of one second
+pub struct Rtc {
+    index: PortWriteOnly<u8>,
+    data: Port<u8>,
+}
+
+impl Rtc {
+    /// # Safety
+    /// The CMOS must not be accessed by anyone else while the Rtc is alive
+    pub const unsafe fn new() -> Self {
+        Self {
+            index: PortWriteOnly::new(CMOS_INDEX),
+            data: Port::new(CMOS_DATA),
+        }
+    }
+
+    fn read_reg(&mut self, reg: u8) -> u8 {
+        unsafe {
+            self.index.write(reg);
+            self.data.read()
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read_reg(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> [u8; 7] {
+        while self.update_in_progress() {
+            spin_loop();
+        }
+        [
+            REG_SECONDS,
+            REG_MINUTES,
+            REG_HOURS,
+            REG_DAY,
+            REG_MONTH,
+            REG_YEAR,
+            REG_CENTURY,
+        ]
+        .map(|reg| self.read_reg(reg))
+    }
+
+    /// Current time in UTC, assuming the RTC is set to UTC
+    pub fn read(&mut self) -> DateTime {
+        // An update can start while we read, wait until we get the same values twice
+        let mut raw = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let [seconds, minutes, hours, day, month, year, century] = raw;
+
+        let status = self.read_reg(REG_STATUS_B);
+        let decode = |v: u8| match status & STATUS_B_BINARY {
+            0 => from_bcd(v),
+            _ => v,
+        };
+        let pm = hours & HOURS_PM != 0;
+        let mut hour = decode(hours & !HOURS_PM);
+        if status & STATUS_B_24H == 0 {
+            // 12 AM is midnight, 12 PM is noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+        let century = match decode(century) {
+            0 => DEFAULT_CENTURY,
+            century => century as u16,
+        };
+
+        DateTime {
+            year: century * 100 + decode(year) as u16,
+            month: decode(month),
+            day: decode(day),
+            hour,
+            minute: decode(minutes),
+            second: decode(seconds),
+            nanosecond: 0,
+        }
+    }
+}
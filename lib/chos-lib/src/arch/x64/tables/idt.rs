@@ -10,7 +10,7 @@ use bitflags::bitflags;
 
 use super::DescriptorRegister;
 use crate::arch::intr::IoPl;
-use crate::arch::regs::{ScratchRegs, CS};
+use crate::arch::regs::{AllRegs, ScratchRegs, CS};
 use crate::config::domain;
 use crate::log::domain_debug;
 use crate::Volatile;
@@ -69,6 +69,7 @@ pub type HandlerNoReturn = HandlerFn<extern "x86-interrupt" fn(StackFrame<Scratc
 pub type HandlerError = HandlerFn<extern "x86-interrupt" fn(StackFrame<ScratchRegs>, u64)>;
 pub type HandlerErrorNoReturn = HandlerFn<extern "x86-interrupt" fn(StackFrame<ScratchRegs>, u64) -> !>;
 pub type HandlerPageFault = HandlerFn<extern "x86-interrupt" fn(StackFrame<ScratchRegs>, PageFaultError)>;
+pub type HandlerAllRegs = HandlerFn<extern "x86-interrupt" fn(StackFrame<AllRegs>)>;
 
 mod private {
     pub trait Sealed {}
@@ -96,6 +97,7 @@ handler_fns!(
     HandlerError,
     HandlerErrorNoReturn,
     HandlerPageFault,
+    HandlerAllRegs,
 );
 
 #[repr(C, align(16))]
@@ -103,7 +105,7 @@ handler_fns!(
 pub struct Idt {
     /* 00 */ pub divide_error: Entry<Handler>,
     /* 01 */ pub debug: Entry<Handler>,
-    /* 02 */ pub non_maskable_interrupt: Entry<Handler>,
+    /* 02 */ pub non_maskable_interrupt: Entry<HandlerAllRegs>,
     /* 03 */ pub breakpoint: Entry<Handler>,
     /* 04 */ pub overflow: Entry<Handler>,
     /* 05 */ pub bound_range_exceeded: Entry<Handler>,
@@ -0,0 +1,34 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use super::msr::Msr;
+
+const IA32_TSC_DEADLINE: Msr = Msr::new(0x6e0);
+
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_FEATURES_ECX_TSC_DEADLINE: u32 = 1 << 24;
+const CPUID_EXT_MAX: u32 = 0x8000_0000;
+const CPUID_EXT_POWER: u32 = 0x8000_0007;
+const CPUID_EXT_POWER_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// The LAPIC timer supports [`TimerMode::TscDeadline`](super::apic::TimerMode::TscDeadline)
+pub fn has_tsc_deadline() -> bool {
+    unsafe { __cpuid(CPUID_FEATURES).ecx & CPUID_FEATURES_ECX_TSC_DEADLINE != 0 }
+}
+
+/// The TSC runs at a constant rate, in all power states
+pub fn has_invariant_tsc() -> bool {
+    unsafe {
+        __cpuid(CPUID_EXT_MAX).eax >= CPUID_EXT_POWER
+            && __cpuid(CPUID_EXT_POWER).edx & CPUID_EXT_POWER_EDX_INVARIANT_TSC != 0
+    }
+}
+
+/// The LAPIC timer fires once the TSC reaches `deadline`, 0 disarms it
+pub unsafe fn set_tsc_deadline(deadline: u64) {
+    IA32_TSC_DEADLINE.write_raw_shared(deadline)
+}
@@ -1,5 +1,5 @@
 pub mod domain {
-    use crate::log::domain;
+    use crate::log::{domain, Domain};
 
     #[cfg(target_arch = "x86_64")]
     domain! {
@@ -8,4 +8,10 @@ pub mod domain {
         TSS = false,
         PAGE_TABLE = false,
     }
+
+    #[cfg(target_arch = "x86_64")]
+    pub static DOMAINS: &[&dyn Domain] = &[&GDT, &IDT, &TSS, &PAGE_TABLE];
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub static DOMAINS: &[&dyn Domain] = &[];
 }
@@ -51,6 +51,7 @@ pub mod str;
 pub mod stride;
 pub mod sync;
 pub mod tar;
+pub mod time;
 mod volatile;
 pub use chos_lib_macros::forward_fmt;
 pub use volatile::*;
@@ -70,3 +71,7 @@ extern "C" fn __lock_disable_sched_save() {}
 #[cfg(test)]
 #[no_mangle]
 extern "C" fn __lock_restore_sched() {}
+
+#[cfg(all(test, feature = "spin-debug"))]
+#[no_mangle]
+fn __spin_lock_timeout(_: *const (), _: Option<u8>, _: &[crate::mm::VAddr]) {}
@@ -1,10 +1,12 @@
 use core::fmt::{Arguments, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use cfg_if::cfg_if;
 
 use crate::sync::{RawLock, Lock};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
 pub enum LogLevel {
     Debug,
     Info,
@@ -75,13 +77,31 @@ pub unsafe fn clear_handler() {
     LOG_HANDLER = None;
 }
 
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+/// Messages below this level are dropped, on top of the log-* features
+pub fn set_level(lvl: LogLevel) {
+    LOG_LEVEL.store(lvl as u8, Ordering::Relaxed);
+}
+
+#[inline]
+fn level_enabled(lvl: LogLevel) -> bool {
+    lvl as u8 >= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
 pub fn log_impl(args: Arguments<'_>, lvl: LogLevel) {
+    if !level_enabled(lvl) {
+        return;
+    }
     if let Some(handler) = unsafe { LOG_HANDLER } {
         handler.log(args, lvl);
     }
 }
 
 pub unsafe fn unsafe_log_impl(args: Arguments<'_>, lvl: LogLevel) {
+    if !level_enabled(lvl) {
+        return;
+    }
     if let Some(handler) = LOG_HANDLER {
         handler.log(args, lvl);
     }
@@ -200,6 +220,7 @@ cfg_if! {
 pub trait Domain {
     fn name(&self) -> &str;
     fn enabled(&self) -> bool;
+    fn set_enabled(&self, enabled: bool);
 }
 
 pub macro domain_println($domain:expr, $($args:tt)*) {
@@ -277,8 +298,9 @@ pub macro unsafe_domain_critical ($domain:expr, $($args:tt)*) {
 pub macro domain ($($name:ident = $value:expr),* $(,)?) {
     $(
         paste::item! {
-            pub struct [<__ $name:camel>];
-            pub const $name: [<__ $name:camel>] = [<__ $name:camel>];
+            pub struct [<__ $name:camel>](::core::sync::atomic::AtomicBool);
+            pub static $name: [<__ $name:camel>] =
+                [<__ $name:camel>](::core::sync::atomic::AtomicBool::new($value));
             impl $crate::log::Domain for [<__ $name:camel>] {
                 #[inline]
                 fn name(&self) -> &str {
@@ -286,7 +308,10 @@ pub macro domain ($($name:ident = $value:expr),* $(,)?) {
                 }
                 #[inline]
                 fn enabled(&self) -> bool {
-                    $value
+                    self.0.load(::core::sync::atomic::Ordering::Relaxed)
+                }
+                fn set_enabled(&self, enabled: bool) {
+                    self.0.store(enabled, ::core::sync::atomic::Ordering::Relaxed)
                 }
             }
         }
@@ -191,6 +191,14 @@ macro_rules! frame {
                     }
                 }
 
+                /// Smallest range that contains both ranges
+                pub fn span(self, rhs: Self) -> Self {
+                    Self::new(
+                        <$name<S>>::min(self.start, rhs.start),
+                        <$name<S>>::max(self.end, rhs.end)
+                    )
+                }
+
                 pub fn contains_address(&self, rhs: $addr) -> bool {
                     self.start.addr() <= rhs && self.end.addr() > rhs
                 }
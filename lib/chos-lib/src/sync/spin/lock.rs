@@ -7,30 +7,144 @@ use crate::init::ConstInit;
 use crate::sync::lock::{Lock, LockGuard, RawLock, RawTryLock, LockGuardProject};
 use crate::sync::rwlock::{RWLock, RWLockReadGuard, RWLockWriteGuard, RawRWLock, RawTryRWLock};
 
+#[cfg(feature = "spin-debug")]
+pub mod debug {
+    use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+    use crate::arch::backtrace;
+    use crate::mm::VAddr;
+
+    // Frame pointer walk of the owner, only a few loads
+    const BACKTRACE_DEPTH: usize = 4;
+    const NO_OWNER: u8 = u8::MAX;
+
+    // A few seconds
+    pub const SPIN_TIMEOUT_TRIES: u64 = 1 << 26;
+
+    extern "Rust" {
+        fn __spin_lock_timeout(lock: *const (), owner_apic_id: Option<u8>, backtrace: &[VAddr]);
+    }
+
+    static CPU_IDS: [u8; 256] = {
+        let mut ids = [0; 256];
+        let mut i = 0;
+        while i < ids.len() {
+            ids[i] = i as u8;
+            i += 1;
+        }
+        ids
+    };
+
+    /// Points GS at the APIC id of this CPU, the spinlocks record it as their owner without
+    /// running `cpuid`. Must be called on every CPU before it takes a spinlock.
+    pub unsafe fn set_cpu_apic_id(apic_id: u8) {
+        crate::arch::regs::GS::write(VAddr::new_unchecked(
+            &CPU_IDS[apic_id as usize] as *const u8 as u64,
+        ));
+    }
+
+    #[cfg(not(test))]
+    fn this_cpu_apic_id() -> u8 {
+        let id: u8;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, gs:[0]",
+                out(reg_byte) id,
+                options(nostack, readonly, preserves_flags),
+            )
+        };
+        id
+    }
+
+    #[cfg(test)]
+    fn this_cpu_apic_id() -> u8 {
+        NO_OWNER
+    }
+
+    /// Who holds the lock and where it was taken, only written by the owner
+    pub struct SpinDebug {
+        owner: AtomicU8,
+        backtrace: [AtomicU64; BACKTRACE_DEPTH],
+    }
+
+    impl SpinDebug {
+        #[allow(clippy::declare_interior_mutable_const)]
+        pub const INIT: Self = {
+            const ZERO: AtomicU64 = AtomicU64::new(0);
+            Self {
+                owner: AtomicU8::new(NO_OWNER),
+                backtrace: [ZERO; BACKTRACE_DEPTH],
+            }
+        };
+
+        pub fn on_lock(&self) {
+            self.owner.store(this_cpu_apic_id(), Ordering::Relaxed);
+            let mut frames = backtrace();
+            for addr in &self.backtrace {
+                addr.store(frames.next().map_or(0, VAddr::as_u64), Ordering::Relaxed);
+            }
+        }
+
+        pub fn on_unlock(&self) {
+            self.owner.store(NO_OWNER, Ordering::Relaxed);
+        }
+
+        pub fn report(&self, lock: *const ()) {
+            let owner = self.owner.load(Ordering::Relaxed);
+            let mut frames = [VAddr::null(); BACKTRACE_DEPTH];
+            let mut count = 0;
+            for addr in &self.backtrace {
+                match addr.load(Ordering::Relaxed) {
+                    0 => break,
+                    addr => frames[count] = unsafe { VAddr::new_unchecked(addr) },
+                }
+                count += 1;
+            }
+            let owner = (owner != NO_OWNER).then(|| owner);
+            unsafe { __spin_lock_timeout(lock, owner, &frames[..count]) };
+        }
+    }
+}
+
 pub struct RawSpinLock {
     lock: AtomicBool,
+    #[cfg(feature = "spin-debug")]
+    debug: debug::SpinDebug,
 }
 
 impl ConstInit for RawSpinLock {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self {
         lock: AtomicBool::new(false),
+        #[cfg(feature = "spin-debug")]
+        debug: debug::SpinDebug::INIT,
     };
 }
 
 unsafe impl RawLock for RawSpinLock {
     #[inline]
     fn lock(&self) {
+        #[cfg(feature = "spin-debug")]
+        let mut tries = 0;
         loop {
             if likely(self.try_lock()) {
                 return;
             }
+            #[cfg(feature = "spin-debug")]
+            {
+                tries += 1;
+                if tries == debug::SPIN_TIMEOUT_TRIES {
+                    self.debug.report(self as *const Self as *const ());
+                }
+            }
             spin_loop();
         }
     }
 
     #[inline]
     unsafe fn unlock(&self) {
+        #[cfg(feature = "spin-debug")]
+        self.debug.on_unlock();
         self.lock.store(false, Ordering::Release);
     }
 }
@@ -38,9 +152,17 @@ unsafe impl RawLock for RawSpinLock {
 unsafe impl RawTryLock for RawSpinLock {
     #[inline]
     fn try_lock(&self) -> bool {
-        self.lock
+        let locked = self
+            .lock
             .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+            .is_ok();
+        #[cfg(feature = "spin-debug")]
+        {
+            if locked {
+                self.debug.on_lock();
+            }
+        }
+        locked
     }
 }
 
@@ -0,0 +1,151 @@
+use core::fmt;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+// Days between 0000-03-01 and 1970-01-01
+const DAYS_TO_UNIX_EPOCH: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+
+/// Date and time in UTC, in the Gregorian calendar. Only dates after the Unix epoch are supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+// Years start in March so that the leap day is the last day of the year
+const fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * DAYS_PER_ERA + doe - DAYS_TO_UNIX_EPOCH
+}
+
+const fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + DAYS_TO_UNIX_EPOCH;
+    let era = days / DAYS_PER_ERA;
+    let doe = days - era * DAYS_PER_ERA;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+impl DateTime {
+    pub const UNIX_EPOCH: Self = Self::from_unix_nanos(0);
+
+    pub const fn from_unix_nanos(ns: u64) -> Self {
+        let secs = ns / NS_PER_SEC;
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let secs = secs % SECS_PER_DAY;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            nanosecond: (ns % NS_PER_SEC) as u32,
+        }
+    }
+
+    pub const fn unix_nanos(&self) -> u64 {
+        let days = days_from_civil(self.year as u64, self.month as u64, self.day as u64);
+        let secs = days * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+        secs * NS_PER_SEC + self.nanosecond as u64
+    }
+
+    /// Doesn't check the day against the length of the month
+    pub const fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= 31
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && (self.nanosecond as u64) < NS_PER_SEC
+    }
+}
+
+/// ISO 8601, the precision is the number of digits of the fraction of second
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )?;
+        match f.precision().map(|p| p.min(9)) {
+            None | Some(0) => (),
+            Some(digits) => write!(
+                f,
+                ".{:0digits$}",
+                self.nanosecond / 10u32.pow(9 - digits as u32),
+                digits = digits,
+            )?,
+        }
+        f.write_str("Z")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+
+    use super::*;
+
+    const fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            nanosecond: 0,
+        }
+    }
+
+    #[test]
+    fn unix_conversions() {
+        assert_eq!(DateTime::UNIX_EPOCH, date(1970, 1, 1, 0, 0, 0));
+        for (date, secs) in [
+            (date(1970, 1, 1, 0, 0, 0), 0),
+            (date(2000, 2, 29, 12, 0, 0), 951_825_600),
+            (date(2000, 3, 1, 0, 0, 0), 951_868_800),
+            (date(2021, 12, 31, 23, 59, 59), 1_640_995_199),
+            (date(2100, 3, 1, 0, 0, 0), 4_107_542_400),
+        ] {
+            assert_eq!(date.unix_nanos(), secs * NS_PER_SEC, "{}", date);
+            assert_eq!(DateTime::from_unix_nanos(secs * NS_PER_SEC), date);
+        }
+        let ns = 1_640_995_199 * NS_PER_SEC + 123_456_789;
+        assert_eq!(DateTime::from_unix_nanos(ns).unix_nanos(), ns);
+    }
+
+    #[test]
+    fn display() {
+        let mut date = date(2021, 3, 4, 5, 6, 7);
+        date.nanosecond = 123_456_789;
+        assert_eq!(format!("{}", date), "2021-03-04T05:06:07Z");
+        assert_eq!(format!("{:.3}", date), "2021-03-04T05:06:07.123Z");
+        assert_eq!(format!("{:.12}", date), "2021-03-04T05:06:07.123456789Z");
+    }
+}